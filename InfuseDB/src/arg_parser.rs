@@ -106,20 +106,19 @@ impl ArgSearch for BetterArg {
 }
 
 pub fn args_parser() -> BetterArg {
+    parse(env::args().skip(1))
+}
+
+fn parse(args: impl Iterator<Item = String>) -> BetterArg {
     let mut parsed = Vec::new();
-    let mut args = env::args().skip(1);
+    let mut args = args.peekable();
     while let Some(arg) = args.next() {
         if arg.starts_with('-') {
             let key = arg;
-            let value = args.next();
-            if value.is_some() {
-                let value = value.unwrap();
-                if value.starts_with('-') {
-                    parsed.push(Arg::Simple(key));
-                    parsed.push(Arg::Simple(value));
-                } else {
-                    parsed.push(Arg::Couple(key, value));
-                }
+            // a flag followed by another flag is a switch (e.g. `-s --max-conn 10`)
+            let value = args.next_if(|value| !value.starts_with('-'));
+            if let Some(value) = value {
+                parsed.push(Arg::Couple(key, value));
             } else {
                 parsed.push(Arg::Simple(key));
             }
@@ -130,3 +129,22 @@ pub fn args_parser() -> BetterArg {
 
     parsed
 }
+
+#[cfg(test)]
+#[test]
+fn test_parse() {
+    let parse_line = |line: &str| parse(line.split_whitespace().map(String::from));
+    let args = parse_line("-s --max-conn 10 --idle-timeout 30 -p db.mdb");
+    assert!(args.get_key("-s").is_some());
+    assert_eq!(args.get_key("--max-conn"), Some("10".to_string()));
+    assert_eq!(args.get_key("--idle-timeout"), Some("30".to_string()));
+    assert_eq!(args.get_key("-p"), Some("db.mdb".to_string()));
+    assert_eq!(args.count_simple(), 1);
+
+    // switches one after another, the last one at the end
+    let args = parse_line("-s -v --port 99 -x");
+    assert_eq!(args.count_simple(), 3);
+    assert_eq!(args.get_key("-v"), Some("-v".to_string()));
+    assert_eq!(args.get_key("--port"), Some("99".to_string()));
+    assert_eq!(args.get_key("-x"), Some("-x".to_string()));
+}
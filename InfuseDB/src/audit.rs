@@ -0,0 +1,94 @@
+// Audit and slow query logs of the server
+// Both are written as NDJSON, one record per line, to rotating files
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use infusedb::RotatingFile;
+use infusedb::utils::json_string;
+
+// Size of each log file before rotating and rotated files kept
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+const KEEP_LOGS: usize = 5;
+
+pub struct Entry<'a> {
+    pub client: SocketAddr,
+    pub user: Option<&'a str>,
+    pub collection: Option<&'a str>,
+    pub command: &'a str,
+    pub error: Option<String>,
+    pub duration: Duration,
+}
+
+pub struct AuditLog {
+    audit: Option<RotatingFile>,
+    slow: Option<RotatingFile>,
+    slow_threshold: Duration,
+}
+
+fn json_opt(value: Option<&str>) -> String {
+    value.map(json_string).unwrap_or("null".to_string())
+}
+
+impl AuditLog {
+    pub fn open(
+        audit_path: Option<&str>,
+        slow_path: Option<&str>,
+        slow_threshold: Duration,
+    ) -> io::Result<Self> {
+        let open = |path: &str| RotatingFile::open(path, MAX_LOG_SIZE, KEEP_LOGS);
+        Ok(AuditLog {
+            audit: audit_path.map(open).transpose()?,
+            slow: slow_path.map(open).transpose()?,
+            slow_threshold,
+        })
+    }
+
+    pub fn record(&mut self, entry: &Entry) {
+        if self.audit.is_none() && self.slow.is_none() {
+            return;
+        }
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let command = json_string(entry.command.trim());
+        let client = json_string(&entry.client.to_string());
+        let collection = json_opt(entry.collection);
+
+        if let Some(audit) = self.audit.as_mut() {
+            let status = match &entry.error {
+                Some(err) => format!(r#""err", "error": {}"#, json_string(err)),
+                None => r#""ok""#.to_string(),
+            };
+            let line = format!(
+                r#"{{"ts": {}, "client": {}, "user": {}, "collection": {}, "command": {}, "status": {}}}"#,
+                ts,
+                client,
+                json_opt(entry.user),
+                collection,
+                command,
+                status
+            );
+            if let Err(err) = audit.append_line(&line) {
+                eprintln!("Error writing {}: {}", audit.path(), err);
+            }
+        }
+
+        if let Some(slow) = self.slow.as_mut()
+            && entry.duration >= self.slow_threshold
+        {
+            let line = format!(
+                r#"{{"ts": {}, "client": {}, "collection": {}, "command": {}, "duration_ms": {}}}"#,
+                ts,
+                client,
+                collection,
+                command,
+                entry.duration.as_secs_f64() * 1000.0
+            );
+            if let Err(err) = slow.append_line(&line) {
+                eprintln!("Error writing {}: {}", slow.path(), err);
+            }
+        }
+    }
+}
@@ -0,0 +1,250 @@
+// Network of the cluster mode
+// Every node listens for the Raft messages on its client port plus
+// CLUSTER_PORT_OFFSET and keeps a connection to each of the other nodes to
+// send its own messages, replies included. Incoming connections are only
+// read, so a message is never answered on the connection it came from.
+use crate::raft::{Decoder, NodeId, RaftNode};
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Interest, Registry, Token};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub const CLUSTER_PORT_OFFSET: u16 = 10000;
+pub const CLUSTER: Token = Token(usize::MAX - 2);
+// tokens of the connections to the other nodes, counting down from here
+const LINKS: usize = usize::MAX - 3;
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// output kept for a node that does not read it, over it the link is dropped
+const MAX_PENDING: usize = 16 * 1024 * 1024;
+
+// Address of the Raft messages of a node, None if its port is too high
+pub fn cluster_addr(addr: SocketAddr) -> Option<SocketAddr> {
+    let port = addr.port().checked_add(CLUSTER_PORT_OFFSET)?;
+    Some(SocketAddr::new(addr.ip(), port))
+}
+
+struct PeerLink {
+    addr: SocketAddr,
+    stream: Option<TcpStream>,
+    pending: Vec<u8>,
+    last_attempt: Option<Instant>,
+}
+
+struct Incoming {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    decoder: Decoder,
+}
+
+pub struct Cluster {
+    pub node: RaftNode,
+    // client addresses of the nodes
+    addrs: HashMap<NodeId, SocketAddr>,
+    listener: TcpListener,
+    links: HashMap<NodeId, PeerLink>,
+    incoming: HashMap<Token, Incoming>,
+}
+
+impl PeerLink {
+    fn disconnect(&mut self, registry: &Registry) {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = registry.deregister(&mut stream);
+        }
+        self.pending.clear();
+    }
+
+    // Connects without blocking, the output waits until it is writable
+    fn connect(&mut self, registry: &Registry, token: Token) {
+        if self.stream.is_some()
+            || self
+                .last_attempt
+                .is_some_and(|at| at.elapsed() < RETRY_INTERVAL)
+        {
+            return;
+        }
+        self.last_attempt = Some(Instant::now());
+        let Ok(mut stream) = TcpStream::connect(self.addr) else {
+            return;
+        };
+        let interest = Interest::READABLE | Interest::WRITABLE;
+        if registry.register(&mut stream, token, interest).is_ok() {
+            self.stream = Some(stream);
+        }
+    }
+
+    fn flush(&mut self, registry: &Registry) {
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+        while !self.pending.is_empty() {
+            match stream.write(&self.pending) {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::NotConnected =>
+                {
+                    break;
+                }
+                Err(_) => {
+                    self.disconnect(registry);
+                    return;
+                }
+            }
+        }
+        if self.pending.len() > MAX_PENDING {
+            self.disconnect(registry);
+        }
+    }
+}
+
+impl Cluster {
+    pub fn new(
+        node: RaftNode,
+        addrs: HashMap<NodeId, SocketAddr>,
+        registry: &Registry,
+    ) -> io::Result<Self> {
+        let own = addrs.get(&node.id).ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Unknown node id",
+        ))?;
+        let port_error = || io::Error::new(io::ErrorKind::InvalidInput, "Cluster port too high");
+        let own = cluster_addr(*own).ok_or_else(port_error)?;
+        let bind = SocketAddr::new([0, 0, 0, 0].into(), own.port());
+        let mut listener = TcpListener::bind(bind)?;
+        registry.register(&mut listener, CLUSTER, Interest::READABLE)?;
+        let mut links = HashMap::new();
+        for (id, addr) in addrs.iter().filter(|(id, _)| **id != node.id) {
+            let link = PeerLink {
+                addr: cluster_addr(*addr).ok_or_else(port_error)?,
+                stream: None,
+                pending: Vec::new(),
+                last_attempt: None,
+            };
+            links.insert(*id, link);
+        }
+        Ok(Cluster {
+            node,
+            addrs,
+            listener,
+            links,
+            incoming: HashMap::new(),
+        })
+    }
+
+    // Client address of the node
+    pub fn addr(&self, id: NodeId) -> Option<SocketAddr> {
+        self.addrs.get(&id).copied()
+    }
+
+    pub fn owns(&self, token: Token) -> bool {
+        self.incoming.contains_key(&token) || self.link_id(token).is_some()
+    }
+
+    fn link_id(&self, token: Token) -> Option<NodeId> {
+        let id = LINKS.checked_sub(token.0)?;
+        self.links.contains_key(&id).then_some(id)
+    }
+
+    pub fn accept(&mut self, registry: &Registry, unique_token: &mut usize) -> io::Result<()> {
+        loop {
+            let (mut stream, _) = match self.listener.accept() {
+                Ok(conn) => conn,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let token = Token(*unique_token);
+            *unique_token += 1;
+            registry.register(&mut stream, token, Interest::READABLE)?;
+            let incoming = Incoming {
+                stream,
+                buffer: Vec::new(),
+                decoder: Decoder::default(),
+            };
+            self.incoming.insert(token, incoming);
+        }
+    }
+
+    pub fn on_event(&mut self, registry: &Registry, token: Token, writable: bool) {
+        if let Some(id) = self.link_id(token) {
+            let link = self.links.get_mut(&id).unwrap();
+            let failed = match link.stream.as_mut() {
+                Some(stream) => matches!(stream.take_error(), Ok(Some(_)) | Err(_)),
+                None => false,
+            };
+            if failed {
+                link.disconnect(registry);
+                return;
+            }
+            // the other node never writes on this connection, only closes it
+            let mut buf = [0u8; 64];
+            if let Some(stream) = link.stream.as_mut()
+                && matches!(stream.read(&mut buf), Ok(0))
+            {
+                link.disconnect(registry);
+                return;
+            }
+            if writable {
+                link.flush(registry);
+            }
+            return;
+        }
+
+        let Some(incoming) = self.incoming.get_mut(&token) else {
+            return;
+        };
+        let mut buf = [0u8; 4096];
+        let mut closed = false;
+        loop {
+            match incoming.stream.read(&mut buf) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(n) => incoming.buffer.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    closed = true;
+                    break;
+                }
+            }
+        }
+        let now = Instant::now();
+        while let Some(end) = incoming.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = incoming.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(message) = incoming.decoder.feed(line.trim_end_matches(['\r', '\n'])) {
+                self.node.step(message, now);
+            }
+        }
+        if closed && let Some(mut incoming) = self.incoming.remove(&token) {
+            let _ = registry.deregister(&mut incoming.stream);
+        }
+    }
+
+    // Advances the timers of the node and sends the messages it produced
+    pub fn tick(&mut self, registry: &Registry) {
+        self.node.tick(Instant::now());
+        for (to, message) in self.node.take_messages() {
+            let Some(link) = self.links.get_mut(&to) else {
+                continue;
+            };
+            link.connect(registry, Token(LINKS - to));
+            if link.stream.is_none() {
+                continue;
+            }
+            link.pending.extend_from_slice(message.encode().as_bytes());
+            link.pending.push(b'\n');
+            link.flush(registry);
+        }
+    }
+
+    pub fn connected(&self) -> usize {
+        self.links.values().filter(|l| l.stream.is_some()).count()
+    }
+}
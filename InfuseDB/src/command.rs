@@ -1,9 +1,77 @@
+use infusedb::utils;
+
+use infusedb::doc;
+use infusedb::{
+    Collection, DataType, EvictionPolicy, Filter, FindOp, InfuseDB, MemoryLimit, Metric, to_vector,
+};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::time::Duration;
 
-use infusedb::utils;
+// Commands that modify the collection
+pub const WRITE_COMMANDS: &[&str] = &[
+    "set", "del", "cas", "restore", "expire", "persist", "create", "drop",
+];
+// Commands that change a setting of the collection, without arguments they
+// only show it
+pub const SETTING_COMMANDS: &[&str] = &["retention", "maxmemory", "fulltext"];
 
-use crate::doc;
-use crate::infusedb::{Collection, DataType, FindOp};
+pub fn is_write(command: &str) -> bool {
+    let mut words = command.split_whitespace();
+    let action = words.next().unwrap_or_default();
+    WRITE_COMMANDS.contains(&action)
+        || (SETTING_COMMANDS.contains(&action) && words.next().is_some())
+}
+
+// Approximate bytes the write command adds to the collection, to make room
+// for them before running it. A value set at a nested path counts whole
+pub fn write_size(db: &mut InfuseDB, collection: &str, command: &str) -> usize {
+    let args = utils::smart_split(command.to_string());
+    let (key_path, value) = match args.first().map(|a| a.as_str()) {
+        Some("set") => (args.get(1), args.get(2)),
+        Some("cas") => (args.get(1), args.get(3)),
+        _ => return 0,
+    };
+    let (Some(key_path), Some(value)) = (key_path, value) else {
+        return 0;
+    };
+    let t = DataType::infer_type(value);
+    let Some(value) = DataType::load(t, value.clone()) else {
+        return 0;
+    };
+    let size = key_path.len() + value.approx_size();
+    match db.get_collection(collection) {
+        Some(c) if !key_path.contains('.') => size.saturating_sub(c.key_size(key_path)),
+        _ => size,
+    }
+}
+
+// Runs the commands of a transaction, each one on its collection, all of
+// them or none: if one fails the changes of the others are undone and its
+// error is returned
+pub fn exec(db: &mut InfuseDB, queued: &[(String, String)]) -> Result<Vec<DataType>, CommandError> {
+    let results = RefCell::new(Vec::new());
+    let error = RefCell::new(None);
+    let committed = db.transaction(|tx| {
+        for (collection, cmd) in queued {
+            tx.run(collection, |collection| match collection.run(cmd) {
+                Ok(result) => {
+                    results.borrow_mut().push(result);
+                    Ok(())
+                }
+                Err(err) => {
+                    *error.borrow_mut() = Some(err);
+                    Err("Command failed")
+                }
+            })?;
+        }
+        Ok(())
+    });
+    match committed {
+        Ok(()) => Ok(results.into_inner()),
+        Err(err) => Err(error.into_inner().unwrap_or(CommandError::Custom(err))),
+    }
+}
 
 pub trait Command {
     fn run(&mut self, command: &str) -> Result<DataType, CommandError>;
@@ -15,10 +83,28 @@ pub enum CommandError {
     UnknownCommand,
     ErrorParsing,
     KeyNotFound(String, String),
+    // conditional write of a key whose version is now the one given
+    VersionMismatch(u64),
+    // write that gives a document the value of another in a unique index
+    ConstraintViolation(String, String),
     Custom(&'static str),
 }
 
 impl CommandError {
+    #[cfg(feature = "server")]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CommandError::EmptyCommand => "empty_command",
+            CommandError::NoEnoughArgs => "no_enough_args",
+            CommandError::UnknownCommand => "unknown_command",
+            CommandError::ErrorParsing => "error_parsing",
+            CommandError::KeyNotFound(_, _) => "key_not_found",
+            CommandError::VersionMismatch(_) => "version_mismatch",
+            CommandError::ConstraintViolation(_, _) => "constraint_violation",
+            CommandError::Custom(_) => "custom",
+        }
+    }
+
     pub fn to_string(&self) -> String {
         match self {
             CommandError::EmptyCommand => "Command is empty".to_string(),
@@ -28,11 +114,234 @@ impl CommandError {
             CommandError::KeyNotFound(key, parent) => {
                 format!("Key {} does not exist in {}", key, parent)
             }
+            CommandError::VersionMismatch(version) => {
+                format!("Version mismatch, current version is {}", version)
+            }
+            CommandError::ConstraintViolation(index, value) => {
+                format!("Unique index {} already has the value {}", index, value)
+            }
             CommandError::Custom(custom) => format!("Unknown error: {}", custom),
         }
     }
 }
 
+// Splits a where expression in words, values and parentheses, keeping
+// quoted texts, regular expressions, arrays and documents whole
+fn where_tokens(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut depth = 0;
+    for chr in text.chars() {
+        match (quote, chr) {
+            (Some(q), _) => {
+                token.push(chr);
+                if chr == q && !escaped {
+                    quote = None;
+                }
+                escaped = chr == '\\' && !escaped;
+            }
+            (None, '"' | '\'') => {
+                token.push(chr);
+                quote = Some(chr);
+            }
+            // a regular expression /.../
+            (None, '/') if token.is_empty() && depth == 0 => {
+                token.push(chr);
+                quote = Some(chr);
+            }
+            (None, '[' | '{') => {
+                token.push(chr);
+                depth += 1;
+            }
+            (None, ']' | '}') => {
+                token.push(chr);
+                depth -= 1;
+            }
+            (None, '(' | ')') if depth == 0 => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+                tokens.push(chr.to_string());
+            }
+            (None, _) if chr.is_whitespace() && depth == 0 => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            _ => token.push(chr),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+// Conditions <sub key> <op> <value> combined with and, or, not
+// and parentheses. not binds tighter than and, and tighter than or
+fn parse_where(args: &[String]) -> Result<Filter, CommandError> {
+    let tokens = where_tokens(&args.join(" "));
+    let mut parser = WhereParser {
+        tokens: &tokens,
+        pos: 0,
+    };
+    let filter = parser.or()?;
+    if parser.pos != tokens.len() {
+        return Err(CommandError::ErrorParsing);
+    }
+    Ok(filter)
+}
+
+struct WhereParser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+}
+
+impl<'a> WhereParser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(|t| t.as_str())
+    }
+
+    fn next(&mut self) -> Result<&'a str, CommandError> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or(CommandError::NoEnoughArgs)?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn or(&mut self) -> Result<Filter, CommandError> {
+        let mut filter = self.and()?;
+        while self.peek() == Some("or") {
+            self.pos += 1;
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, CommandError> {
+        let mut filter = self.not()?;
+        while self.peek() == Some("and") {
+            self.pos += 1;
+            filter = Filter::And(Box::new(filter), Box::new(self.not()?));
+        }
+        Ok(filter)
+    }
+
+    fn not(&mut self) -> Result<Filter, CommandError> {
+        match self.peek() {
+            Some("not") => {
+                self.pos += 1;
+                Ok(Filter::Not(Box::new(self.not()?)))
+            }
+            Some("(") => {
+                self.pos += 1;
+                let filter = self.or()?;
+                if self.next()? != ")" {
+                    return Err(CommandError::ErrorParsing);
+                }
+                Ok(filter)
+            }
+            _ => self.condition(),
+        }
+    }
+
+    fn value(&mut self) -> Result<DataType, CommandError> {
+        let value = self.next()?;
+        let t = DataType::infer_type(value);
+        DataType::load(t, value.to_string()).ok_or(CommandError::ErrorParsing)
+    }
+
+    fn condition(&mut self) -> Result<Filter, CommandError> {
+        let sub_key = self.next()?.to_string();
+        if ["(", ")", "and", "or"].contains(&sub_key.as_str()) {
+            return Err(CommandError::ErrorParsing);
+        }
+        let mut op = self.next()?;
+        let negated = op == "not" && self.peek() != Some("is") && self.peek() != Some("in");
+        if op == "not" {
+            op = match self.next()? {
+                "is" => "!=",
+                "in" => "notin",
+                op => op,
+            };
+        }
+        let op = match op {
+            "==" | "is" => FindOp::Eq,
+            "isnot" | "notis" | "!=" => FindOp::NotEq,
+            ">" | "gt" => FindOp::Gt,
+            "<" | "lt" => FindOp::Lt,
+            ">=" | "ge" => FindOp::Ge,
+            "<=" | "le" => FindOp::Le,
+            "in" => FindOp::In,
+            "notin" => FindOp::NotIn,
+            "exists" => FindOp::Exists,
+            "contains" => FindOp::Contains,
+            "startswith" => FindOp::StartsWith,
+            "endswith" => FindOp::EndsWith,
+            "matches" => {
+                // matches /regex/ or "regex"
+                let pattern = self.next()?;
+                let pattern = match pattern.strip_prefix('/') {
+                    Some(p) => p.strip_suffix('/').ok_or(CommandError::ErrorParsing)?,
+                    None => pattern.trim_matches('"'),
+                };
+                FindOp::matches(pattern).map_err(CommandError::Custom)?
+            }
+            "between" => FindOp::Between,
+            _ => return Err(CommandError::ErrorParsing),
+        };
+        let value = match op {
+            FindOp::Exists => DataType::Boolean(true),
+            FindOp::Matches(ref regex) => DataType::from(regex.as_str()),
+            // between <from> <to>
+            FindOp::Between => DataType::Array(vec![self.value()?, self.value()?]),
+            FindOp::In | FindOp::NotIn => match self.value()? {
+                array @ DataType::Array(_) => array,
+                _ => return Err(CommandError::ErrorParsing),
+            },
+            _ => self.value()?,
+        };
+        let condition = Filter::Condition(sub_key, op, value);
+        // <sub key> not <op> <value>, for the ops without a negated form
+        if negated {
+            return Ok(Filter::Not(Box::new(condition)));
+        }
+        Ok(condition)
+    }
+}
+
+fn cas_command(
+    collection: &mut Collection,
+    key_path: &str,
+    value: DataType,
+    version: u64,
+) -> Result<DataType, CommandError> {
+    let key = key_path.split('.').next().unwrap_or_default();
+    let current = collection.version(key);
+    if current != version {
+        return Err(CommandError::VersionMismatch(current));
+    }
+    set_command(collection, key_path, value)
+}
+
+fn set_command(
+    collection: &mut Collection,
+    key_path: &str,
+    value: DataType,
+) -> Result<DataType, CommandError> {
+    if let Some((index, used)) = collection.unique_violation(key_path, &value) {
+        let (index, used) = (index.to_string(), used.to_string());
+        return Err(CommandError::ConstraintViolation(index, used));
+    }
+    collection
+        .set_path(key_path, value)
+        .map_err(CommandError::Custom)
+}
+
 impl Command for Collection {
     fn run(&mut self, command: &str) -> Result<DataType, CommandError> {
         let command: Vec<String> = utils::smart_split(command.to_string());
@@ -46,38 +355,78 @@ impl Command for Collection {
                     return Err(CommandError::NoEnoughArgs);
                 }
                 let key = args.get(0).unwrap().as_str();
-                let keys: Vec<&str> = key.split(".").collect();
                 let value = args.get(1).unwrap().to_string();
                 let t = DataType::infer_type(&value);
                 let d = DataType::load(t, value).ok_or(CommandError::ErrorParsing)?;
-                let mut parent = &mut self.data;
-                let inter_keys = keys[0..keys.len() - 1].to_vec();
-                for i in 0..inter_keys.len() {
-                    let k = inter_keys[i];
-                    let p = parent.get(k);
-                    if p.is_none() {
-                        let dt = if i + 1 < inter_keys.len() {
-                            let k1 = inter_keys[i + 1];
-                            if k1.parse::<usize>().is_ok() {
-                                DataType::Array(Vec::new())
-                            } else {
-                                DataType::Document(HashMap::new())
-                            }
-                        } else {
-                            DataType::Document(HashMap::new())
-                        };
-                        let _ = parent.set(k, dt);
-                    }
-                    parent = parent.get_mut(k).unwrap();
+                // set key.path value [if-version <n>] [ttl <seconds>]
+                let mut args = &args[..];
+                let mut ttl = None;
+                if args.len() >= 4 && args[args.len() - 2] == "ttl" {
+                    let secs = args[args.len() - 1]
+                        .parse::<f32>()
+                        .map_err(|_| CommandError::ErrorParsing)?;
+                    ttl = Some(
+                        Duration::try_from_secs_f32(secs)
+                            .map_err(|_| CommandError::ErrorParsing)?,
+                    );
+                    args = &args[..args.len() - 2];
                 }
-
-                parent
-                    .set(
-                        keys.last()
-                            .ok_or(CommandError::KeyNotFound("?".to_string(), "??".to_string()))?,
-                        d,
-                    )
-                    .map_err(|_| CommandError::Custom("()"))
+                let result = if args.len() == 4 && args[2] == "if-version" {
+                    let version = args[3].parse().map_err(|_| CommandError::ErrorParsing)?;
+                    cas_command(self, key, d, version)?
+                } else {
+                    set_command(self, key, d)?
+                };
+                if let Some(ttl) = ttl {
+                    self.expire(key.split('.').next().unwrap_or_default(), ttl);
+                }
+                Ok(result)
+            }
+            "expire" => {
+                // expire key <seconds>
+                if args.len() < 2 {
+                    return Err(CommandError::NoEnoughArgs);
+                }
+                let secs = args[1]
+                    .parse::<f32>()
+                    .map_err(|_| CommandError::ErrorParsing)?;
+                let ttl =
+                    Duration::try_from_secs_f32(secs).map_err(|_| CommandError::ErrorParsing)?;
+                if !self.expire(&args[0], ttl) {
+                    return Err(CommandError::KeyNotFound(
+                        args[0].clone(),
+                        "Collection".to_string(),
+                    ));
+                }
+                Ok(DataType::Boolean(true))
+            }
+            "ttl" => {
+                // seconds left, false if the key does not expire
+                let key = args.first().ok_or(CommandError::NoEnoughArgs)?;
+                if self.get(key).is_none() {
+                    return Err(CommandError::KeyNotFound(
+                        key.clone(),
+                        "Collection".to_string(),
+                    ));
+                }
+                match self.ttl(key) {
+                    Some(left) => Ok(DataType::Number(left.as_secs_f32())),
+                    None => Ok(DataType::Boolean(false)),
+                }
+            }
+            "persist" => {
+                let key = args.first().ok_or(CommandError::NoEnoughArgs)?;
+                Ok(DataType::Boolean(self.persist(key)))
+            }
+            "cas" => {
+                // cas key.path <version> value
+                if args.len() < 3 {
+                    return Err(CommandError::NoEnoughArgs);
+                }
+                let version = args[1].parse().map_err(|_| CommandError::ErrorParsing)?;
+                let t = DataType::infer_type(&args[2]);
+                let d = DataType::load(t, args[2].clone()).ok_or(CommandError::ErrorParsing)?;
+                cas_command(self, &args[0], d, version)
             }
             "get" => {
                 // get key.path [where <subkey> <is|not is|gr|ls> <value>]
@@ -86,7 +435,37 @@ impl Command for Collection {
                     return Err(CommandError::NoEnoughArgs);
                 }
                 let proto_key = args.get(0).unwrap().as_str();
-                let is_search = args.len() == 5 && args[1] == "where"; //TODO: improve the comparison selector
+                let with_version = args.last().is_some_and(|a| a == "with-version");
+                let args = if with_version {
+                    &args[..args.len() - 1]
+                } else {
+                    &args[..]
+                };
+                if args.len() == 3 && args[1] == "at" {
+                    // get key.path at <time>
+                    let time =
+                        utils::parse_timestamp(&args[2]).ok_or(CommandError::ErrorParsing)?;
+                    return self
+                        .get_at(proto_key, time)
+                        .ok_or(CommandError::KeyNotFound(
+                            proto_key.to_string(),
+                            "History".to_string(),
+                        ));
+                }
+                // get key.path where <expression>
+                let search = match args.get(1) {
+                    Some(w) if w == "where" => Some(parse_where(&args[2..])?),
+                    Some(_) => return Err(CommandError::ErrorParsing),
+                    None => None,
+                };
+                // an index on the items answers a single condition without a scan
+                let positions = match &search {
+                    Some(Filter::Condition(sub_key, op, value)) => {
+                        self.find_items(proto_key, sub_key, op, value)
+                    }
+                    _ => None,
+                };
+                let version = self.version(proto_key.split('.').next().unwrap_or_default());
 
                 let keys: Vec<&str> = proto_key.split('.').collect();
                 let value = self.get(keys[0]).ok_or(CommandError::KeyNotFound(
@@ -106,31 +485,26 @@ impl Command for Collection {
                         name.push_str(&format!("[{}]", i));
                     }
 
-                    value_pointer.clone()
+                    value_pointer
                 } else {
-                    value.clone()
+                    value
                 };
-                if is_search {
-                    let sub_key = args[2].clone();
-                    let t = DataType::infer_type(&args[4]);
-                    let v = DataType::load(t, args[4].clone());
-                    if v.is_none() {
-                        return Err(CommandError::ErrorParsing);
+                if let Some(filter) = search {
+                    if let (Some(positions), DataType::Array(items)) = (positions, get_result) {
+                        let found = positions.iter().filter_map(|i| items.get(*i));
+                        return Ok(DataType::Array(found.cloned().collect()));
                     }
-                    let value = v.unwrap();
-                    let op = match args[3].as_str() {
-                        "==" | "is" => FindOp::Eq,
-                        "isnot" | "notis" | "!=" => FindOp::NotEq,
-                        ">" | "gt" => FindOp::Gt,
-                        "<" | "lt" => FindOp::Lt,
-                        _ => return Err(CommandError::ErrorParsing),
-                    };
-                    match get_result.find(&sub_key, op, value) {
+                    match get_result.find(&filter) {
                         Some(d) => Ok(d.clone()),
-                        None => Err(CommandError::KeyNotFound(sub_key, "Search".to_string())),
+                        None => Err(CommandError::KeyNotFound(
+                            proto_key.to_string(),
+                            "Search".to_string(),
+                        )),
                     }
+                } else if with_version {
+                    Ok(doc!("value" => get_result.clone(), "version" => version as f32))
                 } else {
-                    Ok(get_result)
+                    Ok(get_result.clone())
                 }
             }
             "del" => {
@@ -141,8 +515,478 @@ impl Command for Collection {
                 self.rm(key);
                 Ok(DataType::Boolean(true))
             }
+            "history" => {
+                let key = args.first().ok_or(CommandError::NoEnoughArgs)?;
+                if self.retention().is_none() {
+                    return Err(CommandError::Custom("History is not enabled"));
+                }
+                let records = self
+                    .history(key)
+                    .into_iter()
+                    .map(|(time, value)| {
+                        let ts = utils::format_timestamp(time);
+                        match value {
+                            Some(value) => doc!("ts" => ts, "value" => value),
+                            None => doc!("ts" => ts, "deleted" => true),
+                        }
+                    })
+                    .collect();
+                Ok(DataType::Array(records))
+            }
+            "restore" => {
+                // restore key <time>
+                if args.len() < 2 {
+                    return Err(CommandError::NoEnoughArgs);
+                }
+                let time = utils::parse_timestamp(&args[1]).ok_or(CommandError::ErrorParsing)?;
+                let value = self.restore(&args[0], time).map_err(CommandError::Custom)?;
+                Ok(value.unwrap_or(DataType::Boolean(false)))
+            }
+            "retention" => {
+                // retention [off | versions <n> | age <seconds>]...
+                if args.first().is_some_and(|a| a == "off") {
+                    self.set_retention(None);
+                } else if !args.is_empty() {
+                    let mut retention = self.retention().unwrap_or_default();
+                    for pair in args.chunks(2) {
+                        let value = pair.get(1).ok_or(CommandError::NoEnoughArgs)?;
+                        let value = value
+                            .parse::<u64>()
+                            .map_err(|_| CommandError::ErrorParsing)?;
+                        match pair[0].as_str() {
+                            "versions" => retention.versions = Some(value as usize),
+                            "age" => retention.max_age = Some(Duration::from_secs(value)),
+                            _ => return Err(CommandError::ErrorParsing),
+                        }
+                    }
+                    self.set_retention(Some(retention));
+                }
+                match self.retention() {
+                    Some(retention) => {
+                        let mut limits = HashMap::new();
+                        if let Some(versions) = retention.versions {
+                            limits.insert("versions".to_string(), DataType::from(versions as f32));
+                        }
+                        if let Some(age) = retention.max_age {
+                            limits.insert("age".to_string(), DataType::from(age.as_secs() as f32));
+                        }
+                        Ok(DataType::Document(limits))
+                    }
+                    None => Ok(DataType::Boolean(false)),
+                }
+            }
+            "maxmemory" => {
+                // maxmemory [off | <bytes> [lru|lfu|random|volatile-ttl|noeviction]]
+                if args.first().is_some_and(|a| a == "off") {
+                    self.set_memory_limit(None);
+                } else if let Some(max_bytes) = args.first() {
+                    let max_bytes = max_bytes.parse().map_err(|_| CommandError::ErrorParsing)?;
+                    let policy = args.get(1).map(|p| p.as_str()).unwrap_or("lru");
+                    let policy = EvictionPolicy::parse(policy).ok_or(CommandError::ErrorParsing)?;
+                    self.set_memory_limit(Some(MemoryLimit { max_bytes, policy }));
+                }
+                let mut status = HashMap::new();
+                if let Some(limit) = self.memory_limit() {
+                    status.insert("max".to_string(), DataType::from(limit.max_bytes as f32));
+                    status.insert("policy".to_string(), DataType::from(limit.policy.as_str()));
+                }
+                status.insert(
+                    "used".to_string(),
+                    DataType::from(self.approx_size() as f32),
+                );
+                status.insert("evicted".to_string(), DataType::from(self.evicted() as f32));
+                Ok(DataType::Document(status))
+            }
+            "fulltext" => {
+                // fulltext [off | on [<field path>]]
+                match args.first().map(|a| a.as_str()) {
+                    Some("off") => self.set_text_index(None),
+                    Some("on") => {
+                        let spec = args.get(1).map(|f| f.as_str()).unwrap_or("*");
+                        self.set_text_index(Some(spec));
+                    }
+                    Some(_) => return Err(CommandError::ErrorParsing),
+                    None => {}
+                }
+                let mut status = HashMap::new();
+                let text_index = self.text_index();
+                status.insert("enabled".to_string(), DataType::from(text_index.is_some()));
+                if let Some(text_index) = text_index {
+                    status.insert("field".to_string(), DataType::from(text_index.spec()));
+                    status.insert("keys".to_string(), DataType::from(text_index.len() as f32));
+                    status.insert(
+                        "terms".to_string(),
+                        DataType::from(text_index.terms() as f32),
+                    );
+                }
+                Ok(DataType::Document(status))
+            }
+            "search" => {
+                // search [key.path] "<terms>"
+                if args.is_empty() {
+                    return Err(CommandError::NoEnoughArgs);
+                }
+                let in_key = args.len() > 1 && !args[0].starts_with('"');
+                let query = args[in_key as usize..].join(" ");
+                let query = query.trim_matches('"');
+                let found = if in_key {
+                    self.search_in(&args[0], query)
+                        .ok_or(CommandError::KeyNotFound(
+                            args[0].clone(),
+                            self.name.clone(),
+                        ))?
+                } else {
+                    self.search(query)
+                };
+                let found = found
+                    .into_iter()
+                    .map(|(key, score)| doc!("key" => key, "score" => score))
+                    .collect();
+                Ok(DataType::Array(found))
+            }
+            "knn" => {
+                // knn <* | *.field | key.path | key.path[*].field> <vector> k <n>
+                //     [metric cosine|l2|dot] [where <expression>]
+                if args.len() < 4 {
+                    return Err(CommandError::NoEnoughArgs);
+                }
+                let t = DataType::infer_type(&args[1]);
+                let query = DataType::load(t, args[1].clone()).ok_or(CommandError::ErrorParsing)?;
+                let query = to_vector(&query).ok_or(CommandError::ErrorParsing)?;
+                if args[2] != "k" {
+                    return Err(CommandError::ErrorParsing);
+                }
+                let k = args[3].parse().map_err(|_| CommandError::ErrorParsing)?;
+                let mut rest = &args[4..];
+                let mut metric = Metric::Cosine;
+                if rest.first().is_some_and(|a| a == "metric") {
+                    let name = rest.get(1).ok_or(CommandError::NoEnoughArgs)?;
+                    metric = Metric::parse(name).ok_or(CommandError::ErrorParsing)?;
+                    rest = &rest[2..];
+                }
+                let filter = match rest.first() {
+                    Some(w) if w == "where" => Some(parse_where(&rest[1..])?),
+                    Some(_) => return Err(CommandError::ErrorParsing),
+                    None => None,
+                };
+                let found = self
+                    .knn(&args[0], &query, k, metric, |value| {
+                        filter.as_ref().is_none_or(|f| f.matches(value))
+                    })
+                    .map_err(CommandError::Custom)?;
+                let found = found
+                    .into_iter()
+                    .map(|(key, distance)| doc!("key" => key, "distance" => distance))
+                    .collect();
+                Ok(DataType::Array(found))
+            }
+            "create" => {
+                // create [unique] index <name> on <*.field | key.path[*].field>
+                // create vector index <name> on <* | *.field> [metric cosine|l2|dot]
+                let kind = args.first().map(|a| a.as_str());
+                let kind = kind.filter(|k| *k == "unique" || *k == "vector");
+                let args = &args[kind.is_some() as usize..];
+                if args.len() < 4 {
+                    return Err(CommandError::NoEnoughArgs);
+                }
+                if args[0] != "index" || args[2] != "on" {
+                    return Err(CommandError::ErrorParsing);
+                }
+                let created = match kind {
+                    Some("unique") => self.create_unique_index(&args[1], &args[3]),
+                    Some(_) => {
+                        let metric = match &args[4..] {
+                            [] => Metric::Cosine,
+                            [m, name] if m == "metric" => {
+                                Metric::parse(name).ok_or(CommandError::ErrorParsing)?
+                            }
+                            _ => return Err(CommandError::ErrorParsing),
+                        };
+                        self.create_vector_index(&args[1], &args[3], metric)
+                    }
+                    None => self.create_index(&args[1], &args[3]),
+                };
+                created.map_err(CommandError::Custom)?;
+                Ok(DataType::Boolean(true))
+            }
+            "drop" => {
+                // drop index <name>
+                if args.len() < 2 {
+                    return Err(CommandError::NoEnoughArgs);
+                }
+                if args[0] != "index" {
+                    return Err(CommandError::ErrorParsing);
+                }
+                Ok(DataType::Boolean(self.drop_index(&args[1])))
+            }
+            "indexes" => {
+                let indexes = self.indexes().into_iter();
+                let mut indexes: HashMap<String, DataType> = indexes
+                    .map(|(name, spec, unique)| {
+                        let spec = if unique {
+                            format!("unique {}", spec)
+                        } else {
+                            spec
+                        };
+                        (name.to_string(), DataType::from(spec))
+                    })
+                    .collect();
+                for (name, spec, metric) in self.vector_indexes() {
+                    let spec = format!("vector {} {}", spec, metric.as_str());
+                    indexes.insert(name.to_string(), DataType::from(spec));
+                }
+                Ok(DataType::Document(indexes))
+            }
             "name" => Ok(doc!("name" => self.name.clone())),
             _ => Err(CommandError::UnknownCommand),
         };
     }
 }
+
+#[cfg(test)]
+#[test]
+fn test_where() {
+    let mut collection = Collection::new("todos");
+    let todos = [
+        (false, 3, "eva"),
+        (false, 1, "ana"),
+        (true, 5, "ana"),
+        (false, 1, "eva"),
+    ];
+    let todos = todos.iter().enumerate().map(|(i, (done, priority, owner))| {
+        doc!("done" => *done, "priority" => *priority, "owner" => *owner, "id" => i as i32)
+    });
+    collection
+        .add("todos", DataType::Array(todos.collect()))
+        .unwrap();
+    let ids = |collection: &mut Collection, command: &str| match collection.run(command) {
+        Ok(DataType::Array(found)) => found
+            .iter()
+            .map(|t| t.get("id").unwrap().to_number() as usize)
+            .collect::<Vec<_>>(),
+        _ => panic!("{} failed", command),
+    };
+    let command = r#"get todos where done is false and (priority > 2 or owner is "ana")"#;
+    assert_eq!(ids(&mut collection, command), vec![0, 1]);
+    let command = "get todos where not (done is false or priority < 2)";
+    assert_eq!(ids(&mut collection, command), vec![2]);
+    let command = r#"get todos where owner not is "eva" and not done is true"#;
+    assert_eq!(ids(&mut collection, command), vec![1]);
+    assert!(collection.run("get todos where (done is false").is_err());
+    assert!(collection.run("get todos where done is false or").is_err());
+    assert!(collection.run("get todos where done").is_err());
+
+    let command = r#"get todos where priority >= 3 and owner in ["eva", "bob"]"#;
+    assert_eq!(ids(&mut collection, command), vec![0]);
+    let command = "get todos where priority between 2 5 and owner matches /^(an a|ana)$/";
+    assert_eq!(ids(&mut collection, command), vec![2]);
+    let command = r#"get todos where owner startswith "e" and id not in [3]"#;
+    assert_eq!(ids(&mut collection, command), vec![0]);
+    let command = r#"get todos where owner not contains "v" and due not exists"#;
+    assert_eq!(ids(&mut collection, command), vec![1, 2]);
+    assert!(collection.run("get todos where owner in ana").is_err());
+    assert!(collection.run("get todos where owner matches /(/").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_where_paths() {
+    let mut collection = Collection::new("orders");
+    let orders = [
+        r#"set orders [{id: 0, tags: ["gift"], customer: {city: "Lima"}}]"#,
+        r#"set orders.+ {id: 1, tags: ["urgent", "gift"], customer: {city: "Madrid"}}"#,
+        r#"set orders.+ {id: 2, tags: [], customer: {city: "Madrid", zip: "28001"}}"#,
+    ];
+    for command in orders {
+        assert!(collection.run(command).is_ok());
+    }
+    let ids = |collection: &mut Collection, command: &str| match collection.run(command) {
+        Ok(DataType::Array(found)) => found
+            .iter()
+            .map(|t| t.get("id").unwrap().to_number() as usize)
+            .collect::<Vec<_>>(),
+        _ => panic!("{} failed", command),
+    };
+    let command = r#"get orders where customer.city is "Madrid""#;
+    assert_eq!(ids(&mut collection, command), vec![1, 2]);
+    let command = r#"get orders where tags.* is "gift" and not tags.* is "urgent""#;
+    assert_eq!(ids(&mut collection, command), vec![0]);
+    let command = r#"get orders where tags.0 is "urgent""#;
+    assert_eq!(ids(&mut collection, command), vec![1]);
+    // != and not in need every tag to differ, and some tag to be there
+    let command = r#"get orders where tags.* != "urgent""#;
+    assert_eq!(ids(&mut collection, command), vec![0]);
+    let command = r#"get orders where tags.* not in ["urgent", "late"]"#;
+    assert_eq!(ids(&mut collection, command), vec![0]);
+    let command = r#"get orders where customer.zip != "28001""#;
+    assert!(ids(&mut collection, command).is_empty());
+
+    // the same items with indexes on the paths
+    let commands = [
+        r#"get orders where customer.city is "Madrid""#,
+        r#"get orders where tags.* is "gift""#,
+        r#"get orders where tags.* > "h""#,
+        r#"get orders where tags.* != "urgent""#,
+        r#"get orders where not tags.* is "urgent""#,
+        r#"get orders where customer.zip between "2" "3""#,
+    ];
+    let scans: Vec<_> = commands.iter().map(|c| ids(&mut collection, c)).collect();
+    for (name, spec) in [
+        ("by_city", "orders[*].customer.city"),
+        ("by_tag", "orders[*].tags.*"),
+        ("by_zip", "orders[*].customer.zip"),
+    ] {
+        collection.create_index(name, spec).unwrap();
+    }
+    for (command, scan) in commands.iter().zip(scans) {
+        assert_eq!(ids(&mut collection, command), scan, "{}", command);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_indexed_where() {
+    let mut collection = Collection::new("todos");
+    let priorities = [3, 1, 5, 1, 4, 2];
+    let todos = priorities.iter().enumerate().map(|(i, priority)| {
+        let owner = ["ana", "eva", "bob"][i % 3];
+        let tags = [["home", "work"], ["work", "urgent"], ["home", "home"]][i % 3];
+        let tags = DataType::Array(tags.iter().map(|t| DataType::from(*t)).collect());
+        let mut todo = doc!("priority" => *priority, "owner" => owner, "id" => i as i32);
+        todo.set("tags", tags).unwrap();
+        // a key with a dot in it
+        todo.set("due.day", DataType::from(i as i32 % 4)).unwrap();
+        todo
+    });
+    let todos = DataType::Array(todos.collect());
+    collection.add("todos", todos).unwrap();
+    let commands = [
+        "get todos where priority == 1",
+        "get todos where priority < 3",
+        "get todos where priority > 3",
+        "get todos where priority > 10",
+        "get todos where priority between 2 4",
+        r#"get todos where owner is "eva""#,
+        r#"get todos where owner > "bob""#,
+        r#"get todos where owner between "a" "c""#,
+        r#"get todos where tags.* is "home""#,
+        r#"get todos where tags.* < "urgent""#,
+        r#"get todos where tags.* > "urgent""#,
+        r#"get todos where tags.* between "u" "z""#,
+        "get todos where due.day == 1",
+        "get todos where due.day < 2",
+        "get todos where due.day > 2",
+        "get todos where due.day between 1 2",
+    ];
+    let scans: Vec<_> = commands.iter().map(|c| collection.run(c).ok()).collect();
+    collection
+        .create_index("by_priority", "todos[*].priority")
+        .unwrap();
+    collection
+        .create_index("by_owner", "todos[*].owner")
+        .unwrap();
+    collection
+        .create_index("by_tag", "todos[*].tags.*")
+        .unwrap();
+    collection
+        .create_index("by_due", "todos[*].due.day")
+        .unwrap();
+    // the index gives the same items as the scan, in the order of the array
+    for (command, scan) in commands.iter().zip(scans) {
+        assert!(scan.is_some(), "{} failed", command);
+        assert_eq!(collection.run(command).ok(), scan, "{}", command);
+    }
+    let found = collection.run("get todos where priority between 2 4");
+    let ids = match found {
+        Ok(DataType::Array(found)) => found.iter().map(|t| t.get("id").cloned()).collect(),
+        _ => Vec::new(),
+    };
+    assert_eq!(ids, [0, 4, 5].map(|i| Some(DataType::from(i))));
+}
+
+#[cfg(test)]
+#[test]
+fn test_exec() {
+    let mut db = InfuseDB::new();
+    db.create_collection("users").unwrap();
+    db.create_collection("posts").unwrap();
+    let queue = |commands: &[(&str, &str)]| -> Vec<(String, String)> {
+        let commands = commands.iter();
+        commands
+            .map(|(c, cmd)| (c.to_string(), cmd.to_string()))
+            .collect()
+    };
+
+    let queued = queue(&[
+        ("users", "set a 1"),
+        ("posts", "set p 2"),
+        ("users", "get a"),
+    ]);
+    let results = exec(&mut db, &queued).ok().unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[2], DataType::from(1));
+
+    // the third command fails, the first two are undone
+    let queued = queue(&[
+        ("users", "set a 5"),
+        ("posts", "del p"),
+        ("users", "cas a 0 3"),
+    ]);
+    let err = exec(&mut db, &queued).err().unwrap();
+    assert!(matches!(err, CommandError::VersionMismatch(_)));
+    assert_eq!(
+        db.get_collection("users").unwrap().get("a"),
+        Some(&DataType::from(1))
+    );
+    assert!(db.get_collection("posts").unwrap().get("p").is_some());
+
+    // the indexes and settings are put back too
+    let queued = queue(&[
+        ("users", "create index by_a on *.a"),
+        ("posts", "create vector index by_v on * metric l2"),
+        ("users", "fulltext on name"),
+        ("users", "maxmemory 1024 lru"),
+        ("users", "retention versions 2"),
+        ("users", "cas a 0 3"),
+    ]);
+    assert!(exec(&mut db, &queued).is_err());
+    let users = db.get_collection("users").unwrap();
+    assert!(users.indexes().is_empty());
+    assert!(users.text_index().is_none());
+    assert!(users.memory_limit().is_none());
+    assert!(users.retention().is_none());
+    assert!(
+        db.get_collection("posts")
+            .unwrap()
+            .vector_indexes()
+            .is_empty()
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_make_room() {
+    let mut db = InfuseDB::new();
+    let users = db.create_collection("users").unwrap();
+    users.run("set a 1").ok().unwrap();
+    users.run("set b 2").ok().unwrap();
+    let max_bytes = db.approx_size();
+    db.set_memory_limit(Some(MemoryLimit {
+        max_bytes,
+        policy: EvictionPolicy::NoEviction,
+    }));
+    // a value of the same size replaced takes no room, a new key does
+    assert_eq!(write_size(&mut db, "users", "set a 3"), 0);
+    assert!(db.make_room(0).is_ok());
+    let size = write_size(&mut db, "users", "set c 3");
+    assert!(size > 0);
+    assert!(db.make_room(size).is_err());
+    assert_eq!(write_size(&mut db, "users", "del a"), 0);
+
+    db.set_memory_limit(Some(MemoryLimit {
+        max_bytes,
+        policy: EvictionPolicy::Lru,
+    }));
+    assert_eq!(db.make_room(size), Ok(1));
+    assert!(db.approx_size() + size <= max_bytes);
+}
@@ -9,13 +9,72 @@ pub const HELP_STR_COL: &'static str = r"Collection commands:
   get <key.path> [where <sub_key> <is|not is|gr|ls> <value>]
       Retrieve the value associated with a key. Nested keys can be accessed with dot notation (e.g., `user.name` or `users.0.name`).
       You can also filter results using where, like: get todo_list where done is true.
+      Combine conditions with and, or, not and parentheses: get todos where done is false and (priority > 2 or owner is 'ana').
+      Operators: is, not is, >, <, >=, <=, in [..], not in [..], exists, contains, startswith, endswith, matches /regex/, between <from> <to>.
+      The sub key can be a dotted path (customer.address.city, items.0.name), with * for any item: get orders where tags.* is urgent.
+      With * the != and not in operators need every item to differ: get orders where tags.* != urgent.
+      Add with-version to get the value together with the version of its top level key.
 
-  set <key.path> <value>
+  set <key.path> <value> [if-version <n>] [ttl <seconds>]
       Set the value of a key. Supports nested keys with dot notation. Value type is inferred automatically (string, number, bool, etc).
+      With if-version, the value is only set if the top level key still has that version.
+      With ttl, the top level key is deleted after the given seconds.
+
+  cas <key.path> <version> <value>
+      Same as set with if-version, version 0 means the key does not exist.
+
+  expire <key> <seconds>
+      Delete the key after the given seconds.
+
+  ttl <key>
+      Seconds left before the key expires, false if it does not expire.
+
+  persist <key>
+      Remove the ttl of the key.
 
   del <key.path>
       Delete a key or nested key from the current collection.
 
+  retention [off | versions <n> | age <seconds>]
+      Keep the previous values of the keys, up to n values per key or for the given seconds.
+
+  get <key.path> at <time>
+      Value of a key at a past time, given as 2024-03-08T10:30:00Z, milliseconds since the epoch or -<seconds> ago.
+
+  history <key>
+      Previous values of a key with the time they were written.
+
+  restore <key> <time>
+      Set a key back to its value at a past time.
+
+  create index <name> on <key.path>[*].<field>
+      Index a field of the items of an array, get ... where uses it for ==, < and >. Use *.<field> for a field of the documents of the collection.
+
+  create unique index <name> on *.<field>
+      Index a field of the documents and fail the writes that give a document a value another one already has.
+
+  create vector index <name> on <* | *.<field>> [metric cosine|l2|dot]
+      Keep the vectors (arrays of numbers) of the keys in a HNSW graph, knn uses it for the same vectors and metric.
+
+  knn <* | *.<field> | key.path | key.path[*].<field>> <vector> k <n> [metric cosine|l2|dot] [where <expression>]
+      The n keys with the closest vectors, cosine by default. Only keys whose value matches the where are considered.
+      With a key path the vectors are the items of the array there, and the keys their positions.
+
+  drop index <name>
+      Remove an index.
+
+  indexes
+      List the indexes of the collection.
+
+  maxmemory [off | <bytes> [lru|lfu|random|volatile-ttl|noeviction]]
+      Limit the approximate memory of the collection. Keys are evicted with the policy (lru by default) or, with noeviction, writes fail.
+
+  fulltext [off | on [<field path>]]
+      Keep a full-text index of the texts of the keys, or only of a field of the documents. Saved and rebuilt on load.
+
+  search [key.path] <terms>
+      Keys with any of the terms ranked by relevance (BM25), or the fields or items of key.path.
+
   name
       Show the name of the currently selected collection.";
 
@@ -40,9 +99,27 @@ pub const HELP_STR_MAIN: &'static str = r"Available commands:
         Delete the selected collection.
         If no collection is selected, you must provide the name of the collection to delete.
 
+    search <collection> <terms>
+        Keys of the collection with any of the terms, ranked by relevance.
+
     commit
         Save all changes made to the database.
 
+    begin
+        Start a transaction, the following commands are queued until exec.
+
+    exec
+        Run the queued commands and show their results, all of them or none: if one fails the others are undone.
+
+    discard
+        Drop the queued commands.
+
+    undo [list]
+        Revert the last change made in this session, with list show the changes that would be reverted.
+
+    redo
+        Make again the last change reverted by undo.
+
     Notes:
     - You must select a collection using 'select' before performing actions on it.
     - If a collection is not selected, 'list' will show all available collections.";
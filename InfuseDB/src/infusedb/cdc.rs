@@ -0,0 +1,102 @@
+// Change data capture
+// Appends one NDJSON record per mutation to a rotating file. Records carry
+// a sequence number that continues from the last one written, so a consumer
+// can resume after a restart of the database without gaps
+use super::change::ChangeEvent;
+use super::rotating_file::RotatingFile;
+use crate::utils::json_string;
+use std::fs;
+use std::io;
+
+pub struct CdcSink {
+    file: RotatingFile,
+    seq: u64,
+}
+
+// Sequence number of the last record in the file, or in the last rotated one
+fn last_sequence(path: &str) -> u64 {
+    for candidate in [path.to_string(), format!("{}.1", path)] {
+        let Ok(contents) = fs::read_to_string(&candidate) else {
+            continue;
+        };
+        let last = contents.lines().rev().find(|l| !l.trim().is_empty());
+        if let Some(line) = last {
+            let seq = line
+                .strip_prefix("{\"seq\": ")
+                .and_then(|rest| rest.split(',').next())
+                .and_then(|n| n.parse::<u64>().ok());
+            if let Some(seq) = seq {
+                return seq;
+            }
+        }
+    }
+    0
+}
+
+impl CdcSink {
+    pub fn open(path: &str, max_size: u64, keep: usize) -> io::Result<Self> {
+        let seq = last_sequence(path);
+        let file = RotatingFile::open(path, max_size, keep)?;
+        Ok(CdcSink { file, seq })
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn record(&mut self, event: &ChangeEvent) -> io::Result<u64> {
+        let value = match &event.new {
+            Some(value) => value.to_json(),
+            None => "null".to_string(),
+        };
+        let line = format!(
+            "{{\"seq\": {}, \"collection\": {}, \"key\": {}, \"op\": \"{}\", \"value\": {}, \"ts\": {}}}",
+            self.seq + 1,
+            json_string(&event.collection),
+            json_string(&event.key),
+            event.op.as_str(),
+            value,
+            event.timestamp_millis()
+        );
+        self.file.append_line(&line)?;
+        self.seq += 1;
+        Ok(self.seq)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_cdc_resume() {
+    use super::change::ChangeOp;
+    use super::data_type::DataType;
+
+    let dir = std::env::temp_dir().join(format!("infusedb_cdc_{}", std::process::id()));
+    let _ = fs::create_dir_all(&dir);
+    let path = dir.join("cdc.ndjson");
+    let path = path.to_str().unwrap();
+    let event = ChangeEvent::new(
+        "users",
+        "john.age",
+        ChangeOp::Set,
+        None,
+        Some(DataType::from(3)),
+    );
+
+    let mut sink = CdcSink::open(path, 1024, 2).unwrap();
+    assert_eq!(sink.record(&event).unwrap(), 1);
+    assert_eq!(sink.record(&event).unwrap(), 2);
+    drop(sink);
+
+    let mut sink = CdcSink::open(path, 1024, 2).unwrap();
+    assert_eq!(sink.sequence(), 2);
+    assert_eq!(sink.record(&event).unwrap(), 3);
+    let contents = fs::read_to_string(path).unwrap();
+    assert!(
+        contents
+            .lines()
+            .last()
+            .unwrap()
+            .starts_with("{\"seq\": 3, \"collection\": \"users\"")
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,111 @@
+// Change notifications of the collections
+// Every mutation of a collection produces a ChangeEvent that is passed to
+// the registered hooks and sent to the watchers of the affected key path
+use super::data_type::DataType;
+use crate::utils::json_string;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ChangeOp {
+    Set,
+    Del,
+    Drop,
+}
+
+impl ChangeOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeOp::Set => "set",
+            ChangeOp::Del => "del",
+            ChangeOp::Drop => "drop",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChangeEvent {
+    pub collection: String,
+    // dotted key path, empty when the whole collection is dropped
+    pub key: String,
+    pub op: ChangeOp,
+    pub old: Option<DataType>,
+    pub new: Option<DataType>,
+    pub timestamp: SystemTime,
+}
+
+pub type ChangeHook = Arc<dyn Fn(&ChangeEvent) + Send + Sync>;
+
+pub(crate) struct Watcher {
+    pub prefix: Option<String>,
+    pub sender: Sender<ChangeEvent>,
+}
+
+// true if one path contains the other, `user` and `user.name` overlap
+// but `user` and `username` do not
+pub fn paths_overlap(a: &str, b: &str) -> bool {
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    long.starts_with(short) && (long.len() == short.len() || long[short.len()..].starts_with('.'))
+}
+
+impl ChangeEvent {
+    pub fn new(
+        collection: &str,
+        key: &str,
+        op: ChangeOp,
+        old: Option<DataType>,
+        new: Option<DataType>,
+    ) -> Self {
+        ChangeEvent {
+            collection: collection.to_string(),
+            key: key.to_string(),
+            op,
+            old,
+            new,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn timestamp_millis(&self) -> u128 {
+        self.timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+    }
+
+    pub fn to_json(&self) -> String {
+        let value = |v: &Option<DataType>| match v {
+            Some(v) => v.to_json(),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"collection\": {}, \"key\": {}, \"op\": \"{}\", \"old\": {}, \"new\": {}, \"ts\": {}}}",
+            json_string(&self.collection),
+            json_string(&self.key),
+            self.op.as_str(),
+            value(&self.old),
+            value(&self.new),
+            self.timestamp_millis()
+        )
+    }
+}
+
+impl Watcher {
+    pub fn wants(&self, event: &ChangeEvent) -> bool {
+        match &self.prefix {
+            Some(prefix) => event.op == ChangeOp::Drop || paths_overlap(prefix, &event.key),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_paths_overlap() {
+    assert!(paths_overlap("user", "user"));
+    assert!(paths_overlap("user", "user.name"));
+    assert!(paths_overlap("user.name", "user"));
+    assert!(!paths_overlap("user", "username"));
+    assert!(!paths_overlap("user.name", "user.age"));
+}
@@ -3,12 +3,29 @@
 // The collection will store the documents in memory and provide a simple API to interact with them
 // The Document will be a HashMap<String, DataType>
 //
-use super::data_type::DataType;
+use super::change::{ChangeEvent, ChangeHook, ChangeOp, Watcher};
+use super::data_type::{DataType, FindOp};
+use super::eviction::{self, EvictionPolicy, MemoryLimit, OUT_OF_MEMORY, Usage};
+use super::finder::IndexKey;
+use super::fulltext::TextIndex;
+use super::history::{History, Retention};
+use super::index::{Index, UNIQUE_VIOLATION, field_value, op_range};
+use super::snapshot::CollectionSnapshot;
+use super::vector::{self, Metric, VectorIndex};
 use crate::utils;
 use std::collections::HashMap;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub type Document = HashMap<String, DataType>;
-
+// Top level keys of a collection. The map and its values are shared with
+// the snapshots and only copied when written while a snapshot is alive,
+// the map on the first write and each value on its own first write
+pub(crate) type Keys = HashMap<String, Arc<DataType>>;
+// Time at which each key with a ttl expires, shared with the snapshots too
+pub(crate) type Expiries = HashMap<String, SystemTime>;
 
 #[macro_export]
 macro_rules! doc {
@@ -26,13 +43,50 @@ macro_rules! doc {
 
 pub struct Collection {
     pub name: String,
-    pub(crate) data: DataType,
-    //b_tree: BNode
+    pub(crate) data: Arc<Keys>,
+    indexes: Vec<Index>,
+    text_index: Option<TextIndex>,
+    vector_indexes: Vec<VectorIndex>,
+    hooks: Vec<ChangeHook>,
+    watchers: Vec<Watcher>,
+    // revision of the last write to each top level key, the revision of
+    // the collection grows with every write so a key never gets a version twice
+    versions: HashMap<String, u64>,
+    revision: u64,
+    history: Option<History>,
+    // expired keys are hidden until reaped
+    expires: Arc<Expiries>,
+    // approximate size of the keys and values
+    used: usize,
+    // use of each key, only tracked while a memory limit may apply
+    usage: Option<HashMap<String, Usage>>,
+    memory_limit: Option<MemoryLimit>,
+    evicted: u64,
+    // off, writes over the memory limit fail instead of evicting
+    eviction: bool,
+    // events of a commit in progress, told to the listeners once it is done
+    held: Option<Vec<ChangeEvent>>,
+}
+
+// Keys of a collection and what is kept about them at some point, to put
+// them back without going through the writes
+pub(crate) struct Checkpoint {
+    data: Arc<Keys>,
+    expires: Arc<Expiries>,
+    versions: HashMap<String, u64>,
+    history: Option<History>,
+    used: usize,
+    evicted: u64,
+    // definitions of the indexes and the settings, built again on restore
+    indexes: Vec<(String, String, bool)>,
+    text_index: Option<String>,
+    vector_indexes: Vec<(String, String, Metric)>,
+    memory_limit: Option<MemoryLimit>,
 }
 
 pub trait _KV {
     fn new(name: &str) -> Self;
-    fn add(&mut self, key: &str, value: DataType) -> &mut Self;
+    fn add(&mut self, key: &str, value: DataType) -> Result<&mut Self, &'static str>;
     fn rm(&mut self, key: &str);
     fn count(&self) -> usize;
     fn list(&self) -> HashMap<String, DataType>;
@@ -46,49 +100,872 @@ impl Collection {
     pub fn new(name: &str) -> Self {
         Collection {
             name: name.to_string(),
-            data: DataType::Document(Document::new()),
-            //b_tree: BNode::new(),
+            data: Arc::new(Keys::new()),
+            indexes: Vec::new(),
+            text_index: None,
+            vector_indexes: Vec::new(),
+            hooks: Vec::new(),
+            watchers: Vec::new(),
+            versions: HashMap::new(),
+            revision: 0,
+            history: None,
+            expires: Arc::new(Expiries::new()),
+            used: 0,
+            usage: None,
+            memory_limit: None,
+            evicted: 0,
+            eviction: true,
+            held: None,
+        }
+    }
+
+    // Collection over keys taken from another one, without its indexes
+    pub(crate) fn from_keys(
+        name: &str,
+        data: Arc<Keys>,
+        expires: Arc<Expiries>,
+        used: usize,
+    ) -> Self {
+        Collection {
+            data,
+            expires,
+            used,
+            ..Collection::new(name)
+        }
+    }
+
+    pub fn add(&mut self, key: &str, value: DataType) -> Result<&mut Self, &'static str> {
+        if self.unique_violation(key, &value).is_some() {
+            return Err(UNIQUE_VIOLATION);
+        }
+        let size = key.len() + value.approx_size();
+        // the limit is checked before writing, noeviction leaves the key as it was
+        self.make_room(key, size)?;
+        let old = self.get_ref(key).filter(|_| self.has_listeners()).cloned();
+        let new = self.has_listeners().then(|| value.clone());
+        self.used -= self.key_size(key);
+        self.used += size;
+        let indexed = self.indexed(key);
+        Arc::make_mut(&mut self.data).insert(key.to_string(), Arc::new(value));
+        self.reindex(key, indexed);
+        // a new value does not keep the ttl of the old one
+        if self.expires.contains_key(key) {
+            Arc::make_mut(&mut self.expires).remove(key);
+        }
+        self.bump(key);
+        self.notify(key, ChangeOp::Set, old, new);
+        Ok(self)
+    }
+
+    // Sets the value of a dotted key path (`user.name`, `users.0.name`)
+    // creating the missing parents
+    pub fn set_path(&mut self, key_path: &str, value: DataType) -> Result<DataType, &'static str> {
+        let keys: Vec<&str> = key_path.split('.').collect();
+        if keys.len() == 1 {
+            self.add(key_path, value)?;
+            return Ok(DataType::Document(self.list()));
+        }
+        if self.unique_violation(key_path, &value).is_some() {
+            return Err(UNIQUE_VIOLATION);
+        }
+        self.reap_key(keys[0]);
+        let (old, new) = if self.has_listeners() {
+            let top = self.get_ref(keys[0]);
+            let old = top.and_then(|top| keys[1..].iter().try_fold(top, |p, k| p.get(k)));
+            let old = old.cloned();
+            (old, Some(value.clone()))
+        } else {
+            (None, None)
+        };
+        // the new value of the top level key is made apart, the limit is
+        // checked with its size before writing
+        let top = self.get_ref(keys[0]).cloned();
+        let mut top = top.unwrap_or_else(|| empty_parent(&keys, 0));
+        let result = set_nested(&mut top, &keys, value)?;
+        let size = keys[0].len() + top.approx_size();
+        self.make_room(keys[0], size)?;
+        let indexed = self.indexed(keys[0]);
+        self.used -= self.key_size(keys[0]);
+        self.used += size;
+        Arc::make_mut(&mut self.data).insert(keys[0].to_string(), Arc::new(top));
+        self.reindex(keys[0], indexed);
+        self.bump(keys[0]);
+        self.notify(key_path, ChangeOp::Set, old, new);
+        Ok(result)
+    }
+
+    // Sets the key path only if its top level key still has the version
+    pub fn cas(
+        &mut self,
+        key_path: &str,
+        value: DataType,
+        version: u64,
+    ) -> Result<DataType, &'static str> {
+        let key = key_path.split('.').next().unwrap_or_default();
+        if self.version(key) != version {
+            return Err("Version mismatch");
+        }
+        self.set_path(key_path, value)
+    }
+
+    // Version of the top level key, 0 if it does not exist
+    pub fn version(&self, key: &str) -> u64 {
+        if self.is_expired(key) {
+            return 0;
+        }
+        self.versions.get(key).copied().unwrap_or(0)
+    }
+
+    // Deletes the key once the time has passed, false if it does not exist
+    pub fn expire_at(&mut self, key: &str, time: SystemTime) -> bool {
+        if self.get_ref(key).is_none() {
+            return false;
+        }
+        Arc::make_mut(&mut self.expires).insert(key.to_string(), time);
+        true
+    }
+
+    pub fn expire(&mut self, key: &str, ttl: Duration) -> bool {
+        self.expire_at(key, SystemTime::now() + ttl)
+    }
+
+    // Time left before the key expires, None if it has no ttl
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        let at = self.expires.get(key)?;
+        Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+
+    // Time at which the key expires, None if it has no ttl
+    pub fn expires_at(&self, key: &str) -> Option<SystemTime> {
+        self.expires.get(key).copied()
+    }
+
+    // Removes the ttl of the key, false if it had none
+    pub fn persist(&mut self, key: &str) -> bool {
+        if !self.expires.contains_key(key) || self.is_expired(key) {
+            return false;
+        }
+        Arc::make_mut(&mut self.expires).remove(key);
+        true
+    }
+
+    fn is_expired(&self, key: &str) -> bool {
+        self.expires
+            .get(key)
+            .is_some_and(|at| *at <= SystemTime::now())
+    }
+
+    // Deletes the key if it has expired
+    fn reap_key(&mut self, key: &str) -> bool {
+        if !self.is_expired(key) {
+            return false;
+        }
+        self.delete(key);
+        true
+    }
+
+    // Deletes the expired keys, returns how many
+    pub fn reap(&mut self) -> usize {
+        let now = SystemTime::now();
+        let expired: Vec<String> = self
+            .expires
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        expired.iter().filter(|key| self.reap_key(key)).count()
+    }
+
+    fn bump(&mut self, key: &str) {
+        self.revision += 1;
+        self.versions.insert(key.to_string(), self.revision);
+        self.record_history(key);
+        if let Some(usage) = self.usage.as_mut() {
+            usage
+                .entry(key.to_string())
+                .or_insert_with(Usage::new)
+                .touch();
+        }
+    }
+
+    // Adds an index named name on `*.<field>`, a field of the documents of
+    // the collection, or `<key.path>[*].<field>`, a field of the items of an
+    // array. It is kept updated on every write
+    pub fn create_index(&mut self, name: &str, spec: &str) -> Result<(), &'static str> {
+        if self.has_index(name) {
+            return Err("Index already exists");
+        }
+        let mut index = Index::new(name, spec)?;
+        index.build(&self.data);
+        self.indexes.push(index);
+        Ok(())
+    }
+
+    // Like create_index on `*.<field>`, writes that would give a document
+    // the value of the field of another one fail. It can not be created if
+    // two documents already share a value
+    pub fn create_unique_index(&mut self, name: &str, spec: &str) -> Result<(), &'static str> {
+        if self.has_index(name) {
+            return Err("Index already exists");
+        }
+        let mut index = Index::new(name, spec)?;
+        if index.array.is_some() {
+            return Err("Unique index must be on *.<field>");
+        }
+        index.unique = true;
+        index.build(&self.data);
+        if index.has_duplicates() {
+            return Err("Duplicate values, can not create the unique index");
+        }
+        self.indexes.push(index);
+        Ok(())
+    }
+
+    // Keeps the vectors at `*` or `*.<field>` of the keys in a graph, used
+    // by knn with the same spec and metric to visit only a few of them
+    pub fn create_vector_index(
+        &mut self,
+        name: &str,
+        spec: &str,
+        metric: Metric,
+    ) -> Result<(), &'static str> {
+        if self.has_index(name) {
+            return Err("Index already exists");
+        }
+        let mut index = VectorIndex::new(name, spec, metric)?;
+        index.build(&self.data);
+        self.vector_indexes.push(index);
+        Ok(())
+    }
+
+    fn has_index(&self, name: &str) -> bool {
+        self.indexes.iter().any(|i| i.name == name)
+            || self.vector_indexes.iter().any(|i| i.name == name)
+    }
+
+    pub fn drop_index(&mut self, name: &str) -> bool {
+        let count = self.indexes.len() + self.vector_indexes.len();
+        self.indexes.retain(|i| i.name != name);
+        self.vector_indexes.retain(|i| i.name != name);
+        self.indexes.len() + self.vector_indexes.len() != count
+    }
+
+    // Names of the indexes with their definitions and if they are unique
+    pub fn indexes(&self) -> Vec<(&str, String, bool)> {
+        self.indexes
+            .iter()
+            .map(|i| (i.name.as_str(), i.spec(), i.unique))
+            .collect()
+    }
+
+    // Names of the vector indexes with their definitions and metrics
+    pub fn vector_indexes(&self) -> Vec<(&str, &str, Metric)> {
+        self.vector_indexes
+            .iter()
+            .map(|i| (i.name.as_str(), i.spec.as_str(), i.metric()))
+            .collect()
+    }
+
+    // The k keys whose vectors at spec (`*` or `*.<field>`) are the closest
+    // to the query with the metric, among those whose value passes the
+    // filter. A vector index with the same spec and metric is used when
+    // there is one, the vectors are compared one by one otherwise.
+    // With `<key.path>` or `<key.path>[*].<field>` the vectors are the items
+    // of the array at the key path, found by their position
+    pub fn knn(
+        &self,
+        spec: &str,
+        query: &[f32],
+        k: usize,
+        metric: Metric,
+        filter: impl Fn(&DataType) -> bool,
+    ) -> Result<Vec<(String, f32)>, &'static str> {
+        if !spec.starts_with('*') {
+            return self.knn_items(spec, query, k, metric, filter);
+        }
+        if !vector::valid_spec(spec) {
+            return Err("Vectors must be at * or *.<field>");
+        }
+        let passes = |key: &str| {
+            let value = self.data.get(key).filter(|_| !self.is_expired(key));
+            value.is_some_and(|v| filter(v))
+        };
+        let index = self
+            .vector_indexes
+            .iter()
+            .find(|i| i.spec == spec && i.metric() == metric);
+        if let Some(index) = index {
+            let found = index.search(query, k, passes);
+            // the filter may leave out too many of the keys visited
+            if found.len() == k {
+                return Ok(found);
+            }
+        }
+        let mut found: Vec<(String, f32)> = self
+            .data
+            .iter()
+            .filter(|(key, _)| passes(key))
+            .filter_map(|(key, value)| {
+                let vector = vector::vector_at(value, spec)?;
+                Some((key.clone(), metric.distance(query, &vector)?))
+            })
+            .collect();
+        found.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        found.truncate(k);
+        Ok(found)
+    }
+
+    // knn over the items of an array, always compared one by one
+    fn knn_items(
+        &self,
+        spec: &str,
+        query: &[f32],
+        k: usize,
+        metric: Metric,
+        filter: impl Fn(&DataType) -> bool,
+    ) -> Result<Vec<(String, f32)>, &'static str> {
+        let (key_path, field) = match spec.split_once("[*].") {
+            Some((key_path, field)) => (key_path, Some(field)),
+            None => (spec, None),
+        };
+        if key_path.is_empty() || field.is_some_and(|f| f.is_empty()) {
+            return Err("Vectors must be at <key.path> or <key.path>[*].<field>");
         }
+        let keys: Vec<&str> = key_path.split('.').collect();
+        let top = self.get_ref(keys[0]).ok_or("Key not found")?;
+        let items = keys[1..].iter().try_fold(top, |parent, k| parent.get(k));
+        let Some(DataType::Array(items)) = items else {
+            return Err("Vectors must be the items of an array");
+        };
+        let mut found: Vec<(String, f32)> = items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| filter(item))
+            .filter_map(|(i, item)| {
+                let vector = match field {
+                    Some(field) => field_value(item, field)?,
+                    None => item,
+                };
+                let vector = vector::to_vector(vector)?;
+                Some((i.to_string(), metric.distance(query, &vector)?))
+            })
+            .collect();
+        // stable, the same distances keep the order of the array
+        found.sort_by(|a, b| a.1.total_cmp(&b.1));
+        found.truncate(k);
+        Ok(found)
     }
 
-    pub fn add(&mut self, key: &str, value: DataType) -> &mut Self {
-        let _ = self.data.set(key, value);
-        return self;
+    // Unique index and value that another document already has, if setting
+    // the key path to the value would break a unique index
+    pub fn unique_violation(&self, key_path: &str, value: &DataType) -> Option<(&str, DataType)> {
+        if !self.indexes.iter().any(|i| i.unique) {
+            return None;
+        }
+        let keys: Vec<&str> = key_path.split('.').collect();
+        let document = if keys.len() == 1 {
+            value.clone()
+        } else {
+            let top = self.get_ref(keys[0]).cloned();
+            let mut top = top.unwrap_or_else(|| empty_parent(&keys, 0));
+            set_nested(&mut top, &keys, value.clone()).ok()?;
+            top
+        };
+        self.indexes.iter().filter(|i| i.unique).find_map(|index| {
+            let mut values = index.values_of(Some(&document)).into_iter();
+            values.find_map(|value| {
+                let others = index.documents(value.clone()..=value.clone());
+                let mut others = others.into_iter();
+                others
+                    .any(|k| k != keys[0] && !self.is_expired(k))
+                    .then_some((index.name.as_str(), value.0))
+            })
+        })
+    }
+
+    // Keys of the documents whose field has the value, None if there is no
+    // index on the documents with that name
+    pub fn find_indexed(&self, name: &str, value: &DataType) -> Option<Vec<String>> {
+        let key = IndexKey(value.clone());
+        self.find_indexed_range(name, key.clone()..=key)
+    }
+
+    // Keys of the documents whose field is in the range, ordered by the
+    // value of the field
+    pub fn find_indexed_range(
+        &self,
+        name: &str,
+        range: impl RangeBounds<IndexKey>,
+    ) -> Option<Vec<String>> {
+        let index = self.indexes.iter().find(|i| i.name == name)?;
+        if index.array.is_some() {
+            return None;
+        }
+        let keys = index.documents(range).into_iter();
+        Some(keys.filter(|k| !self.is_expired(k)).cloned().collect())
+    }
+
+    // Positions of the items of the array at the key path whose field
+    // matches, None if no index covers them or the comparison
+    pub fn find_items(
+        &self,
+        key_path: &str,
+        field: &str,
+        op: &FindOp,
+        value: &DataType,
+    ) -> Option<Vec<usize>> {
+        let index = self
+            .indexes
+            .iter()
+            .find(|i| i.array.as_deref() == Some(key_path) && i.field == field)?;
+        Some(index.items(op_range(op, value)?))
+    }
+
+    // Values of the indexed fields of the key, taken before a write
+    fn indexed(&self, key: &str) -> Vec<Vec<IndexKey>> {
+        let value = self.data.get(key).map(|v| v.as_ref());
+        self.indexes.iter().map(|i| i.values_of(value)).collect()
+    }
+
+    // Updates the indexes with the current value of the key
+    fn reindex(&mut self, key: &str, indexed: Vec<Vec<IndexKey>>) {
+        let value = self.data.get(key).map(|v| v.as_ref());
+        for (index, old) in self.indexes.iter_mut().zip(indexed) {
+            index.update(key, old, value);
+        }
+        if let Some(text_index) = self.text_index.as_mut() {
+            text_index.update(key, value);
+        }
+        for index in self.vector_indexes.iter_mut() {
+            index.update(key, value);
+        }
+    }
+
+    // Keeps a full-text index of the texts of the keys, `*` for all of them
+    // or the path of a field of the documents. None removes it
+    pub fn set_text_index(&mut self, spec: Option<&str>) {
+        self.text_index = spec.map(|spec| {
+            let mut text_index = TextIndex::new(spec);
+            text_index.build(&self.data);
+            text_index
+        });
+    }
+
+    pub fn text_index(&self) -> Option<&TextIndex> {
+        self.text_index.as_ref()
+    }
+
+    // Keys whose texts have any of the terms, ranked by BM25. Without a
+    // full-text index the texts are tokenized for this search
+    pub fn search(&self, query: &str) -> Vec<(String, f32)> {
+        let found = match self.text_index.as_ref() {
+            Some(text_index) => text_index.search(query),
+            None => {
+                let mut text_index = TextIndex::new("*");
+                text_index.build(&self.data);
+                text_index.search(query)
+            }
+        };
+        found
+            .into_iter()
+            .filter(|(key, _)| !self.is_expired(key))
+            .collect()
+    }
+
+    // Like search, over the fields of the document or the items of the
+    // array at the key path. None if it does not exist
+    pub fn search_in(&self, key_path: &str, query: &str) -> Option<Vec<(String, f32)>> {
+        let mut keys = key_path.split('.');
+        let top = self.get_ref(keys.next()?)?;
+        let value = keys.try_fold(top, |parent, k| parent.get(k))?;
+        let spec = self.text_index.as_ref().map_or("*", |t| t.spec());
+        let mut text_index = TextIndex::new(spec);
+        match value {
+            DataType::Document(document) => {
+                for (key, value) in document.iter() {
+                    text_index.update(key, Some(value));
+                }
+            }
+            DataType::Array(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    text_index.update(&i.to_string(), Some(item));
+                }
+            }
+            _ => return None,
+        }
+        Some(text_index.search(query))
+    }
+
+    // Approximate size of the key and its value, 0 if it does not exist
+    pub fn key_size(&self, key: &str) -> usize {
+        self.data
+            .get(key)
+            .map_or(0, |v| key.len() + v.approx_size())
+    }
+
+    // Evicts keys following the policy when a write would take the
+    // approximate size over max_bytes. None removes the limit
+    pub fn set_memory_limit(&mut self, limit: Option<MemoryLimit>) {
+        self.memory_limit = limit;
+        if limit.is_some() {
+            self.track_usage();
+            let _ = self.make_room("", 0);
+        }
+    }
+
+    pub fn memory_limit(&self) -> Option<MemoryLimit> {
+        self.memory_limit
+    }
+
+    // Enables the eviction of keys by the memory limits, on by default
+    pub fn set_eviction(&mut self, enabled: bool) {
+        self.eviction = enabled;
+    }
+
+    // Keys evicted by the memory limit of the collection
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    pub(crate) fn track_usage(&mut self) {
+        if self.usage.is_none() {
+            let keys = self.data.keys().map(|k| (k.clone(), Usage::new()));
+            self.usage = Some(keys.collect());
+        }
+    }
+
+    // Evicts keys other than keep until the collection fits its memory limit
+    // with keep taking size bytes
+    fn make_room(&mut self, keep: &str, size: usize) -> Result<usize, &'static str> {
+        let Some(limit) = self.memory_limit else {
+            return Ok(0);
+        };
+        // nothing is evicted for a value that could never fit
+        if std::mem::size_of::<DataType>() + size > limit.max_bytes {
+            return Err(OUT_OF_MEMORY);
+        }
+        let mut evicted = 0;
+        while self.approx_size() - self.key_size(keep) + size > limit.max_bytes {
+            let (key, _) = self.victim(limit.policy, keep).ok_or(OUT_OF_MEMORY)?;
+            self.evict(&key);
+            self.evicted += 1;
+            evicted += 1;
+        }
+        Ok(evicted)
+    }
+
+    // Key to evict first with the policy and its rank, the lowest rank goes
+    // first when comparing keys of several collections
+    pub(crate) fn victim(&self, policy: EvictionPolicy, keep: &str) -> Option<(String, u64)> {
+        if !self.eviction {
+            return None;
+        }
+        let mut keys = self.data.keys().filter(|k| *k != keep);
+        let usage = |key: &str| self.usage.as_ref().and_then(|u| u.get(key)).copied();
+        let victim = match policy {
+            EvictionPolicy::Lru => keys
+                .map(|k| (k, usage(k).map_or(0, |u| u.last)))
+                .min_by_key(|(_, rank)| *rank),
+            EvictionPolicy::Lfu => keys
+                .map(|k| (k, usage(k).map_or(0, |u| u.hits)))
+                .min_by_key(|(_, rank)| *rank),
+            EvictionPolicy::VolatileTtl => keys
+                .filter_map(|k| {
+                    let at = self.expires.get(k)?.duration_since(UNIX_EPOCH);
+                    Some((k, at.unwrap_or_default().as_millis() as u64))
+                })
+                .min_by_key(|(_, rank)| *rank),
+            EvictionPolicy::Random => {
+                let count = self.data.len() - self.data.contains_key(keep) as usize;
+                if count == 0 {
+                    return None;
+                }
+                let i = eviction::random() as usize % count;
+                keys.nth(i).map(|k| (k, eviction::random()))
+            }
+            EvictionPolicy::NoEviction => None,
+        };
+        victim.map(|(k, rank)| (k.clone(), rank))
+    }
+
+    pub(crate) fn evict(&mut self, key: &str) {
+        self.delete(key);
+    }
+
+    fn record_history(&mut self, key: &str) {
+        if let Some(history) = self.history.as_mut() {
+            history.record(key, self.data.get(key).cloned(), SystemTime::now());
+        }
+    }
+
+    // Keeps the previous values of the keys within the retention limits.
+    // The history starts with the current values, None drops it
+    pub fn set_retention(&mut self, retention: Option<Retention>) {
+        let now = SystemTime::now();
+        match (retention, self.history.as_mut()) {
+            (None, _) => self.history = None,
+            (Some(retention), Some(history)) => {
+                history.retention = retention;
+                history.prune(now);
+            }
+            (Some(retention), None) => {
+                let mut history = History::new(retention);
+                for (key, value) in self.data.iter() {
+                    history.record(key, Some(value.clone()), now);
+                }
+                self.history = Some(history);
+            }
+        }
+    }
+
+    pub fn retention(&self) -> Option<Retention> {
+        self.history.as_ref().map(|h| h.retention)
+    }
+
+    // Value of the key path at the time, None if it did not exist or the
+    // history does not reach that time
+    pub fn get_at(&self, key_path: &str, time: SystemTime) -> Option<DataType> {
+        let mut keys = key_path.split('.');
+        let top = keys.next().unwrap_or_default();
+        let value = self.history.as_ref()?.at(top, time)??;
+        keys.try_fold(value, |p, k| p.get(k)).cloned()
+    }
+
+    // Values of the key from the oldest to the newest with the time they
+    // were written, None when it was deleted
+    pub fn history(&self, key: &str) -> Vec<(SystemTime, Option<DataType>)> {
+        match self.history.as_ref() {
+            Some(history) => history.records(key),
+            None => Vec::new(),
+        }
+    }
+
+    // Sets the key back to its value at the time, deleting it if it did not exist
+    pub fn restore(
+        &mut self,
+        key: &str,
+        time: SystemTime,
+    ) -> Result<Option<DataType>, &'static str> {
+        let history = self.history.as_ref().ok_or("History is not enabled")?;
+        let value = history.at(key, time).ok_or("No history at that time")?;
+        let value = value.cloned();
+        match value.clone() {
+            Some(value) => {
+                self.add(key, value)?;
+            }
+            None => self.rm(key),
+        }
+        Ok(value)
     }
 
     pub fn rm(&mut self, key: &str) {
-        let _ = self.data.remove(key);
+        if self.get_ref(key).is_some() {
+            self.delete(key);
+        }
+    }
+
+    // Removes the key even if it has expired
+    fn delete(&mut self, key: &str) {
+        self.used -= self.key_size(key);
+        let indexed = self.indexed(key);
+        let Some(old) = Arc::make_mut(&mut self.data).remove(key) else {
+            return;
+        };
+        self.reindex(key, indexed);
+        if self.expires.contains_key(key) {
+            Arc::make_mut(&mut self.expires).remove(key);
+        }
+        if let Some(usage) = self.usage.as_mut() {
+            usage.remove(key);
+        }
+        self.versions.remove(key);
+        self.record_history(key);
+        let old = self.has_listeners().then(|| old.as_ref().clone());
+        self.notify(key, ChangeOp::Del, old, None);
+    }
+
+    // Calls the hook with every change made to the collection
+    pub fn on_change(&mut self, hook: ChangeHook) {
+        self.hooks.push(hook);
+    }
+
+    // Receives the changes made under the key path, or all of them without prefix
+    pub fn watch(&mut self, prefix: Option<&str>) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.watchers.push(Watcher {
+            prefix: prefix.map(|p| p.to_string()),
+            sender,
+        });
+        receiver
+    }
+
+    pub(crate) fn has_listeners(&self) -> bool {
+        !self.hooks.is_empty() || !self.watchers.is_empty()
+    }
+
+    pub(crate) fn notify(
+        &mut self,
+        key: &str,
+        op: ChangeOp,
+        old: Option<DataType>,
+        new: Option<DataType>,
+    ) {
+        if !self.has_listeners() {
+            return;
+        }
+        let event = ChangeEvent::new(&self.name, key, op, old, new);
+        match self.held.as_mut() {
+            Some(held) => held.push(event),
+            None => self.tell(&event),
+        }
+    }
+
+    fn tell(&mut self, event: &ChangeEvent) {
+        for hook in self.hooks.iter() {
+            hook(event);
+        }
+        // watchers whose receiver was dropped are removed
+        self.watchers
+            .retain(|w| !w.wants(event) || w.sender.send(event.clone()).is_ok());
+    }
+
+    // Keeps the events from the listeners until release_events
+    pub(crate) fn hold_events(&mut self) {
+        self.held = Some(Vec::new());
+    }
+
+    pub(crate) fn release_events(&mut self) {
+        for event in self.held.take().unwrap_or_default() {
+            self.tell(&event);
+        }
     }
 
     pub fn count(&self) -> usize {
-        self.data.to_document().len()
+        self.snapshot().count()
     }
 
     pub fn list(&self) -> HashMap<String, DataType> {
-        return self.data.to_document().clone();
+        self.snapshot().list()
     }
 
     pub fn get(&mut self, key: &str) -> Option<&DataType> {
-        return self.data.get(key);
+        if let Some(usage) = self.usage.as_mut()
+            && let Some(usage) = usage.get_mut(key)
+        {
+            usage.touch();
+        }
+        self.get_ref(key)
     }
 
-    pub fn dump(&self) -> String {
-        let mut result = String::new();
-        result.push_str(format!("[{}]\n", self.name).as_str());
-        for (k, v) in self.data.to_document().iter() {
-            let t = match v.get_type() {
-                "id" => "1",
-                "text" => "2",
-                "number" => "3",
-                "boolean" => "4",
-                "array" => "5",
-                "document" => "6",
-                _ => "7",
-            };
-            let line = format!("{} {} {}\n", t, k, v.to_string());
-            result.push_str(line.as_str());
+    fn get_ref(&self, key: &str) -> Option<&DataType> {
+        if self.is_expired(key) {
+            return None;
+        }
+        self.data.get(key).map(|v| v.as_ref())
+    }
+
+    pub fn approx_size(&self) -> usize {
+        std::mem::size_of::<DataType>() + self.used
+    }
+
+    // Immutable copy of the collection as it is now, cheap to take
+    pub fn snapshot(&self) -> CollectionSnapshot {
+        CollectionSnapshot {
+            name: self.name.clone(),
+            data: self.data.clone(),
+            expires: self.expires.clone(),
+            retention: self.retention(),
+            memory_limit: self.memory_limit,
+            indexes: self.index_definitions(),
+            revision: self.revision,
+            used: self.used,
+        }
+    }
+
+    pub(crate) fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            data: self.data.clone(),
+            expires: self.expires.clone(),
+            versions: self.versions.clone(),
+            history: self.history.clone(),
+            used: self.used,
+            evicted: self.evicted,
+            indexes: self
+                .indexes()
+                .iter()
+                .map(|(n, s, u)| (n.to_string(), s.clone(), *u))
+                .collect(),
+            text_index: self.text_index.as_ref().map(|t| t.spec().to_string()),
+            vector_indexes: self
+                .vector_indexes()
+                .iter()
+                .map(|(n, s, m)| (n.to_string(), s.to_string(), *m))
+                .collect(),
+            memory_limit: self.memory_limit,
         }
-        return result;
+    }
+
+    // Puts the keys, indexes and settings back as they were at the
+    // checkpoint. The revision is kept so the versions given since are not
+    // given again, and the listeners are not told
+    pub(crate) fn restore_checkpoint(&mut self, checkpoint: Checkpoint) {
+        self.data = checkpoint.data;
+        self.expires = checkpoint.expires;
+        self.versions = checkpoint.versions;
+        self.history = checkpoint.history;
+        self.used = checkpoint.used;
+        self.evicted = checkpoint.evicted;
+        self.memory_limit = checkpoint.memory_limit;
+        self.held = None;
+        let indexes = checkpoint.indexes.into_iter();
+        self.indexes = indexes
+            .filter_map(|(name, spec, unique)| {
+                let mut index = Index::new(&name, &spec).ok()?;
+                index.unique = unique;
+                Some(index)
+            })
+            .collect();
+        self.text_index = checkpoint.text_index.map(|spec| TextIndex::new(&spec));
+        let vector_indexes = checkpoint.vector_indexes.into_iter();
+        self.vector_indexes = vector_indexes
+            .filter_map(|(name, spec, metric)| VectorIndex::new(&name, &spec, metric).ok())
+            .collect();
+        for index in self.indexes.iter_mut() {
+            index.build(&self.data);
+        }
+        if let Some(text_index) = self.text_index.as_mut() {
+            text_index.build(&self.data);
+        }
+        for index in self.vector_indexes.iter_mut() {
+            index.build(&self.data);
+        }
+        if let Some(usage) = self.usage.as_mut() {
+            usage.retain(|key, _| self.data.contains_key(key));
+            for key in self.data.keys() {
+                usage.entry(key.clone()).or_insert_with(Usage::new);
+            }
+        }
+    }
+
+    // Lines of the dump that create the indexes again on load
+    fn index_definitions(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for index in self.indexes.iter() {
+            let kind = if index.unique { "unique" } else { "index" };
+            lines.push(format!("{} {} {}", kind, index.name, index.spec()));
+        }
+        if let Some(text_index) = self.text_index.as_ref() {
+            lines.push(format!("fulltext on {}", text_index.spec()));
+        }
+        for index in self.vector_indexes.iter() {
+            let metric = index.metric().as_str();
+            lines.push(format!("vector {} {} {}", index.name, index.spec, metric));
+        }
+        lines
+    }
+
+    pub fn dump(&self) -> String {
+        self.snapshot().dump()
     }
 
     pub fn load(data: &str) -> Collection {
@@ -105,15 +982,71 @@ impl Collection {
             .strip_prefix('[')
             .unwrap();
         let mut result = Collection::new(name);
+        // ttls and the memory limit are set once the keys are loaded
+        let mut expires = Vec::new();
+        let mut memory_limit = None;
+        let mut indexes = Vec::new();
+        let mut text_index = None;
+        let mut vector_indexes = Vec::new();
         for line in parser.into_iter() {
             if line.starts_with('[') {
                 continue;
             }
             let line_text = line.to_string();
             let elements = utils::smart_split(line_text);
+            if elements.len() == 2 && elements[0] == "revision" {
+                // revision <highest version given>, the keys loaded get
+                // versions above it so a version is never given twice
+                if let Ok(revision) = elements[1].parse() {
+                    result.revision = revision;
+                }
+                continue;
+            }
+            if elements.len() == 4 && elements[0] == "vector" {
+                // vector <name> <definition> <metric>
+                if let Some(metric) = Metric::parse(&elements[3]) {
+                    vector_indexes.push((elements[1].clone(), elements[2].clone(), metric));
+                }
+                continue;
+            }
             if elements.len() != 3 {
                 continue;
             }
+            if elements[0] == "retention" {
+                // retention <versions|-> <max age in seconds|->
+                let versions = elements[1].parse().ok();
+                let max_age = elements[2].parse().ok().map(Duration::from_secs);
+                result.set_retention(Some(Retention { versions, max_age }));
+                continue;
+            }
+            if elements[0] == "maxmemory" {
+                // maxmemory <bytes> <policy>
+                let max_bytes = elements[1].parse().ok();
+                let policy = EvictionPolicy::parse(&elements[2]);
+                if let (Some(max_bytes), Some(policy)) = (max_bytes, policy) {
+                    memory_limit = Some(MemoryLimit { max_bytes, policy });
+                }
+                continue;
+            }
+            if elements[0] == "index" || elements[0] == "unique" {
+                // index|unique <name> <definition>
+                let unique = elements[0] == "unique";
+                indexes.push((elements[1].clone(), elements[2].clone(), unique));
+                continue;
+            }
+            if elements[0] == "fulltext" {
+                // fulltext on <* | field path>
+                text_index = Some(elements[2].clone());
+                continue;
+            }
+            if elements[0] == "ttl" {
+                // ttl <key> <milliseconds since the epoch>
+                if let Ok(ms) = elements[2].parse::<u64>() {
+                    let time = UNIX_EPOCH + Duration::from_millis(ms);
+                    expires.push((elements[1].clone(), time));
+                }
+                continue;
+            }
             let raw_t = elements[0].clone();
             let t = raw_t.parse::<u16>();
             if t.is_err() {
@@ -129,30 +1062,419 @@ impl Collection {
                 continue;
             }
             let v = v.unwrap();
-            result.add(k.as_str(), v);
+            let _ = result.add(k.as_str(), v);
+        }
+        for (key, time) in expires {
+            result.expire_at(&key, time);
+        }
+        result.set_memory_limit(memory_limit);
+        for (name, spec, unique) in indexes {
+            let created = if unique {
+                result.create_unique_index(&name, &spec)
+            } else {
+                result.create_index(&name, &spec)
+            };
+            if let Err(err) = created {
+                println!("Error loading index {}: {}", name, err);
+            }
+        }
+        result.set_text_index(text_index.as_deref());
+        for (name, spec, metric) in vector_indexes {
+            if let Err(err) = result.create_vector_index(&name, &spec, metric) {
+                println!("Error loading index {}: {}", name, err);
+            }
         }
 
         return result;
     }
 }
 
+// Parent to create for the intermediate key i of the path when it is missing
+fn empty_parent(keys: &[&str], i: usize) -> DataType {
+    if i + 2 < keys.len() && keys[i + 1].parse::<usize>().is_ok() {
+        DataType::Array(Vec::new())
+    } else {
+        DataType::Document(Document::new())
+    }
+}
+
+// Sets the value at the path below the top level value, creating the parents
+fn set_nested(
+    top: &mut DataType,
+    keys: &[&str],
+    value: DataType,
+) -> Result<DataType, &'static str> {
+    let mut parent = top;
+    for (i, k) in keys.iter().enumerate().take(keys.len() - 1).skip(1) {
+        if parent.get(k).is_none() {
+            parent.set(k, empty_parent(keys, i))?;
+        }
+        parent = parent.get_mut(k).ok_or("Invalid path")?;
+    }
+    parent.set(keys[keys.len() - 1], value)
+}
+
 //TEST
 #[cfg(test)]
 #[test]
 fn test_collection() {
     let mut collection = Collection::new("users");
-    collection.add(
-        "John",
-        doc!(
-          "name" => "John",
-          "age" => 25,
-          "isMarried" => false,
-          "birthDate" => "1995-01-01"
-        ),
-    );
+    collection
+        .add(
+            "John",
+            doc!(
+              "name" => "John",
+              "age" => 25,
+              "isMarried" => false,
+              "birthDate" => "1995-01-01"
+            ),
+        )
+        .unwrap();
     assert!(collection.get("John").is_some());
 }
 
+#[test]
+fn test_watch() {
+    let mut collection = Collection::new("users");
+    let all = collection.watch(None);
+    let john = collection.watch(Some("John"));
+    collection.add("John", doc!("age" => 25)).unwrap();
+    collection.add("Jane", doc!("age" => 30)).unwrap();
+    collection.rm("John");
+
+    let events: Vec<ChangeEvent> = all.try_iter().collect();
+    assert_eq!(events.len(), 3);
+    let events: Vec<ChangeEvent> = john.try_iter().collect();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].op, ChangeOp::Set);
+    assert_eq!(events[0].new, Some(doc!("age" => 25)));
+    assert_eq!(events[1].op, ChangeOp::Del);
+    assert_eq!(events[1].old, Some(doc!("age" => 25)));
+}
+
+#[test]
+fn test_versions() {
+    let mut collection = Collection::new("users");
+    assert_eq!(collection.version("John"), 0);
+    collection.add("John", doc!("age" => 25)).unwrap();
+    let version = collection.version("John");
+    assert!(version > 0);
+    collection.set_path("John.age", DataType::from(26)).unwrap();
+    assert!(collection.version("John") > version);
+    assert!(
+        collection
+            .cas("John.age", DataType::from(27), version)
+            .is_err()
+    );
+    let version = collection.version("John");
+    assert!(
+        collection
+            .cas("John.age", DataType::from(27), version)
+            .is_ok()
+    );
+    // a deleted key does not get back an old version
+    collection.rm("John");
+    assert!(collection.cas("John", doc!("age" => 1), 0).is_ok());
+    assert!(collection.version("John") > version);
+
+    // nor once loaded again
+    let version = collection.version("John");
+    collection.add("Jane", doc!("age" => 30)).unwrap();
+    let mut loaded = Collection::load(&collection.dump());
+    assert!(loaded.version("John") > collection.version("Jane"));
+    assert!(loaded.cas("John.age", DataType::from(2), version).is_err());
+}
+
+#[test]
+fn test_history() {
+    let mut collection = Collection::new("users");
+    collection.add("john", DataType::from(1)).unwrap();
+    collection.set_retention(Some(Retention {
+        versions: Some(3),
+        max_age: None,
+    }));
+    let start = SystemTime::now();
+    // records are kept with millisecond precision
+    let tick = || std::thread::sleep(Duration::from_millis(2));
+    tick();
+    collection.add("john", DataType::from(2)).unwrap();
+    tick();
+    collection.add("john", DataType::from(3)).unwrap();
+    tick();
+    collection.add("john", DataType::from(4)).unwrap();
+    tick();
+    collection.rm("john");
+
+    assert_eq!(collection.history("john").len(), 3);
+    assert_eq!(collection.history("john")[2].1, None);
+    // the value of start was dropped by the retention
+    assert_eq!(collection.get_at("john", start), None);
+    let time = collection.history("john")[0].0;
+    assert_eq!(collection.get_at("john", time), Some(DataType::from(3)));
+    assert_eq!(
+        collection.restore("john", time),
+        Ok(Some(DataType::from(3)))
+    );
+    assert_eq!(collection.get("john"), Some(&DataType::from(3)));
+}
+
+#[test]
+fn test_ttl() {
+    let mut collection = Collection::new("sessions");
+    collection.add("a", DataType::from(1)).unwrap();
+    collection.add("b", DataType::from(2)).unwrap();
+    assert!(collection.expire("a", Duration::from_secs(60)));
+    assert!(!collection.expire("missing", Duration::from_secs(60)));
+    assert!(collection.ttl("a").is_some());
+    assert!(collection.persist("a"));
+    assert_eq!(collection.ttl("a"), None);
+
+    collection.expire_at("a", SystemTime::now() - Duration::from_secs(1));
+    collection.expire("b", Duration::from_secs(60));
+    assert_eq!(collection.get("a"), None);
+    assert_eq!(collection.count(), 1);
+    assert!(!collection.list().contains_key("a"));
+    assert_eq!(collection.version("a"), 0);
+
+    let loaded = Collection::load(&collection.dump());
+    assert_eq!(loaded.count(), 1);
+    assert!(loaded.ttl("b").is_some());
+
+    assert_eq!(collection.reap(), 1);
+    assert_eq!(collection.reap(), 0);
+    // a new value drops the ttl
+    collection.add("b", DataType::from(3)).unwrap();
+    assert_eq!(collection.ttl("b"), None);
+}
+
+#[test]
+fn test_eviction() {
+    let mut collection = Collection::new("cache");
+    collection.add("a", DataType::from(1)).unwrap();
+    let size = collection.approx_size();
+    collection.add("b", DataType::from(2)).unwrap();
+    collection.add("c", DataType::from(3)).unwrap();
+    let max_bytes = collection.approx_size();
+    collection.set_memory_limit(Some(MemoryLimit {
+        max_bytes,
+        policy: EvictionPolicy::Lru,
+    }));
+    collection.get("c");
+    collection.get("a");
+    // b is the least recently used
+    collection.add("d", DataType::from(4)).unwrap();
+    assert!(collection.approx_size() <= max_bytes);
+    assert_eq!(collection.get("b"), None);
+    assert!(collection.get("a").is_some());
+    assert_eq!(collection.evicted(), 1);
+
+    collection.set_memory_limit(Some(MemoryLimit {
+        max_bytes: size,
+        policy: EvictionPolicy::NoEviction,
+    }));
+    assert!(collection.set_path("e", DataType::from(5)).is_err());
+    collection.rm("c");
+    collection.rm("d");
+    // a new key over the limit is not written, replacing one of the same size is
+    assert_eq!(
+        collection.add("e", DataType::from(5)).err(),
+        Some(OUT_OF_MEMORY)
+    );
+    assert_eq!(collection.get("e"), None);
+    assert!(collection.add("a", DataType::from(5)).is_ok());
+    assert!(collection.add("a", DataType::from("longer")).is_err());
+    assert_eq!(collection.get("a"), Some(&DataType::from(5)));
+    collection.rm("a");
+    // nested paths count the new value of their top level key
+    let nested = collection.set_path("e.f", DataType::from(5));
+    assert_eq!(nested.err(), Some(OUT_OF_MEMORY));
+    assert_eq!(collection.get("e"), None);
+    assert!(collection.set_path("e", DataType::from(5)).is_ok());
+    collection.set_memory_limit(None);
+    assert_eq!(
+        collection.approx_size(),
+        Collection::load(&collection.dump()).approx_size()
+    );
+
+    // without eviction the writes over the limit fail whatever the policy
+    collection.set_eviction(false);
+    collection.set_memory_limit(Some(MemoryLimit {
+        max_bytes: size,
+        policy: EvictionPolicy::Lru,
+    }));
+    assert_eq!(collection.count(), 1);
+    assert!(collection.set_path("f", DataType::from(6)).is_err());
+    assert_eq!(collection.evicted(), 1);
+}
+
+#[test]
+fn test_indexes() {
+    let mut collection = Collection::new("users");
+    collection
+        .add("ana", doc!("age" => 30, "city" => "Madrid"))
+        .unwrap();
+    collection
+        .add("joao", doc!("age" => 25, "city" => "Lisboa"))
+        .unwrap();
+    collection.create_index("by_age", "*.age").unwrap();
+    assert!(collection.create_index("by_age", "*.city").is_err());
+    assert!(collection.create_index("bad", "age").is_err());
+    collection.add("eva", doc!("age" => 30)).unwrap();
+    let found = collection.find_indexed("by_age", &DataType::from(30));
+    assert_eq!(found.map(|keys| keys.len()), Some(2));
+    assert_eq!(
+        collection.find_indexed("by_city", &DataType::from("Madrid")),
+        None
+    );
+
+    collection.set_path("ana.age", DataType::from(31)).unwrap();
+    collection.rm("eva");
+    let found = collection.find_indexed("by_age", &DataType::from(30));
+    assert_eq!(found, Some(Vec::new()));
+    let older = collection.find_indexed_range("by_age", IndexKey(DataType::from(26))..);
+    assert_eq!(older, Some(vec!["ana".to_string()]));
+
+    // items of an array, indexed again when it changes
+    collection
+        .add("tasks", DataType::Array(vec![doc!("done" => true)]))
+        .unwrap();
+    collection.create_index("by_done", "tasks[*].done").unwrap();
+    collection
+        .set_path("tasks.+", doc!("done" => false))
+        .unwrap();
+    collection
+        .set_path("tasks.+", doc!("done" => true))
+        .unwrap();
+    let done = collection.find_items("tasks", "done", &FindOp::Eq, &DataType::from(true));
+    assert_eq!(done, Some(vec![0, 2]));
+    assert!(collection.drop_index("by_age"));
+    assert_eq!(
+        collection.indexes(),
+        vec![("by_done", "tasks[*].done".to_string(), false)]
+    );
+}
+
+#[test]
+fn test_unique_index() {
+    let mut collection = Collection::new("users");
+    collection
+        .add("1", doc!("email" => "ana@mail.com"))
+        .unwrap();
+    collection
+        .add("2", doc!("email" => "ana@mail.com"))
+        .unwrap();
+    assert!(collection.create_unique_index("email", "*.email").is_err());
+    collection.rm("2");
+    collection.create_unique_index("email", "*.email").unwrap();
+    assert!(
+        collection
+            .create_unique_index("items", "tasks[*].id")
+            .is_err()
+    );
+
+    let used = collection.add("2", doc!("email" => "ana@mail.com"));
+    assert_eq!(used.err(), Some(UNIQUE_VIOLATION));
+    assert!(collection.get("2").is_none());
+    collection
+        .add("2", doc!("email" => "eva@mail.com"))
+        .unwrap();
+    let violation = collection.unique_violation("2.email", &DataType::from("ana@mail.com"));
+    assert_eq!(violation, Some(("email", DataType::from("ana@mail.com"))));
+    assert!(
+        collection
+            .set_path("2.email", DataType::from("ana@mail.com"))
+            .is_err()
+    );
+    assert_eq!(
+        collection.get("2").and_then(|d| d.get("email")).cloned(),
+        Some(DataType::from("eva@mail.com"))
+    );
+    // a document can be written again with its own value
+    collection
+        .set_path("1.email", DataType::from("ana@mail.com"))
+        .unwrap();
+
+    let loaded = Collection::load(&collection.dump());
+    assert_eq!(
+        loaded.indexes(),
+        vec![("email", "*.email".to_string(), true)]
+    );
+}
+
+#[test]
+fn test_knn() {
+    let mut collection = Collection::new("docs");
+    for (key, x, y, lang) in [("a", 1, 0, "en"), ("b", 1, 1, "es"), ("c", 0, 1, "en")] {
+        let embedding = DataType::Array(vec![DataType::from(x), DataType::from(y)]);
+        collection
+            .add(key, doc!("embedding" => embedding, "lang" => lang))
+            .unwrap();
+    }
+    collection.add("d", DataType::from("no vector")).unwrap();
+    let keys = |found: Vec<(String, f32)>| found.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    let all = |_: &DataType| true;
+    let found = collection.knn("*.embedding", &[1.0, 0.1], 2, Metric::L2, all);
+    assert_eq!(keys(found.unwrap()), vec!["a", "b"]);
+    assert!(
+        collection
+            .knn("embedding", &[1.0], 1, Metric::L2, all)
+            .is_err()
+    );
+    assert!(
+        collection
+            .knn("*embedding", &[1.0], 1, Metric::L2, all)
+            .is_err()
+    );
+
+    // items of an array, by position
+    let items = [(0, 1), (1, 0), (1, 1), (0, 1)].map(|(x, y)| {
+        let vector = DataType::Array(vec![DataType::from(x), DataType::from(y)]);
+        doc!("vector" => vector.clone(), "tag" => if x == 0 { "up" } else { "right" })
+    });
+    collection
+        .add("points", doc!("all" => DataType::Array(items.to_vec())))
+        .unwrap();
+    let found = collection.knn("points.all[*].vector", &[0.0, 2.0], 2, Metric::L2, all);
+    assert_eq!(keys(found.unwrap()), vec!["0", "3"]);
+    let right = |v: &DataType| v.matches("tag", &FindOp::Eq, &DataType::from("right"));
+    let found = collection.knn("points.all[*].vector", &[0.0, 2.0], 1, Metric::L2, right);
+    assert_eq!(keys(found.unwrap()), vec!["2"]);
+    let vectors = items.iter().map(|i| i.get("vector").cloned().unwrap());
+    collection
+        .add("vectors", DataType::Array(vectors.collect()))
+        .unwrap();
+    let found = collection.knn("vectors", &[1.0, 0.0], 1, Metric::Cosine, all);
+    assert_eq!(keys(found.unwrap()), vec!["1"]);
+    assert!(
+        collection
+            .knn("points", &[1.0], 1, Metric::L2, all)
+            .is_err()
+    );
+    assert!(
+        collection
+            .knn("points.all[*].", &[1.0], 1, Metric::L2, all)
+            .is_err()
+    );
+    collection.rm("points");
+    collection.rm("vectors");
+
+    collection
+        .create_vector_index("by_embedding", "*.embedding", Metric::Cosine)
+        .unwrap();
+    let english = |v: &DataType| v.matches("lang", &FindOp::Eq, &DataType::from("en"));
+    let found = collection.knn("*.embedding", &[1.0, 1.0], 3, Metric::Cosine, english);
+    assert_eq!(keys(found.unwrap()), vec!["a", "c"]);
+    collection.rm("a");
+    let found = collection.knn("*.embedding", &[1.0, 0.0], 1, Metric::Cosine, all);
+    assert_eq!(keys(found.unwrap()), vec!["b"]);
+
+    let loaded = Collection::load(&collection.dump());
+    assert_eq!(
+        loaded.vector_indexes(),
+        vec![("by_embedding", "*.embedding", Metric::Cosine)]
+    );
+}
+
 #[test]
 fn test_dump() {
     let header = "[prueba]\n";
@@ -161,9 +1483,9 @@ fn test_dump() {
     let kv_age = "3 age 15";
 
     let mut collection = Collection::new("prueba");
-    collection.add("name", DataType::from("Juan"));
-    collection.add("surname", DataType::from("Perez"));
-    collection.add("age", DataType::from(15));
+    collection.add("name", DataType::from("Juan")).unwrap();
+    collection.add("surname", DataType::from("Perez")).unwrap();
+    collection.add("age", DataType::from(15)).unwrap();
 
     let dump = collection.dump();
     println!("{}", dump);
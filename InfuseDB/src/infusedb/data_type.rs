@@ -6,13 +6,67 @@ use std::usize;
 //
 // The data type will be used to store the data in the documents
 use super::collection::Document;
+use regex_lite::Regex;
+use std::cmp::Ordering;
 use uuid::Uuid;
 
 pub enum FindOp {
     Eq,
     NotEq,
+    // numbers, and texts and ids in lexicographic order
     Gt,
     Lt,
+    Ge,
+    Le,
+    // the value is an array with the candidates
+    In,
+    NotIn,
+    // the sub key is there, the value is ignored
+    Exists,
+    // substring of a text or item of an array
+    Contains,
+    StartsWith,
+    EndsWith,
+    Matches(Regex),
+    // the value is an array with both ends, included
+    Between,
+}
+
+impl FindOp {
+    pub fn matches(pattern: &str) -> Result<Self, &'static str> {
+        let regex = Regex::new(pattern).map_err(|_| "Invalid regular expression")?;
+        Ok(FindOp::Matches(regex))
+    }
+}
+
+// Order of numbers, texts and ids, None for other types or mixed ones
+fn compare(a: &DataType, b: &DataType) -> Option<Ordering> {
+    match (a, b) {
+        (DataType::Number(a), DataType::Number(b)) => a.partial_cmp(b),
+        (DataType::Text(a), DataType::Text(b)) => Some(a.cmp(b)),
+        (DataType::Id(a), DataType::Id(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+// Conditions of a where combined with and, or and not
+pub enum Filter {
+    // <sub key> <op> <value>
+    Condition(String, FindOp, DataType),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn matches(&self, item: &DataType) -> bool {
+        match self {
+            Filter::Condition(sub_key, op, value) => item.matches(sub_key, op, value),
+            Filter::And(a, b) => a.matches(item) && b.matches(item),
+            Filter::Or(a, b) => a.matches(item) || b.matches(item),
+            Filter::Not(filter) => !filter.matches(item),
+        }
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -50,6 +104,18 @@ impl DataType {
         }
     }
 
+    // type code used by the file format, inverse of `load`
+    pub fn type_id(&self) -> u16 {
+        match self {
+            DataType::Id(_) => 1,
+            DataType::Text(_) => 2,
+            DataType::Number(_) => 3,
+            DataType::Boolean(_) => 4,
+            DataType::Array(_) => 5,
+            DataType::Document(_) => 6,
+        }
+    }
+
     pub fn get(&self, index: &str) -> Option<&DataType> {
         match self {
             DataType::Array(v) => {
@@ -72,29 +138,99 @@ impl DataType {
         }
     }
 
-    pub fn find(&self, sub_key: &str, op: FindOp, value: DataType) -> Option<DataType> {
-        let mut result: Vec<DataType> = Vec::new();
+    // Values at the dotted path, `*` goes through every item of an array
+    // or value of a document
+    pub fn select<'a>(&'a self, path: &[&str], result: &mut Vec<&'a DataType>) {
+        let Some((key, rest)) = path.split_first() else {
+            result.push(self);
+            return;
+        };
+        match (*key, self) {
+            ("*", DataType::Array(items)) => items.iter().for_each(|v| v.select(rest, result)),
+            ("*", DataType::Document(document)) => {
+                document.values().for_each(|v| v.select(rest, result))
+            }
+            // keys may have dots, the longest one on the path is taken
+            (_, DataType::Document(document)) => {
+                let found = (1..=path.len()).rev().find_map(|n| {
+                    let value = document.get(&path[..n].join("."))?;
+                    Some((value, &path[n..]))
+                });
+                if let Some((value, rest)) = found {
+                    value.select(rest, result);
+                }
+            }
+            _ => {
+                if let Some(value) = self.get(key) {
+                    value.select(rest, result);
+                }
+            }
+        }
+    }
+
+    // If any value at the sub key path (`customer.address.city`,
+    // `tags.*`) compares to the value given with op. The negative ops
+    // (`!=`, `not in`) need all of them to compare, so `tags.* != "x"`
+    // is true when no tag is "x". A missing sub key never matches
+    pub fn matches(&self, sub_key: &str, op: &FindOp, value: &DataType) -> bool {
+        let path: Vec<&str> = sub_key.split('.').collect();
+        let mut found = Vec::new();
+        self.select(&path, &mut found);
+        let mut found = found.into_iter();
+        match op {
+            FindOp::NotEq | FindOp::NotIn => {
+                found.len() > 0 && found.all(|sub_item| sub_item.compares(op, value))
+            }
+            _ => found.any(|sub_item| sub_item.compares(op, value)),
+        }
+    }
+
+    // If the value compares to the other one with op
+    fn compares(&self, op: &FindOp, value: &DataType) -> bool {
+        let order = || compare(self, value);
+        let text = match self {
+            DataType::Text(text) => Some(text.as_str()),
+            _ => None,
+        };
+        let pattern = match value {
+            DataType::Text(pattern) => Some(pattern.as_str()),
+            _ => None,
+        };
+        match op {
+            FindOp::Eq => self == value,
+            FindOp::NotEq => self != value,
+            FindOp::Gt => order() == Some(Ordering::Greater),
+            FindOp::Lt => order() == Some(Ordering::Less),
+            FindOp::Ge => order().is_some_and(|o| o.is_ge()),
+            FindOp::Le => order().is_some_and(|o| o.is_le()),
+            FindOp::In => matches!(value, DataType::Array(v) if v.contains(self)),
+            FindOp::NotIn => matches!(value, DataType::Array(v) if !v.contains(self)),
+            FindOp::Exists => true,
+            FindOp::Contains => match (self, pattern) {
+                (DataType::Array(items), _) => items.contains(value),
+                (DataType::Text(text), Some(pattern)) => text.contains(pattern),
+                _ => false,
+            },
+            FindOp::StartsWith => text.zip(pattern).is_some_and(|(t, p)| t.starts_with(p)),
+            FindOp::EndsWith => text.zip(pattern).is_some_and(|(t, p)| t.ends_with(p)),
+            FindOp::Matches(regex) => text.is_some_and(|t| regex.is_match(t)),
+            FindOp::Between => match value {
+                DataType::Array(ends) if ends.len() == 2 => {
+                    let from = compare(self, &ends[0]);
+                    let to = compare(self, &ends[1]);
+                    from.is_some_and(|o| o.is_ge()) && to.is_some_and(|o| o.is_le())
+                }
+                _ => false,
+            },
+        }
+    }
+
+    // Items of the array that match the filter
+    pub fn find(&self, filter: &Filter) -> Option<DataType> {
         match self {
             DataType::Array(v) => {
-                for item in v {
-                    let sub_item = item.get(sub_key);
-                    if sub_item.is_none() {
-                        continue;
-                    }
-                    let sub_item = sub_item.unwrap();
-                    let items_are_number = matches!(value, DataType::Number(_))
-                        && matches!(*sub_item, DataType::Number(_));
-                    let matched = match op {
-                        FindOp::Eq => *sub_item == value,
-                        FindOp::NotEq => *sub_item != value,
-                        FindOp::Gt => items_are_number && sub_item.to_number() > value.to_number(),
-                        FindOp::Lt => items_are_number && sub_item.to_number() < value.to_number(),
-                    };
-                    if matched {
-                        result.push(item.clone());
-                    }
-                }
-                return Some(DataType::Array(result));
+                let result = v.iter().filter(|item| filter.matches(item));
+                Some(DataType::Array(result.cloned().collect()))
             }
 
             _ => None,
@@ -141,6 +277,20 @@ impl DataType {
         }
     }
 
+    // Rough estimate of the memory used by the value, in bytes
+    pub fn approx_size(&self) -> usize {
+        let payload = match self {
+            DataType::Id(_) | DataType::Number(_) | DataType::Boolean(_) => 0,
+            DataType::Text(text) => text.capacity(),
+            DataType::Array(array) => array.iter().map(|v| v.approx_size()).sum(),
+            DataType::Document(document) => document
+                .iter()
+                .map(|(k, v)| k.capacity() + v.approx_size())
+                .sum(),
+        };
+        std::mem::size_of::<DataType>() + payload
+    }
+
     //add into
     pub fn to_id(&self) -> Uuid {
         match self {
@@ -476,4 +626,86 @@ mod tests {
         let expected = DataType::from(vec![d!("hello"), d!(10)]);
         assert!(dd == expected);
     }
+
+    #[test]
+    fn test_approx_size() {
+        let base = std::mem::size_of::<DataType>();
+        assert_eq!(d!(10).approx_size(), base);
+        assert!(d!("Hello").approx_size() >= base + 5);
+        let array = d!(["hello", 10]);
+        assert!(array.approx_size() >= 3 * base + 5);
+    }
+
+    #[test]
+    fn test_find_ops() {
+        use super::FindOp;
+        use std::collections::HashMap;
+        let mut user = HashMap::new();
+        user.insert("name".to_string(), d!("ana"));
+        user.insert("age".to_string(), d!(30));
+        user.insert("tags".to_string(), d!(["admin", "dev"]));
+        let user = DataType::Document(user);
+        let check = |key: &str, op: FindOp, value: DataType| user.matches(key, &op, &value);
+
+        assert!(check("age", FindOp::Ge, d!(30)));
+        assert!(!check("age", FindOp::Lt, d!(30)));
+        assert!(check("name", FindOp::Gt, d!("alberto")));
+        assert!(!check("name", FindOp::Gt, d!(1)));
+        assert!(check("age", FindOp::In, d!([20, 30])));
+        assert!(check("name", FindOp::NotIn, d!(["eva"])));
+        assert!(check("tags", FindOp::Exists, d!(true)));
+        assert!(!check("email", FindOp::Exists, d!(true)));
+        assert!(check("tags", FindOp::Contains, d!("dev")));
+        assert!(check("name", FindOp::Contains, d!("n")));
+        assert!(check("name", FindOp::StartsWith, d!("an")));
+        assert!(!check("name", FindOp::EndsWith, d!("an")));
+        assert!(check("name", FindOp::matches("^a.a$").unwrap(), d!("")));
+        assert!(FindOp::matches("(").is_err());
+        assert!(check("age", FindOp::Between, d!([18, 30])));
+        assert!(!check("name", FindOp::Between, d!(["b", "z"])));
+    }
+
+    #[test]
+    fn test_find_paths() {
+        use super::FindOp;
+        use std::collections::HashMap;
+        // nested paths, array positions and any item with *
+        let mut address = HashMap::new();
+        address.insert("city".to_string(), d!("Madrid"));
+        let mut order = HashMap::new();
+        order.insert("address".to_string(), DataType::Document(address));
+        let items = DataType::Array(vec![d!([1, 2]), d!([3])]);
+        order.insert("items".to_string(), items);
+        order.insert("tags".to_string(), d!(["urgent", "gift"]));
+        let order = DataType::Document(order);
+        let check = |key: &str, op: FindOp, value: DataType| order.matches(key, &op, &value);
+        assert!(check("address.city", FindOp::Eq, d!("Madrid")));
+        assert!(!check("address.zip", FindOp::Exists, d!(true)));
+        assert!(check("items.1.0", FindOp::Eq, d!(3)));
+        assert!(check("tags.*", FindOp::Eq, d!("urgent")));
+        assert!(!check("tags.*", FindOp::Eq, d!("late")));
+        assert!(check("items.*.*", FindOp::Gt, d!(2)));
+        assert!(check("*.city", FindOp::StartsWith, d!("Ma")));
+
+        // the negative ops match when every item does
+        assert!(!check("tags.*", FindOp::NotEq, d!("urgent")));
+        assert!(check("tags.*", FindOp::NotEq, d!("late")));
+        assert!(!check("tags.*", FindOp::NotIn, d!(["late", "gift"])));
+        assert!(check("tags.*", FindOp::NotIn, d!(["late"])));
+        assert!(!check("notes.*", FindOp::NotEq, d!("late")));
+        assert!(!check("address.zip", FindOp::NotIn, d!(["28001"])));
+
+        // keys with dots, the longest key on the path first
+        let mut meta = HashMap::new();
+        meta.insert("v1.2".to_string(), d!("old"));
+        meta.insert("v1".to_string(), d!(["new"]));
+        let mut item = HashMap::new();
+        item.insert("meta".to_string(), DataType::Document(meta));
+        item.insert("a.b".to_string(), d!(1));
+        let item = DataType::Document(item);
+        let check = |key: &str, op: FindOp, value: DataType| item.matches(key, &op, &value);
+        assert!(check("a.b", FindOp::Eq, d!(1)));
+        assert!(check("meta.v1.2", FindOp::Eq, d!("old")));
+        assert!(check("meta.v1.0", FindOp::Eq, d!("new")));
+    }
 }
@@ -0,0 +1,78 @@
+// Memory budget of a collection or of the whole database
+// Once the approximate size of the data goes over the budget, keys are
+// evicted following the policy until it fits again. With noeviction the
+// writes fail instead.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionPolicy {
+    // least recently used keys first
+    Lru,
+    // least frequently used keys first
+    Lfu,
+    Random,
+    // keys with a ttl, the closest to expire first
+    VolatileTtl,
+    NoEviction,
+}
+
+impl EvictionPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EvictionPolicy::Lru => "lru",
+            EvictionPolicy::Lfu => "lfu",
+            EvictionPolicy::Random => "random",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+            EvictionPolicy::NoEviction => "noeviction",
+        }
+    }
+
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy {
+            "lru" => Some(EvictionPolicy::Lru),
+            "lfu" => Some(EvictionPolicy::Lfu),
+            "random" => Some(EvictionPolicy::Random),
+            "volatile-ttl" => Some(EvictionPolicy::VolatileTtl),
+            "noeviction" => Some(EvictionPolicy::NoEviction),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryLimit {
+    // approximate size of the data, as given by approx_size
+    pub max_bytes: usize,
+    pub policy: EvictionPolicy,
+}
+
+pub const OUT_OF_MEMORY: &str = "Out of memory, nothing left to evict";
+
+// Shared by all the collections so the last uses of their keys can be
+// compared when evicting for the whole database
+static CLOCK: AtomicU64 = AtomicU64::new(0);
+
+// Use of a key, kept while a memory limit may apply
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Usage {
+    pub last: u64,
+    pub hits: u64,
+}
+
+impl Usage {
+    pub fn new() -> Self {
+        Usage {
+            last: CLOCK.fetch_add(1, Ordering::Relaxed),
+            hits: 0,
+        }
+    }
+
+    pub fn touch(&mut self) {
+        self.last = CLOCK.fetch_add(1, Ordering::Relaxed);
+        self.hits += 1;
+    }
+}
+
+pub(crate) fn random() -> u64 {
+    uuid::Uuid::new_v4().as_u128() as u64
+}
@@ -1,65 +1,430 @@
 // Written by Alberto Ruiz 2024-03-08
-// [Unfinished] module to index and create search structures
+// Module to index and create search structures
 //
-// The B-tree will be used to index the documents
-// and provide a fast search
+// The B-tree is used to index the documents by the value of a field
+// and provide a fast search by value or by range of values
+use super::data_type::DataType;
+use std::cmp::Ordering;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
 
+// Nodes hold between MIN_DEGREE - 1 and 2 * MIN_DEGREE - 1 keys, the root
+// may hold less
+const MIN_DEGREE: usize = 4;
+const MAX_KEYS: usize = 2 * MIN_DEGREE - 1;
 
-// B-tree node
-//      v: value of the content to index
-//      p: position of the document in the collection
-struct MIndex {
-    pub v: i32,
-    pub p: Vec<i32>,
+// DataType with a total order so it can be used as a key of the B-tree.
+// Values of different types are ordered by type: booleans, numbers,
+// texts, ids, arrays and documents
+#[derive(Clone, Debug)]
+pub struct IndexKey(pub DataType);
+
+fn rank(value: &DataType) -> u8 {
+    match value {
+        DataType::Boolean(_) => 0,
+        DataType::Number(_) => 1,
+        DataType::Text(_) => 2,
+        DataType::Id(_) => 3,
+        DataType::Array(_) => 4,
+        DataType::Document(_) => 5,
+    }
 }
 
-//B-tree node
-pub struct BNode {
-    keys: Vec<MIndex>,
-    children: Vec<Box<BNode>>,
+fn compare(a: &DataType, b: &DataType) -> Ordering {
+    match (a, b) {
+        (DataType::Boolean(a), DataType::Boolean(b)) => a.cmp(b),
+        (DataType::Number(a), DataType::Number(b)) => a.total_cmp(b),
+        (DataType::Text(a), DataType::Text(b)) => a.cmp(b),
+        (DataType::Id(a), DataType::Id(b)) => a.cmp(b),
+        (DataType::Array(a), DataType::Array(b)) => {
+            let items = a.iter().zip(b.iter());
+            let first = items.map(|(a, b)| compare(a, b)).find(|o| o.is_ne());
+            first.unwrap_or_else(|| a.len().cmp(&b.len()))
+        }
+        (DataType::Document(a), DataType::Document(b)) => {
+            // documents are compared by their entries sorted by key
+            let mut a: Vec<_> = a.iter().collect();
+            let mut b: Vec<_> = b.iter().collect();
+            a.sort_by(|x, y| x.0.cmp(y.0));
+            b.sort_by(|x, y| x.0.cmp(y.0));
+            let entries = a.iter().zip(b.iter());
+            let first = entries
+                .map(|((ka, va), (kb, vb))| ka.cmp(kb).then_with(|| compare(va, vb)))
+                .find(|o| o.is_ne());
+            first.unwrap_or_else(|| a.len().cmp(&b.len()))
+        }
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
-impl BNode {
-    pub fn new() -> BNode {
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for IndexKey {}
+
+impl From<DataType> for IndexKey {
+    fn from(value: DataType) -> Self {
+        IndexKey(value)
+    }
+}
+
+// B-tree node, the children between two keys hold the keys between them
+//      entries: keys with the values stored for them
+//      children: empty in the leaves, one more than entries otherwise
+struct BNode<K, V> {
+    entries: Vec<(K, Vec<V>)>,
+    children: Vec<BNode<K, V>>,
+}
+
+impl<K: Ord, V: PartialEq> BNode<K, V> {
+    fn new() -> Self {
         BNode {
-            keys: Vec::new(),
+            entries: Vec::new(),
             children: Vec::new(),
         }
     }
 
-    fn _search(node: &BNode, value: i32) -> Option<Vec<i32>> {
-        let mut i = 0;
-        while i < node.keys.len() && value > node.keys[i].v {
-            i += 1;
+    fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    // Position of the key, or of the child that may hold it
+    fn position(&self, key: &K) -> Result<usize, usize> {
+        self.entries.binary_search_by(|(k, _)| k.cmp(key))
+    }
+
+    fn get(&self, key: &K) -> Option<&Vec<V>> {
+        match self.position(key) {
+            Ok(i) => Some(&self.entries[i].1),
+            Err(_) if self.is_leaf() => None,
+            Err(i) => self.children[i].get(key),
+        }
+    }
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut Vec<V>> {
+        match self.position(key) {
+            Ok(i) => Some(&mut self.entries[i].1),
+            Err(_) if self.is_leaf() => None,
+            Err(i) => self.children[i].get_mut(key),
         }
-        if i < node.keys.len() && value == node.keys[i].v {
-            return Some(node.keys[i].p.clone());
+    }
+
+    // Splits the full child i in two, moving its middle key up to this node
+    fn split_child(&mut self, i: usize) {
+        let child = &mut self.children[i];
+        let right = BNode {
+            entries: child.entries.split_off(MIN_DEGREE),
+            children: if child.is_leaf() {
+                Vec::new()
+            } else {
+                child.children.split_off(MIN_DEGREE)
+            },
+        };
+        let middle = child.entries.pop().unwrap();
+        self.entries.insert(i, middle);
+        self.children.insert(i + 1, right);
+    }
+
+    // Inserts a key that is not in the tree into a node that is not full
+    fn insert_non_full(&mut self, key: K, value: V) {
+        let mut i = self.position(&key).unwrap_err();
+        if self.is_leaf() {
+            self.entries.insert(i, (key, vec![value]));
+            return;
         }
-        if node.children.len() > 0 {
-            return BNode::_search(&node.children[i], value);
+        if self.children[i].entries.len() == MAX_KEYS {
+            self.split_child(i);
+            if key > self.entries[i].0 {
+                i += 1;
+            }
         }
-        None
+        self.children[i].insert_non_full(key, value);
     }
 
-    pub fn search(&self, value: i32) -> Option<Vec<i32>> {
-        BNode::_search(&self, value)
+    // Joins the child i + 1 and the key i into the child i
+    fn merge_children(&mut self, i: usize) {
+        let (key, right) = (self.entries.remove(i), self.children.remove(i + 1));
+        let left = &mut self.children[i];
+        left.entries.push(key);
+        left.entries.extend(right.entries);
+        left.children.extend(right.children);
     }
 
-    pub fn insert(&mut self, value: i32, pos: i32) {
-        let mut i = 0;
-        while i < self.keys.len() && value > self.keys[i].v {
-            i += 1;
+    // Makes sure the child i has more than the minimum of keys before
+    // going down into it, returns the child to go down into
+    fn fill_child(&mut self, i: usize) -> usize {
+        if self.children[i].entries.len() >= MIN_DEGREE {
+            return i;
         }
-        if i < self.keys.len() && value == self.keys[i].v {
-            self.keys[i].p.push(pos);
-            return;
+        if i > 0 && self.children[i - 1].entries.len() >= MIN_DEGREE {
+            // borrow from the left sibling through the parent
+            let left = &mut self.children[i - 1];
+            let entry = left.entries.pop().unwrap();
+            let child = left.children.pop();
+            let entry = mem::replace(&mut self.entries[i - 1], entry);
+            let node = &mut self.children[i];
+            node.entries.insert(0, entry);
+            if let Some(child) = child {
+                node.children.insert(0, child);
+            }
+            i
+        } else if i + 1 < self.children.len() && self.children[i + 1].entries.len() >= MIN_DEGREE {
+            let right = &mut self.children[i + 1];
+            let entry = right.entries.remove(0);
+            let child = (!right.is_leaf()).then(|| right.children.remove(0));
+            let entry = mem::replace(&mut self.entries[i], entry);
+            let node = &mut self.children[i];
+            node.entries.push(entry);
+            if let Some(child) = child {
+                node.children.push(child);
+            }
+            i
+        } else if i + 1 < self.children.len() {
+            self.merge_children(i);
+            i
+        } else {
+            self.merge_children(i - 1);
+            i - 1
         }
-        if self.children.len() > 0 {
-            self.children[i].insert(value, pos);
-            return;
+    }
+
+    fn remove_last(&mut self) -> (K, Vec<V>) {
+        if self.is_leaf() {
+            return self.entries.pop().unwrap();
+        }
+        let i = self.fill_child(self.children.len() - 1);
+        self.children[i].remove_last()
+    }
+
+    fn remove_first(&mut self) -> (K, Vec<V>) {
+        if self.is_leaf() {
+            return self.entries.remove(0);
         }
-        self.keys.insert(i, MIndex { v: value, p: vec![pos] });
+        let i = self.fill_child(0);
+        self.children[i].remove_first()
     }
 
+    // Removes the key from the subtree, the node has more than the minimum
+    // of keys unless it is the root
+    fn remove(&mut self, key: &K) -> Option<(K, Vec<V>)> {
+        match self.position(key) {
+            Ok(i) if self.is_leaf() => Some(self.entries.remove(i)),
+            Ok(i) => {
+                if self.children[i].entries.len() >= MIN_DEGREE {
+                    // replaced by the previous key
+                    let previous = self.children[i].remove_last();
+                    Some(mem::replace(&mut self.entries[i], previous))
+                } else if self.children[i + 1].entries.len() >= MIN_DEGREE {
+                    let next = self.children[i + 1].remove_first();
+                    Some(mem::replace(&mut self.entries[i], next))
+                } else {
+                    self.merge_children(i);
+                    self.children[i].remove(key)
+                }
+            }
+            Err(_) if self.is_leaf() => None,
+            Err(i) => {
+                let i = self.fill_child(i);
+                self.children[i].remove(key)
+            }
+        }
+    }
 
-}
\ No newline at end of file
+    fn collect<'a>(&'a self, range: &impl RangeBounds<K>, out: &mut Vec<(&'a K, &'a V)>) {
+        for i in 0..=self.entries.len() {
+            // keys of the child i are between the keys i - 1 and i
+            let after_start = i == self.entries.len()
+                || match range.start_bound() {
+                    Bound::Included(start) | Bound::Excluded(start) => self.entries[i].0 > *start,
+                    Bound::Unbounded => true,
+                };
+            let before_end = i == 0
+                || match range.end_bound() {
+                    Bound::Included(end) | Bound::Excluded(end) => self.entries[i - 1].0 < *end,
+                    Bound::Unbounded => true,
+                };
+            if !self.is_leaf() && after_start && before_end {
+                self.children[i].collect(range, out);
+            }
+            if let Some((key, values)) = self.entries.get(i)
+                && range.contains(key)
+            {
+                out.extend(values.iter().map(|v| (key, v)));
+            }
+        }
+    }
+
+    // Checks the order and the sizes of the nodes, returns the height
+    #[cfg(test)]
+    fn check(&self, root: bool) -> usize {
+        assert!(self.entries.len() <= MAX_KEYS);
+        assert!(root || self.entries.len() >= MIN_DEGREE - 1);
+        assert!(self.entries.windows(2).all(|w| w[0].0 < w[1].0));
+        if self.is_leaf() {
+            return 1;
+        }
+        assert_eq!(self.children.len(), self.entries.len() + 1);
+        let heights: Vec<usize> = self.children.iter().map(|c| c.check(false)).collect();
+        assert!(heights.iter().all(|h| *h == heights[0]));
+        heights[0] + 1
+    }
+}
+
+// Balanced B-tree keeping several values for each key
+pub struct BTree<K, V> {
+    root: BNode<K, V>,
+    len: usize,
+}
+
+impl<K: Ord, V: PartialEq> BTree<K, V> {
+    pub fn new() -> Self {
+        BTree {
+            root: BNode::new(),
+            len: 0,
+        }
+    }
+
+    // Number of values stored
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Adds the value to the key, false if it was already there
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        if let Some(values) = self.root.get_mut(&key) {
+            if values.contains(&value) {
+                return false;
+            }
+            values.push(value);
+            self.len += 1;
+            return true;
+        }
+        if self.root.entries.len() == MAX_KEYS {
+            let old_root = mem::replace(&mut self.root, BNode::new());
+            self.root.children.push(old_root);
+            self.root.split_child(0);
+        }
+        self.root.insert_non_full(key, value);
+        self.len += 1;
+        true
+    }
+
+    // Removes the value from the key, and the key once it has no values.
+    // False if it was not there
+    pub fn remove(&mut self, key: &K, value: &V) -> bool {
+        let Some(values) = self.root.get_mut(key) else {
+            return false;
+        };
+        let Some(i) = values.iter().position(|v| v == value) else {
+            return false;
+        };
+        values.remove(i);
+        self.len -= 1;
+        if values.is_empty() {
+            self.remove_key(key);
+        }
+        true
+    }
+
+    // Removes the key with all its values
+    pub fn remove_key(&mut self, key: &K) -> Option<Vec<V>> {
+        let (_, values) = self.root.remove(key)?;
+        self.len -= values.len();
+        // the root goes away when its last key is merged into a child
+        if self.root.entries.is_empty() && !self.root.is_leaf() {
+            self.root = self.root.children.remove(0);
+        }
+        Some(values)
+    }
+
+    // Values of the key
+    pub fn get(&self, key: &K) -> &[V] {
+        self.root.get(key).map(|v| v.as_slice()).unwrap_or_default()
+    }
+
+    // Keys in the range with each of their values, in order
+    pub fn range(&self, range: impl RangeBounds<K>) -> Vec<(&K, &V)> {
+        let mut out = Vec::new();
+        self.root.collect(&range, &mut out);
+        out
+    }
+}
+
+impl<K: Ord, V: PartialEq> Default for BTree<K, V> {
+    fn default() -> Self {
+        BTree::new()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_btree() {
+    let mut tree = BTree::new();
+    // inserted out of order to split on both sides
+    for i in 0..500 {
+        assert!(tree.insert((i * 7919) % 500, i));
+    }
+    assert!(!tree.insert(7919 % 500, 1));
+    assert!(tree.insert(7919 % 500, 1000));
+    assert_eq!(tree.len(), 501);
+    assert!(tree.root.check(true) > 2);
+    assert_eq!(tree.get(&(7919 % 500)), &[1, 1000]);
+    assert_eq!(tree.get(&600), &[] as &[i32]);
+
+    let keys: Vec<i32> = tree.range(10..20).iter().map(|(k, _)| **k).collect();
+    assert_eq!(keys, (10..20).collect::<Vec<i32>>());
+    assert_eq!(tree.range(..=2).len(), 3);
+    assert_eq!(tree.range(495..).len(), 5);
+
+    // removing merges the nodes back down to a single leaf
+    assert!(tree.remove(&(7919 % 500), &1000));
+    assert!(!tree.remove(&(7919 % 500), &1000));
+    for key in (0..500).rev().step_by(2).chain((0..500).step_by(2)) {
+        tree.remove_key(&key);
+        tree.root.check(true);
+    }
+    assert!(tree.is_empty());
+    assert!(tree.root.is_leaf() && tree.root.entries.is_empty());
+}
+
+#[test]
+fn test_index_key() {
+    let key = |v: DataType| IndexKey(v);
+    assert!(key(DataType::from(2)) < key(DataType::from(10)));
+    assert!(key(DataType::from("b")) > key(DataType::from("a")));
+    assert!(key(DataType::from(true)) < key(DataType::from(0)));
+    assert!(key(DataType::from(100)) < key(DataType::from("1")));
+    let short = DataType::Array(vec![DataType::from(1)]);
+    let long = DataType::Array(vec![DataType::from(1), DataType::from(0)]);
+    assert!(key(short) < key(long));
+
+    let mut tree = BTree::new();
+    tree.insert(key(DataType::from("madrid")), "ana".to_string());
+    tree.insert(key(DataType::from("lisboa")), "joao".to_string());
+    tree.insert(key(DataType::from(3)), "num".to_string());
+    let texts = tree.range(key(DataType::from(""))..);
+    assert_eq!(texts.len(), 2);
+    assert_eq!(
+        tree.get(&key(DataType::from("madrid"))),
+        &["ana".to_string()]
+    );
+}
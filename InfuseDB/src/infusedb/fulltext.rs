@@ -0,0 +1,192 @@
+// Full-text index of a collection
+// The texts of each top level key (or only the one at a field path) are split
+// in lowercase terms without stop words, and kept in an inverted index so
+// `search` ranks the keys with BM25 without tokenizing the whole collection
+use super::collection::Keys;
+use super::data_type::DataType;
+use super::index::field_value;
+use std::collections::{HashMap, HashSet};
+
+// BM25 term frequency saturation and length normalization
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "from", "if", "in", "into",
+    "is", "it", "its", "no", "not", "of", "on", "or", "so", "such", "that", "the", "their", "then",
+    "there", "these", "they", "this", "to", "was", "were", "will", "with",
+];
+
+// Lowercase words of the text, without stop words
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+        .collect()
+}
+
+// Texts inside the value, in documents and arrays too
+fn texts<'a>(value: &'a DataType, result: &mut Vec<&'a str>) {
+    match value {
+        DataType::Text(text) => result.push(text),
+        DataType::Array(items) => items.iter().for_each(|item| texts(item, result)),
+        DataType::Document(document) => document.values().for_each(|v| texts(v, result)),
+        _ => {}
+    }
+}
+
+pub struct TextIndex {
+    // path of the text indexed in each document, None for all of them
+    pub field: Option<String>,
+    // frequency of the terms of each key
+    documents: HashMap<String, HashMap<String, u32>>,
+    // number of terms of each key
+    lengths: HashMap<String, usize>,
+    // keys with each term
+    terms: HashMap<String, HashSet<String>>,
+    total_length: usize,
+}
+
+impl TextIndex {
+    // `*` indexes every text, anything else is the path of the field
+    pub fn new(spec: &str) -> Self {
+        TextIndex {
+            field: (spec != "*").then(|| spec.to_string()),
+            documents: HashMap::new(),
+            lengths: HashMap::new(),
+            terms: HashMap::new(),
+            total_length: 0,
+        }
+    }
+
+    pub fn spec(&self) -> &str {
+        self.field.as_deref().unwrap_or("*")
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    pub fn terms(&self) -> usize {
+        self.terms.len()
+    }
+
+    pub(crate) fn build(&mut self, data: &Keys) {
+        self.documents.clear();
+        self.lengths.clear();
+        self.terms.clear();
+        self.total_length = 0;
+        for (key, value) in data.iter() {
+            self.update(key, Some(value));
+        }
+    }
+
+    // Indexes the current value of the key, None when it was deleted
+    pub(crate) fn update(&mut self, key: &str, value: Option<&DataType>) {
+        if let Some(old) = self.documents.remove(key) {
+            for term in old.keys() {
+                if let Some(keys) = self.terms.get_mut(term) {
+                    keys.remove(key);
+                    if keys.is_empty() {
+                        self.terms.remove(term);
+                    }
+                }
+            }
+            self.total_length -= self.lengths.remove(key).unwrap_or(0);
+        }
+        let value = match (value, self.field.as_deref()) {
+            (Some(value), Some(field)) => field_value(value, field),
+            (value, None) => value,
+            (None, _) => None,
+        };
+        let mut found = Vec::new();
+        if let Some(value) = value {
+            texts(value, &mut found);
+        }
+        let mut frequencies: HashMap<String, u32> = HashMap::new();
+        let mut length = 0;
+        for term in found.into_iter().flat_map(tokenize) {
+            *frequencies.entry(term).or_default() += 1;
+            length += 1;
+        }
+        if frequencies.is_empty() {
+            return;
+        }
+        for term in frequencies.keys() {
+            let keys = self.terms.entry(term.clone()).or_default();
+            keys.insert(key.to_string());
+        }
+        self.documents.insert(key.to_string(), frequencies);
+        self.lengths.insert(key.to_string(), length);
+        self.total_length += length;
+    }
+
+    // Keys with any of the terms of the query, the most relevant first
+    pub fn search(&self, query: &str) -> Vec<(String, f32)> {
+        let mut query = tokenize(query);
+        query.sort();
+        query.dedup();
+        let count = self.documents.len() as f32;
+        let average = self.total_length as f32 / count.max(1.0);
+        let mut scores: HashMap<&str, f32> = HashMap::new();
+        for term in query.iter() {
+            let Some(keys) = self.terms.get(term) else {
+                continue;
+            };
+            let found = keys.len() as f32;
+            let idf = ((count - found + 0.5) / (found + 0.5) + 1.0).ln();
+            for key in keys.iter() {
+                let frequency = self.documents[key][term] as f32;
+                let length = self.lengths[key] as f32;
+                let norm = K1 * (1.0 - B + B * length / average);
+                *scores.entry(key).or_default() +=
+                    idf * frequency * (K1 + 1.0) / (frequency + norm);
+            }
+        }
+        let mut result: Vec<(String, f32)> = scores
+            .into_iter()
+            .map(|(k, s)| (k.to_string(), s))
+            .collect();
+        result.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        result
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_search() {
+    use std::sync::Arc;
+    assert_eq!(tokenize("The Rust, and the DB!"), vec!["rust", "db"]);
+
+    let mut data = Keys::new();
+    let note = |text: &str| Arc::new(DataType::from(text));
+    data.insert("a".to_string(), note("rust is fast and rust is safe"));
+    data.insert("b".to_string(), note("a database written in rust"));
+    data.insert("c".to_string(), note("python notes"));
+    let mut index = TextIndex::new("*");
+    index.build(&data);
+    let keys = |found: Vec<(String, f32)>| found.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    assert_eq!(keys(index.search("Rust")), vec!["a", "b"]);
+    assert_eq!(keys(index.search("database rust")), vec!["b", "a"]);
+    assert!(index.search("the").is_empty());
+
+    index.update("a", None);
+    index.update("c", Some(&DataType::from("rust notes")));
+    // the shorter text ranks first
+    assert_eq!(keys(index.search("rust")), vec!["c", "b"]);
+    assert_eq!(index.len(), 2);
+
+    // only the field of the documents
+    let mut index = TextIndex::new("title");
+    let mut document = HashMap::new();
+    document.insert("title".to_string(), DataType::from("Rust"));
+    document.insert("body".to_string(), DataType::from("python"));
+    index.update("d", Some(&DataType::Document(document)));
+    assert_eq!(keys(index.search("rust")), vec!["d"]);
+    assert!(index.search("python").is_empty());
+}
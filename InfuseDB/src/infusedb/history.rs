@@ -0,0 +1,95 @@
+// History of the values of the keys of a collection
+// Every write of a top level key records its new value, shared with the
+// collection, and the time it was written. A deletion records None.
+// Records are kept while the retention allows it, the last one of each key
+// is always kept while the key exists.
+use super::data_type::DataType;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Limits of the history, None means unlimited
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Retention {
+    // values kept for each key, the current one included
+    pub versions: Option<usize>,
+    // time a replaced value is kept
+    pub max_age: Option<Duration>,
+}
+
+type Record = (SystemTime, Option<Arc<DataType>>);
+
+#[derive(Clone)]
+pub(crate) struct History {
+    pub retention: Retention,
+    // records of each key from the oldest to the newest
+    keys: HashMap<String, VecDeque<Record>>,
+}
+
+impl History {
+    pub fn new(retention: Retention) -> Self {
+        History {
+            retention,
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, key: &str, value: Option<Arc<DataType>>, now: SystemTime) {
+        // times are shown and parsed in milliseconds, a record must be found
+        // with the time shown for it
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let now = UNIX_EPOCH + Duration::from_millis(since_epoch.as_millis() as u64);
+        let records = self.keys.entry(key.to_string()).or_default();
+        records.push_back((now, value));
+        History::prune_records(&self.retention, records, now);
+    }
+
+    fn prune_records(retention: &Retention, records: &mut VecDeque<Record>, now: SystemTime) {
+        if let Some(versions) = retention.versions {
+            while records.len() > versions.max(1) {
+                records.pop_front();
+            }
+        }
+        if let Some(max_age) = retention.max_age {
+            let limit = now.checked_sub(max_age).unwrap_or(UNIX_EPOCH);
+            // a record is needed until the one replacing it gets too old
+            while records.len() > 1 && records[1].0 <= limit {
+                records.pop_front();
+            }
+        }
+    }
+
+    pub fn prune(&mut self, now: SystemTime) {
+        let retention = self.retention;
+        let limit = retention
+            .max_age
+            .and_then(|max_age| now.checked_sub(max_age));
+        self.keys.retain(|_, records| {
+            History::prune_records(&retention, records, now);
+            // keys deleted before the retained time are forgotten
+            !(records.len() == 1
+                && records[0].1.is_none()
+                && limit.is_some_and(|limit| records[0].0 <= limit))
+        });
+    }
+
+    // Value of the key at the time, Some(None) if it did not exist then and
+    // None if the history does not reach that time
+    pub fn at(&self, key: &str, time: SystemTime) -> Option<Option<&DataType>> {
+        let records = self.keys.get(key)?;
+        let (_, value) = records.iter().rev().find(|(at, _)| *at <= time)?;
+        Some(value.as_deref())
+    }
+
+    pub fn records(&self, key: &str) -> Vec<(SystemTime, Option<DataType>)> {
+        self.keys
+            .get(key)
+            .map(|records| {
+                records
+                    .iter()
+                    .map(|(at, value)| (*at, value.as_deref().cloned()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,338 @@
+// Named secondary indexes of a collection
+// An index keeps, in a B-tree, the value of a field of the documents of the
+// collection (`*.<field>`) or of the items of an array key
+// (`<key.path>[*].<field>`), so they can be found without a scan. The field
+// is found like in the where conditions, with `*` it gives several values
+use super::collection::Keys;
+use super::data_type::{DataType, FindOp};
+use super::finder::{BTree, IndexKey};
+use std::collections::HashSet;
+use std::ops::{Bound, RangeBounds};
+use uuid::Uuid;
+
+pub struct Index {
+    pub name: String,
+    // key path of the array whose items are indexed, None for the documents
+    pub array: Option<String>,
+    // path of the field inside each document or item
+    pub field: String,
+    // no two documents may have the same value of the field
+    pub unique: bool,
+    // keys of the documents by value
+    documents: BTree<IndexKey, String>,
+    // positions of the items by value
+    items: BTree<IndexKey, usize>,
+}
+
+pub const UNIQUE_VIOLATION: &str = "Value already used in a unique index";
+
+// Values at the field path, found like in the where conditions: `*` goes
+// through every item and the keys may have dots
+pub(crate) fn field_values<'a>(value: &'a DataType, path: &str) -> Vec<&'a DataType> {
+    let path: Vec<&str> = path.split('.').collect();
+    let mut found = Vec::new();
+    value.select(&path, &mut found);
+    found
+}
+
+pub(crate) fn field_value<'a>(value: &'a DataType, path: &str) -> Option<&'a DataType> {
+    field_values(value, path).into_iter().next()
+}
+
+// Lowest and highest keys of the values of the type, ordered ones only
+fn type_bounds(value: &DataType) -> Option<(Bound<IndexKey>, Bound<IndexKey>)> {
+    let key = |value| IndexKey(value);
+    match value {
+        // NaN sorts after every number and -NaN before
+        DataType::Number(_) => Some((
+            Bound::Included(key(DataType::Number(-f32::NAN))),
+            Bound::Included(key(DataType::Number(f32::NAN))),
+        )),
+        // ids sort after the texts
+        DataType::Text(_) => Some((
+            Bound::Included(key(DataType::Text(String::new()))),
+            Bound::Excluded(key(DataType::Id(Uuid::nil()))),
+        )),
+        DataType::Id(_) => Some((
+            Bound::Included(key(DataType::Id(Uuid::nil()))),
+            Bound::Included(key(DataType::Id(Uuid::max()))),
+        )),
+        _ => None,
+    }
+}
+
+// Range of the values matching the comparison, None if the index can not
+// answer it. Like DataType::matches, the order only compares values of the
+// same type
+pub(crate) fn op_range(
+    op: &FindOp,
+    value: &DataType,
+) -> Option<(Bound<IndexKey>, Bound<IndexKey>)> {
+    let key = IndexKey(value.clone());
+    if let FindOp::Eq = op {
+        return Some((Bound::Included(key.clone()), Bound::Included(key)));
+    }
+    if let (FindOp::Between, DataType::Array(ends)) = (op, value) {
+        let [from, to] = ends.as_slice() else {
+            return None;
+        };
+        if from.get_type() != to.get_type() {
+            return None;
+        }
+        type_bounds(from)?;
+        let (from, to) = (IndexKey(from.clone()), IndexKey(to.clone()));
+        return Some((Bound::Included(from), Bound::Included(to)));
+    }
+    let (lowest, highest) = type_bounds(value)?;
+    match op {
+        FindOp::Gt => Some((Bound::Excluded(key), highest)),
+        FindOp::Ge => Some((Bound::Included(key), highest)),
+        FindOp::Lt => Some((lowest, Bound::Excluded(key))),
+        FindOp::Le => Some((lowest, Bound::Included(key))),
+        _ => None,
+    }
+}
+
+impl Index {
+    pub fn new(name: &str, spec: &str) -> Result<Self, &'static str> {
+        let (array, field) = if let Some(field) = spec.strip_prefix("*.") {
+            (None, field)
+        } else if let Some((array, field)) = spec.split_once("[*].") {
+            (Some(array.to_string()), field)
+        } else {
+            return Err("Index must be on *.<field> or <key.path>[*].<field>");
+        };
+        if field.is_empty() || array.as_ref().is_some_and(|a| a.is_empty()) {
+            return Err("Index must be on *.<field> or <key.path>[*].<field>");
+        }
+        Ok(Index {
+            name: name.to_string(),
+            array,
+            field: field.to_string(),
+            unique: false,
+            documents: BTree::new(),
+            items: BTree::new(),
+        })
+    }
+
+    // Definition of the index, as given to new
+    pub fn spec(&self) -> String {
+        match &self.array {
+            Some(array) => format!("{}[*].{}", array, self.field),
+            None => format!("*.{}", self.field),
+        }
+    }
+
+    // Top level key holding the array indexed
+    fn array_key(&self) -> Option<&str> {
+        let array = self.array.as_deref()?;
+        array.split('.').next()
+    }
+
+    pub(crate) fn build(&mut self, data: &Keys) {
+        self.documents = BTree::new();
+        self.items = BTree::new();
+        match self.array_key() {
+            Some(key) => {
+                let key = key.to_string();
+                self.update(&key, Vec::new(), data.get(&key).map(|v| v.as_ref()));
+            }
+            None => {
+                for (key, value) in data.iter() {
+                    self.update(key, Vec::new(), Some(value));
+                }
+            }
+        }
+    }
+
+    // Values indexed for the document of the key, taken before writing it.
+    // A field with `*` may give several
+    pub(crate) fn values_of(&self, document: Option<&DataType>) -> Vec<IndexKey> {
+        let (None, Some(document)) = (&self.array, document) else {
+            return Vec::new();
+        };
+        let values = field_values(document, &self.field).into_iter();
+        let mut values: Vec<IndexKey> = values.map(|v| IndexKey(v.clone())).collect();
+        values.sort();
+        values.dedup();
+        values
+    }
+
+    // Updates the index after a write of the top level key, old are the
+    // values_of the key before it
+    pub(crate) fn update(&mut self, key: &str, old: Vec<IndexKey>, value: Option<&DataType>) {
+        let Some(array) = self.array.as_deref() else {
+            let new = self.values_of(value);
+            for value in old.iter().filter(|v| !new.contains(v)) {
+                self.documents.remove(value, &key.to_string());
+            }
+            for value in new.into_iter().filter(|v| !old.contains(v)) {
+                self.documents.insert(value, key.to_string());
+            }
+            return;
+        };
+        if self.array_key() != Some(key) {
+            return;
+        }
+        // the positions of the items move, the array is indexed again
+        self.items = BTree::new();
+        let mut path = array.split('.').skip(1);
+        let items = value.and_then(|v| path.try_fold(v, |parent, k| parent.get(k)));
+        if let Some(DataType::Array(items)) = items {
+            for (i, item) in items.iter().enumerate() {
+                for value in field_values(item, &self.field) {
+                    self.items.insert(IndexKey(value.clone()), i);
+                }
+            }
+        }
+    }
+
+    // Keys of the documents whose field is in the range, once each
+    pub(crate) fn documents(&self, range: impl RangeBounds<IndexKey>) -> Vec<&String> {
+        let mut seen = HashSet::new();
+        self.documents
+            .range(range)
+            .into_iter()
+            .map(|(_, k)| k)
+            .filter(|k| seen.insert(*k))
+            .collect()
+    }
+
+    // If two documents have the same value of the field
+    pub(crate) fn has_duplicates(&self) -> bool {
+        let values = self.documents.range(..);
+        values.windows(2).any(|w| w[0].0 == w[1].0)
+    }
+
+    // Positions of the items whose field is in the range, in order
+    pub(crate) fn items(&self, range: impl RangeBounds<IndexKey>) -> Vec<usize> {
+        let mut items: Vec<usize> = self
+            .items
+            .range(range)
+            .into_iter()
+            .map(|(_, i)| *i)
+            .collect();
+        items.sort();
+        items.dedup();
+        items
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_op_range() {
+    use crate::doc;
+    let values = [
+        DataType::from(3),
+        DataType::from(-1),
+        DataType::from(10),
+        DataType::from(3),
+        DataType::from("bob"),
+        DataType::from("ana"),
+        DataType::Boolean(true),
+        DataType::Id(Uuid::nil()),
+    ];
+    let mut index = Index::new("by_v", "*.v").unwrap();
+    for (i, value) in values.iter().enumerate() {
+        index.update(
+            &i.to_string(),
+            Vec::new(),
+            Some(&doc!("v" => value.clone())),
+        );
+    }
+    let between = |from: DataType, to: DataType| DataType::Array(vec![from, to]);
+    let ops = [
+        (FindOp::Eq, DataType::from(3)),
+        (FindOp::Eq, DataType::from("ana")),
+        (FindOp::Eq, DataType::Boolean(true)),
+        (FindOp::Lt, DataType::from(3)),
+        (FindOp::Le, DataType::from(3)),
+        (FindOp::Gt, DataType::from(3)),
+        (FindOp::Ge, DataType::from(-1)),
+        (FindOp::Gt, DataType::from("ana")),
+        (FindOp::Lt, DataType::from("bob")),
+        (
+            FindOp::Between,
+            between(DataType::from(0), DataType::from(10)),
+        ),
+        (
+            FindOp::Between,
+            between(DataType::from("a"), DataType::from("b")),
+        ),
+    ];
+    // the index finds the same documents as a scan
+    for (op, value) in ops {
+        let range = op_range(&op, &value).unwrap();
+        let mut found: Vec<&String> = index.documents(range);
+        found.sort();
+        let scan: Vec<String> = (0..values.len())
+            .filter(|i| doc!("v" => values[*i].clone()).matches("v", &op, &value))
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(found, scan.iter().collect::<Vec<_>>(), "{:?}", value);
+    }
+    // comparisons the index can not answer
+    assert!(op_range(&FindOp::Lt, &DataType::Boolean(true)).is_none());
+    assert!(op_range(&FindOp::NotEq, &DataType::from(3)).is_none());
+    let mixed = between(DataType::from(1), DataType::from("b"));
+    assert!(op_range(&FindOp::Between, &mixed).is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn test_index_update() {
+    use crate::doc;
+    let find = |index: &Index, value: DataType| {
+        let key = IndexKey(value);
+        let keys = index.documents(key.clone()..=key);
+        keys.into_iter().cloned().collect::<Vec<_>>()
+    };
+    let mut index = Index::new("by_age", "*.age").unwrap();
+    let ana = doc!("age" => 30);
+    index.update("ana", Vec::new(), Some(&ana));
+    index.update("eva", Vec::new(), Some(&doc!("age" => 30)));
+    assert_eq!(find(&index, DataType::from(30)), vec!["ana", "eva"]);
+    // the old value is removed when the field changes or the key goes away
+    let old = index.values_of(Some(&ana));
+    index.update("ana", old, Some(&doc!("age" => 31)));
+    assert_eq!(find(&index, DataType::from(30)), vec!["eva"]);
+    assert_eq!(find(&index, DataType::from(31)), vec!["ana"]);
+    let old = index.values_of(Some(&doc!("age" => 30)));
+    index.update("eva", old, None);
+    assert!(find(&index, DataType::from(30)).is_empty());
+    // documents without the field are not indexed
+    index.update("joao", Vec::new(), Some(&doc!("city" => "Lisboa")));
+    assert_eq!(index.documents(..).len(), 1);
+
+    // every value given by * is indexed, and keys with dots are found
+    let mut index = Index::new("by_tag", "*.meta.tag.list.*").unwrap();
+    let tags = |tags: &[&str]| {
+        let tags = DataType::Array(tags.iter().map(|t| DataType::from(*t)).collect());
+        let mut meta = doc!("v" => 1);
+        meta.set("tag.list", tags).unwrap();
+        doc!("meta" => meta)
+    };
+    let ana = tags(&["a", "b", "a"]);
+    index.update("ana", Vec::new(), Some(&ana));
+    assert_eq!(find(&index, DataType::from("a")), vec!["ana"]);
+    assert_eq!(index.documents(..), vec!["ana"]);
+    let old = index.values_of(Some(&ana));
+    index.update("ana", old, Some(&tags(&["b"])));
+    assert!(find(&index, DataType::from("a")).is_empty());
+    assert_eq!(find(&index, DataType::from("b")), vec!["ana"]);
+
+    // items are indexed by position, only for the key of the array
+    let mut index = Index::new("by_done", "todo.tasks[*].done").unwrap();
+    let tasks = |done: Vec<bool>| {
+        let tasks = done.into_iter().map(|d| doc!("done" => d)).collect();
+        doc!("tasks" => DataType::Array(tasks))
+    };
+    index.update("todo", Vec::new(), Some(&tasks(vec![true, false, true])));
+    index.update("other", Vec::new(), Some(&tasks(vec![false])));
+    let done = op_range(&FindOp::Eq, &DataType::Boolean(true)).unwrap();
+    assert_eq!(index.items(done.clone()), vec![0, 2]);
+    index.update("todo", Vec::new(), Some(&tasks(vec![false, true])));
+    assert_eq!(index.items(done.clone()), vec![1]);
+    index.update("todo", Vec::new(), None);
+    assert!(index.items(done).is_empty());
+}
@@ -2,20 +2,75 @@
 // InfuseDB is a in-memory database,
 // it will store the data in memory and provide a simple API to interact with it
 
+mod cdc;
+mod change;
 mod collection;
 mod data_type;
+mod eviction;
+mod finder;
+mod fulltext;
+mod history;
+mod index;
+mod rotating_file;
+mod snapshot;
+mod transaction;
 pub mod utils;
+mod vector;
+pub use cdc::CdcSink;
+pub use change::{ChangeEvent, ChangeHook, ChangeOp};
 pub use collection::Collection;
 pub use data_type::DataType;
-pub use data_type::FindOp; //TODO: change to own trait and file
+pub use data_type::{Filter, FindOp}; //TODO: change to own trait and file
+pub use eviction::{EvictionPolicy, MemoryLimit};
+pub use finder::{BTree, IndexKey};
+pub use fulltext::TextIndex;
+pub use history::Retention;
+pub use rotating_file::RotatingFile;
+pub use snapshot::{CollectionSnapshot, Snapshot};
+use std::cell::Cell;
 use std::fs;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+pub use transaction::Transaction;
+pub use vector::{Metric, to_vector};
 
 pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
+// Statistics of the dumps made in this session
+#[derive(Default, Clone, Copy)]
+pub struct DumpStats {
+    pub count: u64,
+    pub total_duration: Duration,
+    pub last_duration: Duration,
+    pub last_size: usize,
+    pub last_at: Option<SystemTime>,
+}
+
 pub struct InfuseDB {
     pub path: String,
     collections: Vec<Collection>,
+    // updated by dump, which only reads the collections
+    dump_stats: Cell<DumpStats>,
+    hooks: Vec<ChangeHook>,
+    // budget of all the collections together, each one may have its own too
+    memory_limit: Option<MemoryLimit>,
+    evicted: u64,
+    eviction: bool,
+}
+
+// Saves the snapshot in the file, returns the bytes written
+fn write_snapshot(path: &str, snapshot: &Snapshot) -> Result<usize, &'static str> {
+    if !Path::new(path).exists()
+        && let Err(e) = fs::File::create(path)
+    {
+        println!("{} {:?}", path, e);
+        return Err("Error creating file");
+    }
+    snapshot.write(path).map_err(|e| {
+        println!("{:?}", e);
+        "Error saving file"
+    })
 }
 
 impl InfuseDB {
@@ -23,6 +78,11 @@ impl InfuseDB {
         InfuseDB {
             path: "./default.mdb".to_string(),
             collections: Vec::new(),
+            dump_stats: Cell::default(),
+            hooks: Vec::new(),
+            memory_limit: None,
+            evicted: 0,
+            eviction: true,
         }
     }
 
@@ -59,43 +119,97 @@ impl InfuseDB {
         Ok(InfuseDB {
             collections,
             path: path.to_string(),
+            dump_stats: Cell::default(),
+            hooks: Vec::new(),
+            memory_limit: None,
+            evicted: 0,
+            eviction: true,
         })
     }
 
-    pub fn dump(&self) -> Result<(), &str> {
-        let mut result = String::new();
-        for collection in self.collections.iter() {
-            let page = collection.dump();
-            result.push_str(page.as_str());
-            result.push_str("\n");
-        }
-        let path = Path::new(&self.path);
-        if !path.exists() {
-            if let Err(e) = fs::File::create(&self.path) {
-                println!("{} {:?}", self.path, e);
-                return Err("Error creating file");
-            }
+    // Immutable copy of every collection as they are now, cheap to take.
+    // It can be read or saved with Snapshot::write while the database changes
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            collections: self.collections.iter().map(|c| c.snapshot()).collect(),
         }
+    }
 
-        let r = fs::write(path, result);
-        if r.is_err() {
-            println!("{:?}", r.err().unwrap());
-            return Err("Error saving file");
-        }
+    pub fn dump(&self) -> Result<(), &str> {
+        let start = Instant::now();
+        let size = write_snapshot(&self.path, &self.snapshot())?;
+        self.dumped(size, start.elapsed());
+        Ok(())
+    }
+
+    // Like dump for a database shared between threads, the lock is only held
+    // to take the snapshot, not while it is written
+    pub fn dump_shared(db: &Mutex<InfuseDB>) -> Result<(), &'static str> {
+        let start = Instant::now();
+        let (snapshot, path) = {
+            let db = db.lock().unwrap();
+            (db.snapshot(), db.path.clone())
+        };
+        let size = write_snapshot(&path, &snapshot)?;
+        db.lock().unwrap().dumped(size, start.elapsed());
         Ok(())
     }
 
+    fn dumped(&self, size: usize, duration: Duration) {
+        let mut stats = self.dump_stats.get();
+        stats.count += 1;
+        stats.total_duration += duration;
+        stats.last_duration = duration;
+        stats.last_size = size;
+        stats.last_at = Some(SystemTime::now());
+        self.dump_stats.set(stats);
+    }
+
+    // time of the last successful dump in this session
+    pub fn last_save(&self) -> Option<SystemTime> {
+        self.dump_stats.get().last_at
+    }
+
+    pub fn dump_stats(&self) -> DumpStats {
+        self.dump_stats.get()
+    }
+
+    // approximate memory used by all the collections, in bytes
+    pub fn approx_size(&self) -> usize {
+        self.collections.iter().map(|c| c.approx_size()).sum()
+    }
+
     pub fn create_collection(&mut self, name: &str) -> Result<&mut Collection, &str> {
         //check if collection exists
         if self.collections.iter().any(|x| x.name == name) {
             Err("Collection already exists")
         } else {
-            let collection = Collection::new(name);
+            let mut collection = Collection::new(name);
+            for hook in self.hooks.iter() {
+                collection.on_change(hook.clone());
+            }
+            if self.memory_limit.is_some() {
+                collection.track_usage();
+            }
+            collection.set_eviction(self.eviction);
             self.collections.push(collection);
             return Ok(self.collections.last_mut().unwrap());
         }
     }
 
+    // Adds a collection built elsewhere, replacing the one with the same name
+    pub fn insert_collection(&mut self, mut collection: Collection) {
+        for hook in self.hooks.iter() {
+            collection.on_change(hook.clone());
+        }
+        if self.memory_limit.is_some() {
+            collection.track_usage();
+        }
+        collection.set_eviction(self.eviction);
+        self.collections.retain(|c| c.name != collection.name);
+        self.collections.push(collection);
+    }
+
     pub fn get_collection(&mut self, name: &str) -> Option<&mut Collection> {
         //return a mutable reference to collection
         let index = self
@@ -124,7 +238,105 @@ impl InfuseDB {
             .iter()
             .position(|x| x.name == name)
             .unwrap();
-        self.collections.remove(index);
+        let mut collection = self.collections.remove(index);
+        collection.notify("", ChangeOp::Drop, None, None);
+    }
+
+    // Limit of the approximate size of all the collections, applied by make_room
+    pub fn set_memory_limit(&mut self, limit: Option<MemoryLimit>) {
+        self.memory_limit = limit;
+        if limit.is_some() {
+            for collection in self.collections.iter_mut() {
+                collection.track_usage();
+            }
+        }
+    }
+
+    pub fn memory_limit(&self) -> Option<MemoryLimit> {
+        self.memory_limit
+    }
+
+    // Enables the eviction of keys by the memory limits of the database and
+    // of every collection, when off make_room and the writes over a limit
+    // fail instead
+    pub fn set_eviction(&mut self, enabled: bool) {
+        self.eviction = enabled;
+        for collection in self.collections.iter_mut() {
+            collection.set_eviction(enabled);
+        }
+    }
+
+    // Evicts keys of any collection until the database fits its memory
+    // limit with the incoming bytes of a write, to call before it. Fails
+    // with noeviction or when there is nothing left to evict
+    pub fn make_room(&mut self, incoming: usize) -> Result<usize, &'static str> {
+        let Some(limit) = self.memory_limit else {
+            return Ok(0);
+        };
+        let mut size = self.approx_size() + incoming;
+        let mut evicted = 0;
+        while size > limit.max_bytes {
+            let victim = self
+                .collections
+                .iter()
+                .enumerate()
+                .filter_map(|(i, c)| c.victim(limit.policy, "").map(|(k, rank)| (i, k, rank)))
+                .min_by_key(|(_, _, rank)| *rank);
+            let (i, key, _) = victim.ok_or(eviction::OUT_OF_MEMORY)?;
+            let collection = &mut self.collections[i];
+            let before = collection.approx_size();
+            collection.evict(&key);
+            size -= before - collection.approx_size();
+            self.evicted += 1;
+            evicted += 1;
+        }
+        Ok(evicted)
+    }
+
+    // Keys evicted by the memory limits of the database and its collections
+    pub fn evicted(&self) -> u64 {
+        self.evicted + self.collections.iter().map(|c| c.evicted()).sum::<u64>()
+    }
+
+    // Deletes the expired keys of every collection, returns how many
+    pub fn reap_expired(&mut self) -> usize {
+        self.collections.iter_mut().map(|c| c.reap()).sum()
+    }
+
+    // Stages changes to apply together with Transaction::commit
+    pub fn begin(&mut self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    // Runs the closure in a transaction, committed if it returns Ok and
+    // rolled back otherwise
+    pub fn transaction<'a, T, F>(&'a mut self, f: F) -> Result<T, &'static str>
+    where
+        F: FnOnce(&mut Transaction<'a>) -> Result<T, &'static str>,
+    {
+        let mut tx = self.begin();
+        let value = f(&mut tx)?;
+        tx.commit()?;
+        Ok(value)
+    }
+
+    // Calls the hook with the changes of every collection, present or future
+    pub fn on_change(&mut self, hook: ChangeHook) {
+        for collection in self.collections.iter_mut() {
+            collection.on_change(hook.clone());
+        }
+        self.hooks.push(hook);
+    }
+
+    // Streams every mutation to a rotating NDJSON file, see CdcSink
+    pub fn enable_cdc(&mut self, path: &str, max_size: u64, keep: usize) -> std::io::Result<()> {
+        let sink = Mutex::new(CdcSink::open(path, max_size, keep)?);
+        self.on_change(Arc::new(move |event| {
+            if let Err(err) = sink.lock().unwrap().record(event) {
+                eprintln!("Error writing change feed: {}", err);
+            }
+        }));
+        Ok(())
     }
 }
 
@@ -0,0 +1,89 @@
+// Append only file that rotates when it grows over a size limit
+// Rotated files are renamed to <path>.1, <path>.2 ... up to the
+// number of files to keep, the oldest one is removed
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+
+pub struct RotatingFile {
+    path: String,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &str, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_string(),
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Writes the line followed by '\n', rotating first if it would not fit
+    pub fn append_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(format!("{}.{}", self.path, self.keep));
+            for i in (1..self.keep).rev() {
+                let _ = fs::rename(
+                    format!("{}.{}", self.path, i),
+                    format!("{}.{}", self.path, i + 1),
+                );
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_rotation() {
+    let dir = std::env::temp_dir().join(format!("infusedb_rotate_{}", std::process::id()));
+    let _ = fs::create_dir_all(&dir);
+    let path = dir.join("log.ndjson");
+    let path = path.to_str().unwrap();
+    let mut file = RotatingFile::open(path, 10, 2).unwrap();
+    file.append_line("first").unwrap();
+    file.append_line("second").unwrap();
+    file.append_line("third").unwrap();
+    file.append_line("fourth").unwrap();
+    assert_eq!(fs::read_to_string(path).unwrap(), "fourth\n");
+    assert_eq!(
+        fs::read_to_string(format!("{}.1", path)).unwrap(),
+        "third\n"
+    );
+    assert_eq!(
+        fs::read_to_string(format!("{}.2", path)).unwrap(),
+        "second\n"
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,219 @@
+// Snapshots of the collections and of the whole database
+// A snapshot shares the data of the collection when taken, the collection
+// copies what it writes afterwards, so reading or saving a snapshot does
+// not need to keep the database locked
+use super::collection::{Collection, Expiries, Keys};
+use super::data_type::DataType;
+use super::eviction::MemoryLimit;
+use super::history::Retention;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone)]
+pub struct CollectionSnapshot {
+    pub name: String,
+    pub(crate) data: Arc<Keys>,
+    pub(crate) expires: Arc<Expiries>,
+    pub(crate) retention: Option<Retention>,
+    pub(crate) memory_limit: Option<MemoryLimit>,
+    // definitions of the indexes, as dumped
+    pub(crate) indexes: Vec<String>,
+    // highest version given to a key
+    pub(crate) revision: u64,
+    // approximate size of the keys and values
+    pub(crate) used: usize,
+}
+
+impl CollectionSnapshot {
+    fn is_expired(&self, key: &str, now: SystemTime) -> bool {
+        self.expires.get(key).is_some_and(|at| *at <= now)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&DataType> {
+        if self.is_expired(key, SystemTime::now()) {
+            return None;
+        }
+        self.data.get(key).map(|v| v.as_ref())
+    }
+
+    pub fn count(&self) -> usize {
+        let now = SystemTime::now();
+        let expired = self.expires.keys().filter(|k| self.is_expired(k, now));
+        self.data.len() - expired.count()
+    }
+
+    // Keys and values, without the expired ones
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &DataType)> {
+        let now = SystemTime::now();
+        self.data
+            .iter()
+            .filter(move |(k, _)| !self.is_expired(k, now))
+            .map(|(k, v)| (k, v.as_ref()))
+    }
+
+    pub fn list(&self) -> HashMap<String, DataType> {
+        self.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    // Collection sharing the keys of the snapshot, without the indexes, to
+    // run the read commands on it
+    pub fn to_collection(&self) -> Collection {
+        Collection::from_keys(
+            &self.name,
+            self.data.clone(),
+            self.expires.clone(),
+            self.used,
+        )
+    }
+
+    // Page of the collection in the .mdb format
+    pub fn dump(&self) -> String {
+        let mut result = String::new();
+        result.push_str(format!("[{}]\n", self.name).as_str());
+        result.push_str(&format!("revision {}\n", self.revision));
+        if let Some(retention) = self.retention {
+            let limit = |v: Option<String>| v.unwrap_or("-".to_string());
+            result.push_str(&format!(
+                "retention {} {}\n",
+                limit(retention.versions.map(|v| v.to_string())),
+                limit(retention.max_age.map(|age| age.as_secs().to_string()))
+            ));
+        }
+        if let Some(memory) = self.memory_limit {
+            let line = format!(
+                "maxmemory {} {}\n",
+                memory.max_bytes,
+                memory.policy.as_str()
+            );
+            result.push_str(&line);
+        }
+        for index in self.indexes.iter() {
+            result.push_str(index);
+            result.push('\n');
+        }
+        for (k, v) in self.iter() {
+            let line = format!("{} {} {}\n", v.type_id(), k, v.to_string());
+            result.push_str(line.as_str());
+        }
+        let now = SystemTime::now();
+        for (k, at) in self
+            .expires
+            .iter()
+            .filter(|(k, _)| !self.is_expired(k, now))
+        {
+            let ms = at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            result.push_str(&format!("ttl {} {}\n", k, ms));
+        }
+        result
+    }
+}
+
+#[derive(Clone)]
+pub struct Snapshot {
+    pub collections: Vec<CollectionSnapshot>,
+}
+
+impl Snapshot {
+    pub fn get_collection(&self, name: &str) -> Option<&CollectionSnapshot> {
+        self.collections.iter().find(|c| c.name == name)
+    }
+
+    pub fn dump(&self) -> String {
+        let mut result = String::new();
+        for collection in self.collections.iter() {
+            result.push_str(collection.dump().as_str());
+            result.push('\n');
+        }
+        result
+    }
+
+    // Saves the snapshot as a .mdb file, returns the bytes written
+    pub fn write(&self, path: &str) -> std::io::Result<usize> {
+        let contents = self.dump();
+        fs::write(path, &contents)?;
+        Ok(contents.len())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_snapshot_isolation() {
+    let mut collection = Collection::new("users");
+    collection.add("john", DataType::from(1)).unwrap();
+    collection.set_path("jane.age", DataType::from(30)).unwrap();
+    let snapshot = collection.snapshot();
+    // the size is carried over, not counted again
+    let size = collection.approx_size();
+    assert_eq!(snapshot.to_collection().approx_size(), size);
+
+    collection.add("john", DataType::from(2)).unwrap();
+    collection.set_path("jane.age", DataType::from(31)).unwrap();
+    collection.rm("john");
+    collection.add("bob", DataType::from(3)).unwrap();
+
+    assert_eq!(snapshot.count(), 2);
+    assert_eq!(snapshot.get("john"), Some(&DataType::from(1)));
+    assert_eq!(
+        snapshot.get("jane").and_then(|j| j.get("age")),
+        Some(&DataType::from(30))
+    );
+    assert!(snapshot.get("bob").is_none());
+    assert_eq!(collection.count(), 2);
+}
+
+#[cfg(test)]
+#[test]
+fn test_snapshot_read_with_writes() {
+    use super::InfuseDB;
+    use std::sync::{Mutex, mpsc};
+    use std::thread;
+
+    let db = Arc::new(Mutex::new(InfuseDB::new()));
+    {
+        let mut db = db.lock().unwrap();
+        let collection = db.create_collection("users").unwrap();
+        for i in 0..100 {
+            collection.add(&i.to_string(), DataType::from(i)).unwrap();
+        }
+    }
+    // the lock is only held to take the snapshot
+    let snapshot = db
+        .lock()
+        .unwrap()
+        .get_collection("users")
+        .unwrap()
+        .snapshot();
+    let (reading, started) = mpsc::channel();
+    let (written, done) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        let users = snapshot.to_collection();
+        let mut count = 0;
+        for _ in users.list() {
+            if count == 0 {
+                reading.send(()).unwrap();
+                done.recv().unwrap();
+            }
+            count += 1;
+        }
+        count
+    });
+
+    // the write goes through while the reader is in the middle of the list
+    started.recv().unwrap();
+    {
+        let mut db = db.lock().unwrap();
+        let users = db.get_collection("users").unwrap();
+        users.add("new", DataType::from(1)).unwrap();
+    }
+    written.send(()).unwrap();
+    assert_eq!(reader.join().unwrap(), 100);
+    assert_eq!(
+        db.lock().unwrap().get_collection("users").unwrap().count(),
+        101
+    );
+}
@@ -0,0 +1,251 @@
+// Transactions over one or more collections
+// Changes are staged on a copy of the keys they touch and only applied to
+// the collections on commit, so a rolled back transaction leaves the
+// database and its listeners untouched. If a change fails while committing,
+// the collections are put back as they were. The listeners are told of the
+// changes once all of them are applied.
+use super::InfuseDB;
+use super::collection::{Checkpoint, Collection};
+use super::data_type::DataType;
+use std::collections::{HashMap, HashSet};
+
+// function run on a collection when committing
+type Run<'a> = Box<dyn FnOnce(&mut Collection) -> Result<(), &'static str> + 'a>;
+
+enum Op<'a> {
+    Add(String, DataType),
+    SetPath(String, DataType),
+    Rm(String),
+    Run(Run<'a>),
+}
+
+// Copy of the keys of a collection touched by the transaction
+struct Staged {
+    scratch: Collection,
+    touched: HashSet<String>,
+}
+
+pub struct Transaction<'a> {
+    db: &'a mut InfuseDB,
+    staged: HashMap<String, Staged>,
+    ops: Vec<(String, Op<'a>)>,
+}
+
+fn top_key(key_path: &str) -> &str {
+    key_path.split('.').next().unwrap_or_default()
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(db: &'a mut InfuseDB) -> Self {
+        Transaction {
+            db,
+            staged: HashMap::new(),
+            ops: Vec::new(),
+        }
+    }
+
+    // Copy of the collection with the current value of the key
+    fn stage(&mut self, collection: &str, key: &str) -> Result<&mut Collection, &'static str> {
+        let source = self
+            .db
+            .get_collection(collection)
+            .ok_or("Collection does not exist")?;
+        let staged = self
+            .staged
+            .entry(collection.to_string())
+            .or_insert_with(|| Staged {
+                scratch: Collection::new(collection),
+                touched: HashSet::new(),
+            });
+        if staged.touched.insert(key.to_string())
+            && let Some(value) = source.get(key)
+        {
+            let _ = staged.scratch.add(key, value.clone());
+        }
+        Ok(&mut staged.scratch)
+    }
+
+    pub fn add(
+        &mut self,
+        collection: &str,
+        key: &str,
+        value: DataType,
+    ) -> Result<(), &'static str> {
+        self.stage(collection, key)?.add(key, value.clone())?;
+        self.ops
+            .push((collection.to_string(), Op::Add(key.to_string(), value)));
+        Ok(())
+    }
+
+    pub fn set_path(
+        &mut self,
+        collection: &str,
+        key_path: &str,
+        value: DataType,
+    ) -> Result<DataType, &'static str> {
+        let scratch = self.stage(collection, top_key(key_path))?;
+        let result = scratch.set_path(key_path, value.clone())?;
+        let op = Op::SetPath(key_path.to_string(), value);
+        self.ops.push((collection.to_string(), op));
+        Ok(result)
+    }
+
+    pub fn rm(&mut self, collection: &str, key: &str) -> Result<(), &'static str> {
+        self.stage(collection, key)?.rm(key);
+        self.ops
+            .push((collection.to_string(), Op::Rm(key.to_string())));
+        Ok(())
+    }
+
+    // Runs the function on the collection when committing, after the
+    // changes made before. What it changes is not seen by get
+    pub fn run<F>(&mut self, collection: &str, f: F) -> Result<(), &'static str>
+    where
+        F: FnOnce(&mut Collection) -> Result<(), &'static str> + 'a,
+    {
+        if self.db.get_collection(collection).is_none() {
+            return Err("Collection does not exist");
+        }
+        self.staged
+            .entry(collection.to_string())
+            .or_insert_with(|| Staged {
+                scratch: Collection::new(collection),
+                touched: HashSet::new(),
+            });
+        self.ops
+            .push((collection.to_string(), Op::Run(Box::new(f))));
+        Ok(())
+    }
+
+    // Value of the key as seen inside the transaction
+    pub fn get(&mut self, collection: &str, key: &str) -> Option<DataType> {
+        if let Some(staged) = self.staged.get_mut(collection)
+            && staged.touched.contains(key)
+        {
+            return staged.scratch.get(key).cloned();
+        }
+        self.db.get_collection(collection)?.get(key).cloned()
+    }
+
+    // Applies every change, or none of them
+    pub fn commit(self) -> Result<(), &'static str> {
+        let mut checkpoints: Vec<(String, Checkpoint)> = Vec::new();
+        for name in self.staged.keys() {
+            let collection = self.db.get_collection(name).unwrap();
+            checkpoints.push((name.clone(), collection.checkpoint()));
+            collection.hold_events();
+        }
+        for (name, op) in self.ops {
+            let collection = self.db.get_collection(&name).unwrap();
+            let result = match op {
+                Op::Add(key, value) => collection.add(&key, value).map(|_| ()),
+                Op::SetPath(key_path, value) => collection.set_path(&key_path, value).map(|_| ()),
+                Op::Rm(key) => {
+                    collection.rm(&key);
+                    Ok(())
+                }
+                Op::Run(f) => f(collection),
+            };
+            if let Err(err) = result {
+                for (name, checkpoint) in checkpoints {
+                    let collection = self.db.get_collection(&name).unwrap();
+                    collection.restore_checkpoint(checkpoint);
+                }
+                return Err(err);
+            }
+        }
+        for (name, _) in checkpoints {
+            self.db.get_collection(&name).unwrap().release_events();
+        }
+        Ok(())
+    }
+
+    // Discards the staged changes, the same as dropping the transaction
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+#[test]
+fn test_transaction_commit() {
+    let mut db = InfuseDB::new();
+    let _ = db.create_collection("users");
+    let _ = db.create_collection("posts");
+    db.get_collection("users")
+        .unwrap()
+        .add("john", DataType::from(1))
+        .unwrap();
+
+    let result = db.transaction(|tx| {
+        tx.add("users", "jane", DataType::from(2))?;
+        tx.set_path("posts", "first.author", DataType::from("jane"))?;
+        tx.rm("users", "john")?;
+        assert_eq!(tx.get("users", "john"), None);
+        assert_eq!(tx.get("users", "jane"), Some(DataType::from(2)));
+        Ok(())
+    });
+    assert!(result.is_ok());
+    let users = db.get_collection("users").unwrap();
+    assert!(users.get("john").is_none());
+    assert_eq!(users.get("jane"), Some(&DataType::from(2)));
+    assert!(db.get_collection("posts").unwrap().get("first").is_some());
+}
+
+#[cfg(test)]
+#[test]
+fn test_transaction_rollback() {
+    let mut db = InfuseDB::new();
+    let _ = db.create_collection("users");
+    db.get_collection("users")
+        .unwrap()
+        .add("john", DataType::from(1))
+        .unwrap();
+
+    // the second change fails, the first one is not applied
+    let result = db.transaction(|tx| {
+        tx.add("users", "jane", DataType::from(2))?;
+        tx.set_path("users", "john.age", DataType::from(30))
+    });
+    assert!(result.is_err());
+    assert!(db.get_collection("users").unwrap().get("jane").is_none());
+
+    let mut tx = db.begin();
+    tx.rm("users", "john").unwrap();
+    tx.rollback();
+    assert!(db.get_collection("users").unwrap().get("john").is_some());
+}
+
+#[cfg(test)]
+#[test]
+fn test_transaction_commit_failure() {
+    use super::doc;
+    let mut db = InfuseDB::new();
+    let users = db.create_collection("users").unwrap();
+    users.add("john", doc!("email" => "john@a.com")).unwrap();
+    users.create_unique_index("by_email", "*.email").unwrap();
+    users.set_retention(Some(Default::default()));
+    let version = users.version("john");
+    let (sender, events) = std::sync::mpsc::channel();
+    users.on_change(std::sync::Arc::new(move |event: &super::ChangeEvent| {
+        let _ = sender.send(event.key.clone());
+    }));
+
+    // the copies do not have the unique index, the second add fails on commit
+    let result = db.transaction(|tx| {
+        tx.rm("users", "john")?;
+        tx.add("users", "jane", doc!("email" => "jane@a.com"))?;
+        tx.add("users", "ana", doc!("email" => "jane@a.com"))
+    });
+    assert!(result.is_err());
+    let users = db.get_collection("users").unwrap();
+    assert!(users.get("jane").is_none());
+    assert_eq!(users.version("john"), version);
+    assert_eq!(users.history("john").len(), 1);
+    assert!(users.history("jane").is_empty());
+    let email = DataType::from("john@a.com");
+    assert_eq!(
+        users.find_indexed("by_email", &email).map(|k| k.len()),
+        Some(1)
+    );
+    // the changes rolled back are not events
+    assert_eq!(events.try_iter().count(), 0);
+}
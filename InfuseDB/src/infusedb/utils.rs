@@ -1,3 +1,5 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 pub fn smart_split(text: String) -> Vec<String> {
     let words = text.split_whitespace();
     let mut result = Vec::new();
@@ -21,9 +23,9 @@ pub fn smart_split(text: String) -> Vec<String> {
                 let count3 =
                     last.matches("[").count() as isize - last.matches("]").count() as isize;
                 let count4 =
-                    word.matches("{").count() as isize - word.matches("}").count() as isize;
+                    last.matches("{").count() as isize - last.matches("}").count() as isize;
 
-                if count % 2 != 0 && count2 % 2 != 0 && count3 == 0 && count4 == 0 {
+                if count % 2 == 0 && count2 % 2 == 0 && count3 == 0 && count4 == 0 {
                     word_finished = true;
                 }
             }
@@ -32,6 +34,126 @@ pub fn smart_split(text: String) -> Vec<String> {
     return result;
 }
 
+// Quotes and escapes a text to be used as a JSON string
+pub fn json_string(text: &str) -> String {
+    let mut result = String::with_capacity(text.len() + 2);
+    result.push('"');
+    for chr in text.chars() {
+        match chr {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+// Matches a text against a glob pattern where `*` is any sequence
+// of characters and `?` any single character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` and the text matched when it was found
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// Days since 1970-01-01 of a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Time as UTC RFC 3339 with milliseconds, `2024-03-08T10:30:00.000Z`
+pub fn format_timestamp(time: SystemTime) -> String {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    let secs = millis.div_euclid(1000);
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let rem = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        millis.rem_euclid(1000)
+    )
+}
+
+// Parses a UTC RFC 3339 time (`2024-03-08T10:30:00Z`, fraction optional),
+// milliseconds since the epoch, or seconds ago when negative (`-3600`)
+pub fn parse_timestamp(text: &str) -> Option<SystemTime> {
+    if let Ok(number) = text.parse::<i64>() {
+        return if number < 0 {
+            SystemTime::now().checked_sub(Duration::from_secs(number.unsigned_abs()))
+        } else {
+            UNIX_EPOCH.checked_add(Duration::from_millis(number as u64))
+        };
+    }
+    let text = text.strip_suffix('Z')?;
+    let (date, time) = text.split_once('T')?;
+    let date: Vec<i64> = date
+        .split('-')
+        .map(|v| v.parse().ok())
+        .collect::<Option<_>>()?;
+    let (time, fraction) = time.split_once('.').unwrap_or((time, "0"));
+    let time: Vec<i64> = time
+        .split(':')
+        .map(|v| v.parse().ok())
+        .collect::<Option<_>>()?;
+    if date.len() != 3 || time.len() != 3 || fraction.is_empty() {
+        return None;
+    }
+    let millis = format!("{:0<3}", fraction).get(..3)?.parse::<u64>().ok()?;
+    let days = days_from_civil(date[0], date[1], date[2]);
+    let secs = days * 86400 + time[0] * 3600 + time[1] * 60 + time[2];
+    let secs = u64::try_from(secs).ok()?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs) + Duration::from_millis(millis))
+}
+
 #[cfg(test)]
 #[test]
 fn test_smart_split() {
@@ -45,4 +167,42 @@ fn test_smart_split() {
     let v = smart_split(r#"text 'word1 "word2"'"#.to_string());
     assert_eq!(v.len(), 2);
     assert_eq!(v.last().unwrap(), r#"'word1 "word2"'"#);
+    let v = smart_split(r#"knn * [1, 2] k 3 where name is "a b""#.to_string());
+    assert_eq!(v[2], "[1, 2]");
+    assert_eq!(v.len(), 9);
+    let v = smart_split(r#"set k {name: "a b", tags: [1, 2]} ttl 5"#.to_string());
+    assert_eq!(v[2], r#"{name: "a b", tags: [1, 2]}"#);
+    assert_eq!(v.len(), 5);
+}
+
+#[test]
+fn test_json_string() {
+    assert_eq!(json_string("text"), r#""text""#);
+    assert_eq!(json_string(r#"say "hi""#), r#""say \"hi\"""#);
+    assert_eq!(json_string("a\nb"), r#""a\nb""#);
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("news.*", "news.sports"));
+    assert!(glob_match("*", ""));
+    assert!(glob_match("n?ws", "news"));
+    assert!(glob_match("a*b*c", "axxbyyc"));
+    assert!(!glob_match("news.*", "weather"));
+    assert!(!glob_match("a*b", "axxbc"));
+}
+
+#[test]
+fn test_timestamps() {
+    let time = parse_timestamp("2024-03-08T10:30:05.25Z").unwrap();
+    let millis = time.duration_since(UNIX_EPOCH).unwrap().as_millis();
+    assert_eq!(millis, 1709893805250);
+    assert_eq!(format_timestamp(time), "2024-03-08T10:30:05.250Z");
+    assert_eq!(parse_timestamp("1709893805250"), Some(time));
+    assert_eq!(
+        format_timestamp(parse_timestamp("2000-02-29T23:59:59Z").unwrap()),
+        "2000-02-29T23:59:59.000Z"
+    );
+    assert!(parse_timestamp("-60").unwrap() < SystemTime::now());
+    assert!(parse_timestamp("yesterday").is_none());
 }
@@ -0,0 +1,383 @@
+// Nearest neighbor search over vectors stored as arrays of numbers
+// knn compares every vector of the collection, a vector index keeps them in
+// a HNSW graph (hierarchical navigable small world) to visit only a few
+use super::collection::Keys;
+use super::data_type::DataType;
+use super::index::field_value;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Metric {
+    // 1 - cosine similarity
+    Cosine,
+    // euclidean distance
+    L2,
+    // negated dot product, so the closest is still the lowest
+    Dot,
+}
+
+impl Metric {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Metric::Cosine => "cosine",
+            Metric::L2 => "l2",
+            Metric::Dot => "dot",
+        }
+    }
+
+    pub fn parse(metric: &str) -> Option<Self> {
+        match metric {
+            "cosine" => Some(Metric::Cosine),
+            "l2" => Some(Metric::L2),
+            "dot" => Some(Metric::Dot),
+            _ => None,
+        }
+    }
+
+    // None if the vectors do not have the same length
+    pub fn distance(&self, a: &[f32], b: &[f32]) -> Option<f32> {
+        if a.len() != b.len() {
+            return None;
+        }
+        let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+        let distance = match self {
+            Metric::Cosine => {
+                let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+                let norms = norm(a) * norm(b);
+                if norms == 0.0 { 1.0 } else { 1.0 - dot / norms }
+            }
+            Metric::L2 => a
+                .iter()
+                .zip(b)
+                .map(|(x, y)| (x - y) * (x - y))
+                .sum::<f32>()
+                .sqrt(),
+            Metric::Dot => -dot,
+        };
+        Some(distance)
+    }
+}
+
+// The numbers of an array, None if it is not an array of numbers
+pub fn to_vector(value: &DataType) -> Option<Vec<f32>> {
+    let DataType::Array(items) = value else {
+        return None;
+    };
+    items
+        .iter()
+        .map(|item| match item {
+            DataType::Number(n) => Some(*n),
+            _ => None,
+        })
+        .collect()
+}
+
+// Vector of a top level value, `*` is the value itself and `*.<field>` a
+// field of the document
+pub(crate) fn vector_at(value: &DataType, spec: &str) -> Option<Vec<f32>> {
+    match spec.strip_prefix("*.") {
+        Some(field) => to_vector(field_value(value, field)?),
+        None if spec == "*" => to_vector(value),
+        None => None,
+    }
+}
+
+pub(crate) fn valid_spec(spec: &str) -> bool {
+    spec == "*" || spec.strip_prefix("*.").is_some_and(|f| !f.is_empty())
+}
+
+// Distance to the query and node, ordered by the distance
+#[derive(Clone, Copy, PartialEq)]
+struct Candidate(f32, usize);
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+// Neighbors of each node in every layer
+const M: usize = 16;
+const EF_CONSTRUCTION: usize = 64;
+const EF_SEARCH: usize = 64;
+
+struct Node {
+    key: String,
+    vector: Vec<f32>,
+    // neighbors in each layer, from 0 up to the level of the node
+    neighbors: Vec<Vec<usize>>,
+    // removed nodes stay in the graph to keep it connected
+    removed: bool,
+}
+
+pub struct Hnsw {
+    pub metric: Metric,
+    nodes: Vec<Node>,
+    keys: HashMap<String, usize>,
+    entry: Option<usize>,
+    removed: usize,
+}
+
+// Layers of a key, taken from its hash so the graph is the same every time
+fn level(key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let uniform = (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64;
+    (-(1.0 - uniform).ln() / (M as f64).ln()) as usize
+}
+
+impl Hnsw {
+    pub fn new(metric: Metric) -> Self {
+        Hnsw {
+            metric,
+            nodes: Vec::new(),
+            keys: HashMap::new(),
+            entry: None,
+            removed: 0,
+        }
+    }
+
+    fn distance(&self, query: &[f32], node: usize) -> f32 {
+        let vector = &self.nodes[node].vector;
+        self.metric.distance(query, vector).unwrap_or(f32::INFINITY)
+    }
+
+    // Closest nodes to the query in a layer, starting from the entries
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entries: &[usize],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<usize> = entries.iter().copied().collect();
+        let mut candidates = BinaryHeap::new();
+        let mut found = BinaryHeap::new();
+        for &entry in entries {
+            let candidate = Candidate(self.distance(query, entry), entry);
+            candidates.push(Reverse(candidate));
+            found.push(candidate);
+        }
+        while let Some(Reverse(closest)) = candidates.pop() {
+            if found.len() >= ef && found.peek().is_some_and(|f: &Candidate| closest.0 > f.0) {
+                break;
+            }
+            for &neighbor in &self.nodes[closest.1].neighbors[layer] {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let candidate = Candidate(self.distance(query, neighbor), neighbor);
+                if found.len() < ef || found.peek().is_some_and(|f| candidate.0 < f.0) {
+                    candidates.push(Reverse(candidate));
+                    found.push(candidate);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        found.into_sorted_vec()
+    }
+
+    // Entry of the layer 0, going down from the top one
+    fn descend(&self, query: &[f32], to: usize) -> Option<usize> {
+        let mut entry = self.entry?;
+        let top = self.nodes[entry].neighbors.len() - 1;
+        for layer in (to + 1..=top).rev() {
+            entry = self.search_layer(query, &[entry], 1, layer)[0].1;
+        }
+        Some(entry)
+    }
+
+    // Adds or replaces the vector of the key
+    pub fn insert(&mut self, key: &str, vector: Vec<f32>) {
+        self.remove(key);
+        let node = self.nodes.len();
+        let level = level(key);
+        self.nodes.push(Node {
+            key: key.to_string(),
+            vector,
+            neighbors: vec![Vec::new(); level + 1],
+            removed: false,
+        });
+        self.keys.insert(key.to_string(), node);
+        let Some(entry) = self.entry else {
+            self.entry = Some(node);
+            return;
+        };
+        let top = self.nodes[entry].neighbors.len() - 1;
+        let query = self.nodes[node].vector.clone();
+        let mut entries = vec![self.descend(&query, level.min(top)).unwrap_or(entry)];
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(&query, &entries, EF_CONSTRUCTION, layer);
+            let max = if layer == 0 { M * 2 } else { M };
+            let neighbors: Vec<usize> = found.iter().take(M).map(|c| c.1).collect();
+            for &neighbor in &neighbors {
+                self.nodes[neighbor].neighbors[layer].push(node);
+                if self.nodes[neighbor].neighbors[layer].len() > max {
+                    self.prune(neighbor, layer, max);
+                }
+            }
+            self.nodes[node].neighbors[layer] = neighbors;
+            entries = found.iter().map(|c| c.1).collect();
+        }
+        if level > top {
+            self.entry = Some(node);
+        }
+    }
+
+    // Keeps the max closest neighbors of the node in the layer
+    fn prune(&mut self, node: usize, layer: usize, max: usize) {
+        let vector = &self.nodes[node].vector;
+        let mut neighbors: Vec<Candidate> = self.nodes[node].neighbors[layer]
+            .iter()
+            .map(|&n| Candidate(self.distance(vector, n), n))
+            .collect();
+        neighbors.sort();
+        neighbors.truncate(max);
+        self.nodes[node].neighbors[layer] = neighbors.into_iter().map(|c| c.1).collect();
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        let Some(node) = self.keys.remove(key) else {
+            return false;
+        };
+        self.nodes[node].removed = true;
+        self.removed += 1;
+        // built again once most of the graph is removed nodes
+        if self.removed > self.keys.len().max(M) {
+            let live: Vec<(String, Vec<f32>)> = self
+                .nodes
+                .drain(..)
+                .filter(|n| !n.removed)
+                .map(|n| (n.key, n.vector))
+                .collect();
+            *self = Hnsw::new(self.metric);
+            for (key, vector) in live {
+                self.insert(&key, vector);
+            }
+        }
+        true
+    }
+
+    // Approximate k closest keys to the query that pass the filter, with
+    // their distances
+    pub fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        filter: impl Fn(&str) -> bool,
+    ) -> Vec<(String, f32)> {
+        let Some(entry) = self.descend(query, 0) else {
+            return Vec::new();
+        };
+        let found = self.search_layer(query, &[entry], EF_SEARCH.max(k), 0);
+        found
+            .into_iter()
+            .map(|c| (&self.nodes[c.1], c.0))
+            .filter(|(node, distance)| !node.removed && distance.is_finite() && filter(&node.key))
+            .take(k)
+            .map(|(node, distance)| (node.key.clone(), distance))
+            .collect()
+    }
+}
+
+// Vector index of a collection, on the vectors at spec of the keys
+pub struct VectorIndex {
+    pub name: String,
+    pub spec: String,
+    graph: Hnsw,
+}
+
+impl VectorIndex {
+    pub fn new(name: &str, spec: &str, metric: Metric) -> Result<Self, &'static str> {
+        if !valid_spec(spec) {
+            return Err("Vector index must be on * or *.<field>");
+        }
+        Ok(VectorIndex {
+            name: name.to_string(),
+            spec: spec.to_string(),
+            graph: Hnsw::new(metric),
+        })
+    }
+
+    pub fn metric(&self) -> Metric {
+        self.graph.metric
+    }
+
+    pub(crate) fn build(&mut self, data: &Keys) {
+        self.graph = Hnsw::new(self.graph.metric);
+        for (key, value) in data.iter() {
+            self.update(key, Some(value));
+        }
+    }
+
+    // Indexes the current value of the key, None when it was deleted
+    pub(crate) fn update(&mut self, key: &str, value: Option<&DataType>) {
+        match value.and_then(|v| vector_at(v, &self.spec)) {
+            Some(vector) => self.graph.insert(key, vector),
+            None => {
+                self.graph.remove(key);
+            }
+        }
+    }
+
+    pub(crate) fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        filter: impl Fn(&str) -> bool,
+    ) -> Vec<(String, f32)> {
+        self.graph.search(query, k, filter)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_knn() {
+    assert_eq!(Metric::L2.distance(&[0.0, 0.0], &[3.0, 4.0]), Some(5.0));
+    assert_eq!(Metric::Dot.distance(&[1.0, 2.0], &[3.0, 4.0]), Some(-11.0));
+    assert_eq!(Metric::Cosine.distance(&[1.0, 0.0], &[0.0, 2.0]), Some(1.0));
+    assert_eq!(Metric::Cosine.distance(&[1.0], &[1.0, 2.0]), None);
+    assert_eq!(
+        to_vector(&DataType::Array(vec![
+            DataType::from(1),
+            DataType::from("a")
+        ])),
+        None
+    );
+
+    // points of a grid, the graph finds the same closest ones
+    let mut graph = Hnsw::new(Metric::L2);
+    for x in 0..20 {
+        for y in 0..20 {
+            graph.insert(&format!("{}-{}", x, y), vec![x as f32, y as f32]);
+        }
+    }
+    assert_eq!(graph.keys.len(), 400);
+    let found = graph.search(&[4.2, 7.1], 1, |_| true);
+    assert_eq!(found[0].0, "4-7");
+    let found = graph.search(&[4.2, 7.1], 5, |key| key != "4-7");
+    assert_eq!(found.len(), 5);
+    assert!(found.iter().all(|(key, _)| key != "4-7"));
+    assert_eq!(found[0].0, "5-7");
+
+    for x in 0..20 {
+        for y in 0..19 {
+            graph.remove(&format!("{}-{}", x, y));
+        }
+    }
+    let found = graph.search(&[4.2, 7.1], 2, |_| true);
+    let keys: Vec<&str> = found.iter().map(|(k, _)| k.as_str()).collect();
+    assert_eq!(keys, vec!["4-19", "5-19"]);
+}
@@ -1,23 +1,43 @@
 mod arg_parser;
+#[cfg(feature = "server")]
+mod audit;
+#[cfg(feature = "server")]
+mod cluster;
 mod command;
 mod help_const;
-mod infusedb;
+#[cfg(feature = "server")]
+mod metrics;
+#[cfg(feature = "server")]
+mod raft;
+#[cfg(feature = "server")]
+mod replication;
 #[cfg(feature = "server")]
 mod server;
+mod undo;
 
 #[cfg(feature = "server")]
 use server::Server;
 
 use arg_parser::{ArgSearch, args_parser};
 use command::Command;
-use infusedb::{DataType, InfuseDB, VERSION, utils};
+use infusedb::{DataType, EvictionPolicy, InfuseDB, MemoryLimit, VERSION, utils};
+use undo::UndoStack;
 
 use std::io::Write;
+#[cfg(feature = "server")]
+use std::net::ToSocketAddrs;
 use std::path::Path;
+#[cfg(feature = "server")]
+use std::time::Duration;
 use std::{env, io};
 
 const DEFAULT_PATH: &str = "~/.infusedb/default.mdb";
 const DEFAULT_COLLECTION_NAME: &str = "default";
+#[cfg(feature = "server")]
+const DEFAULT_PORT: usize = 1234;
+// size of each change feed file and rotated files kept
+const CDC_MAX_SIZE: u64 = 64 * 1024 * 1024;
+const CDC_KEEP: usize = 10;
 
 fn format_data_type(data: DataType, sub: u32) -> String {
     match data {
@@ -56,11 +76,30 @@ fn main() {
     } else {
         db = InfuseDB::load(&path).unwrap();
     }
+    if let Some(cdc_path) = args.get_key("--cdc") {
+        let cdc_path = cdc_path.replace("~", home);
+        if let Err(err) = db.enable_cdc(&cdc_path, CDC_MAX_SIZE, CDC_KEEP) {
+            println!("Error opening change feed {}: {}", cdc_path, err);
+        }
+    }
+    if let Some(max_bytes) = args.get_key("--maxmemory") {
+        let policy = args.get_key("--maxmemory-policy");
+        let policy = policy.as_deref().unwrap_or("lru");
+        let (Ok(max_bytes), Some(policy)) = (max_bytes.parse(), EvictionPolicy::parse(policy))
+        else {
+            println!("Invalid memory limit: {} {}", max_bytes, policy);
+            return;
+        };
+        db.set_memory_limit(Some(MemoryLimit { max_bytes, policy }));
+    }
     println!("InfuseDB {}", VERSION);
     if db.get_collection(&collection_name).is_none() {
         let _ = db.create_collection(&collection_name);
     }
     let mut selected = String::new();
+    // commands of the open transaction with their collection
+    let mut queued: Option<Vec<(String, String)>> = None;
+    let mut undo = UndoStack::default();
     if args.count_simple() == 0 {
         loop {
             print!("{}> ", selected);
@@ -79,8 +118,61 @@ fn main() {
                 Vec::new()
             };
 
+            let passthrough = [
+                "begin", "exec", "discard", "exit", "select", "deselect", "help", "undo", "redo",
+            ];
+            if let Some(queue) = queued.as_mut()
+                && !passthrough.contains(&action.as_str())
+            {
+                if ["new", "del_col", "commit"].contains(&action.as_str()) {
+                    println!("{} can not be part of a transaction", action);
+                } else if selected.is_empty() {
+                    println!("No collection selected");
+                } else {
+                    queue.push((selected.clone(), buffer.clone()));
+                    println!("Queued");
+                }
+                continue;
+            }
+
             if action == "exit" {
                 break;
+            } else if action == "begin" {
+                if queued.is_some() {
+                    println!("Transaction already started");
+                } else {
+                    queued = Some(Vec::new());
+                }
+                continue;
+            } else if action == "discard" {
+                if queued.take().is_none() {
+                    println!("No transaction started");
+                }
+                continue;
+            } else if action == "exec" {
+                let Some(queue) = queued.take() else {
+                    println!("No transaction started");
+                    continue;
+                };
+                let size = queue
+                    .iter()
+                    .map(|(c, cmd)| command::write_size(&mut db, c, cmd))
+                    .sum();
+                if queue.iter().any(|(_, cmd)| command::is_write(cmd))
+                    && let Err(err) = db.make_room(size)
+                {
+                    println!("{:?}", err);
+                    continue;
+                }
+                match undo.exec(&mut db, &queue) {
+                    Ok(results) => {
+                        for (i, result) in results.into_iter().enumerate() {
+                            println!("{}) {}", i + 1, format_data_type(result, 0));
+                        }
+                    }
+                    Err(err) => println!("Transaction failed: {:?}", err.to_string()),
+                }
+                continue;
             } else if action == "select" {
                 if args.len() >= 1 && db.get_collection_list().contains(&args[0]) {
                     selected = args[0].clone()
@@ -105,15 +197,47 @@ fn main() {
                         continue;
                     }
                 }
-                db.remove_collection(selected);
+                match db.get_collection(&selected) {
+                    Some(collection) => {
+                        undo.dropped(collection);
+                        db.remove_collection(selected);
+                    }
+                    None => println!("Collection don't exists"),
+                }
                 selected = String::new();
             } else if action == "new" {
                 if args.len() != 0 {
-                    let _ = db.create_collection(&args[0]);
+                    if db.create_collection(&args[0]).is_ok() {
+                        undo.created(&args[0]);
+                    }
                 } else {
                     println!("No collection name provided");
                 }
                 continue;
+            } else if action == "undo" || action == "redo" {
+                if args.first().is_some_and(|a| a == "list") {
+                    for description in undo.pending() {
+                        println!("-> {}", description);
+                    }
+                    continue;
+                }
+                let done = if action == "undo" {
+                    undo.undo(&mut db)
+                } else {
+                    undo.redo(&mut db)
+                };
+                match done {
+                    Some(Ok(description)) if action == "undo" => {
+                        println!("Reverted: {}", description)
+                    }
+                    Some(Ok(description)) => println!("Redone: {}", description),
+                    Some(Err(err)) => println!("Can not {} {}", action, err),
+                    None => println!("Nothing to {}", action),
+                }
+                if !selected.is_empty() && db.get_collection(&selected).is_none() {
+                    selected = String::new();
+                }
+                continue;
             } else if action == "commit" {
                 let r = db.dump();
                 if r.is_err() {
@@ -122,6 +246,19 @@ fn main() {
                     println!("Changed saved");
                 }
                 continue;
+            } else if selected.is_empty() && action == "search" {
+                // search <collection> "<terms>"
+                let Some(collection) = args.first().and_then(|name| db.get_collection(name)) else {
+                    println!("Collection don't exists");
+                    continue;
+                };
+                let terms = args[1..].join(" ");
+                let r = collection.run(&format!("search \"{}\"", terms.trim_matches('"')));
+                match r {
+                    Ok(result) => println!("{}", format_data_type(result, 0)),
+                    Err(err) => println!("{:?}", err.to_string()),
+                }
+                continue;
             } else if action == "help" {
                 if selected.is_empty() {
                     println!("{}", help_const::HELP_STR_MAIN);
@@ -135,8 +272,14 @@ fn main() {
                 println!("No collection selected");
                 continue;
             }
-            let collection = db.get_collection(selected.as_str()).unwrap();
-            let r = collection.run(&buffer);
+            let size = command::write_size(&mut db, &selected, &buffer);
+            if command::is_write(&buffer)
+                && let Err(err) = db.make_room(size)
+            {
+                println!("{:?}", err);
+                continue;
+            }
+            let r = undo.run(&mut db, &selected, &buffer);
             let output = match r {
                 Ok(result) => format!("{}", format_data_type(result, 0)),
                 Err(err) => format!("{:?}", err.to_string()),
@@ -146,8 +289,72 @@ fn main() {
     } else {
         #[cfg(feature = "server")]
         if args.get_key("-s").is_some() {
-            let mut server = Server::new("0.0.0.0", 1234, db).expect("vaia");
-            println!("Starting server on 1234");
+            let port = args
+                .get_key("--port")
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(DEFAULT_PORT);
+            let mut server = Server::new("0.0.0.0", port, db).expect("vaia");
+            let secs = |key: &str| {
+                args.get_key(key)
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .map(Duration::from_secs)
+            };
+            server.config.max_connections =
+                args.get_key("--max-conn").and_then(|v| v.parse().ok());
+            server.config.idle_timeout = secs("--idle-timeout");
+            server.config.max_lifetime = secs("--max-lifetime");
+            server.config.metrics_addr = args
+                .get_key("--metrics")
+                .and_then(|port| format!("0.0.0.0:{}", port).parse().ok());
+            server.config.audit_log = args.get_key("--audit-log");
+            server.config.slow_log = args.get_key("--slow-log");
+            let slow_ms = args.get_key("--slow-ms").and_then(|v| v.parse().ok());
+            server.config.slow_threshold = Duration::from_millis(slow_ms.unwrap_or(10));
+            if let Some(primary) = args.get_key("--replica-of") {
+                let addr = primary.to_socket_addrs().ok().and_then(|mut a| a.next());
+                if addr.is_none() {
+                    println!("Invalid primary address: {}", primary);
+                    return;
+                }
+                server.config.replica_of = addr;
+            }
+            if let Some(nodes) = args.get_key("--cluster") {
+                for node in nodes.split(',') {
+                    let parsed = node.split_once('=').and_then(|(id, addr)| {
+                        let id = id.parse::<usize>().ok()?;
+                        let addr = addr.to_socket_addrs().ok()?.next()?;
+                        Some((id, addr))
+                    });
+                    let Some((id, addr)) = parsed else {
+                        println!("Invalid cluster node: {}", node);
+                        return;
+                    };
+                    if cluster::cluster_addr(addr).is_none() {
+                        println!(
+                            "Invalid cluster node: {}, the port plus {} must be below 65536",
+                            node,
+                            cluster::CLUSTER_PORT_OFFSET
+                        );
+                        return;
+                    }
+                    server.config.cluster.insert(id, addr);
+                }
+                let id = args.get_key("--node").and_then(|v| v.parse().ok());
+                match id {
+                    Some(id) if server.config.cluster.contains_key(&id) => {
+                        server.config.node_id = id
+                    }
+                    _ => {
+                        println!("--node must be the id of one of the cluster nodes");
+                        return;
+                    }
+                }
+                if server.config.replica_of.is_some() {
+                    println!("--replica-of can not be used in cluster mode");
+                    return;
+                }
+            }
+            println!("Starting server on {}", port);
             let _ = server.listen();
 
             return;
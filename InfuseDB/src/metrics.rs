@@ -0,0 +1,189 @@
+// Prometheus metrics for the server
+// Counters are updated from the server loop and rendered in the
+// text exposition format when the metrics listener is scraped
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::InfuseDB;
+
+// Upper bounds of the latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.1, 1.0,
+];
+
+// Label value with \, " and new lines escaped
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        for (i, bound) in self.bounds.iter().enumerate() {
+            if value <= *bound {
+                self.counts[i] += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+pub struct Metrics {
+    commands: HashMap<String, u64>,
+    errors: HashMap<&'static str, u64>,
+    latency: Histogram,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            commands: HashMap::new(),
+            errors: HashMap::new(),
+            latency: Histogram::new(&LATENCY_BUCKETS),
+            bytes_in: 0,
+            bytes_out: 0,
+        }
+    }
+
+    pub fn command(&mut self, name: &str, elapsed: Duration, error: Option<&'static str>) {
+        *self.commands.entry(name.to_string()).or_insert(0) += 1;
+        if let Some(kind) = error {
+            *self.errors.entry(kind).or_insert(0) += 1;
+        }
+        self.latency.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn bytes_in(&mut self, n: usize) {
+        self.bytes_in += n as u64;
+    }
+
+    pub fn bytes_out(&mut self, n: usize) {
+        self.bytes_out += n as u64;
+    }
+
+    pub fn render(&self, db: &mut InfuseDB, connections: usize) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP infusedb_commands_total Commands processed by name.\n");
+        out.push_str("# TYPE infusedb_commands_total counter\n");
+        let mut commands: Vec<_> = self.commands.iter().collect();
+        commands.sort();
+        for (name, count) in commands {
+            let _ = writeln!(
+                out,
+                "infusedb_commands_total{{command=\"{}\"}} {}",
+                label(name),
+                count
+            );
+        }
+
+        out.push_str("# HELP infusedb_errors_total Failed commands by error kind.\n");
+        out.push_str("# TYPE infusedb_errors_total counter\n");
+        let mut errors: Vec<_> = self.errors.iter().collect();
+        errors.sort();
+        for (kind, count) in errors {
+            let _ = writeln!(out, "infusedb_errors_total{{kind=\"{}\"}} {}", kind, count);
+        }
+
+        out.push_str("# HELP infusedb_command_duration_seconds Command latency.\n");
+        out.push_str("# TYPE infusedb_command_duration_seconds histogram\n");
+        self.latency
+            .render(&mut out, "infusedb_command_duration_seconds");
+
+        out.push_str("# HELP infusedb_bytes_received_total Bytes read from clients.\n");
+        out.push_str("# TYPE infusedb_bytes_received_total counter\n");
+        let _ = writeln!(out, "infusedb_bytes_received_total {}", self.bytes_in);
+        out.push_str("# HELP infusedb_bytes_sent_total Bytes written to clients.\n");
+        out.push_str("# TYPE infusedb_bytes_sent_total counter\n");
+        let _ = writeln!(out, "infusedb_bytes_sent_total {}", self.bytes_out);
+
+        out.push_str("# HELP infusedb_connections Open client connections.\n");
+        out.push_str("# TYPE infusedb_connections gauge\n");
+        let _ = writeln!(out, "infusedb_connections {}", connections);
+
+        let dumps = db.dump_stats();
+        out.push_str("# HELP infusedb_dump_duration_seconds Time spent saving the database.\n");
+        out.push_str("# TYPE infusedb_dump_duration_seconds summary\n");
+        let total = dumps.total_duration.as_secs_f64();
+        let _ = writeln!(out, "infusedb_dump_duration_seconds_sum {}", total);
+        let _ = writeln!(out, "infusedb_dump_duration_seconds_count {}", dumps.count);
+        out.push_str("# HELP infusedb_dump_size_bytes Size of the last saved file.\n");
+        out.push_str("# TYPE infusedb_dump_size_bytes gauge\n");
+        let _ = writeln!(out, "infusedb_dump_size_bytes {}", dumps.last_size);
+
+        out.push_str("# HELP infusedb_evicted_keys_total Keys evicted by the memory limits.\n");
+        out.push_str("# TYPE infusedb_evicted_keys_total counter\n");
+        let _ = writeln!(out, "infusedb_evicted_keys_total {}", db.evicted());
+
+        out.push_str("# HELP infusedb_keys Keys stored by collection.\n");
+        out.push_str("# TYPE infusedb_keys gauge\n");
+        for name in db.get_collection_list() {
+            let count = db.get_collection(&name).unwrap().count();
+            let name = label(&name);
+            let _ = writeln!(out, "infusedb_keys{{collection=\"{}\"}} {}", name, count);
+        }
+        out
+    }
+}
+
+// Wraps the metrics in a minimal HTTP response
+pub fn http_response(body: &str) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+#[test]
+fn test_histogram() {
+    let mut h = Histogram::new(&[1.0, 2.0]);
+    h.observe(0.5);
+    h.observe(1.5);
+    h.observe(3.0);
+    let mut out = String::new();
+    h.render(&mut out, "h");
+    assert!(out.contains("h_bucket{le=\"1\"} 1"));
+    assert!(out.contains("h_bucket{le=\"2\"} 2"));
+    assert!(out.contains("h_bucket{le=\"+Inf\"} 3"));
+    assert!(out.contains("h_count 3"));
+}
+
+#[cfg(test)]
+#[test]
+fn test_label() {
+    assert_eq!(label("users"), "users");
+    assert_eq!(label("a\\b \"c\"\nd"), "a\\\\b \\\"c\\\"\\nd");
+}
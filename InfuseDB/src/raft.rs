@@ -0,0 +1,758 @@
+// Raft consensus for the cluster mode
+// The node is a state machine without any IO: the server feeds it the
+// messages received from the other nodes and the passing of time, and
+// sends the messages it leaves in the outbox. Committed entries are taken
+// by the server and applied to the database in order.
+//
+// Messages are sent as lines, an append is followed by one line per entry:
+//
+//   vote-request <term> <from> <last_index> <last_term>
+//   vote <term> <from> <granted>
+//   append <term> <from> <prev_index> <prev_term> <commit> <count>
+//   <term> <collection> <command>
+//   append-reply <term> <from> <success> <match_index>
+//
+// The term, the vote and the log are persisted in an append only file,
+// replayed when the node starts:
+//
+//   term <term> <voted_for|->
+//   entry <index> <term> <collection> <command>
+//
+// The server does not save the .mdb file, a restarted node applies the
+// whole log again once it is committed
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+pub type NodeId = usize;
+
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(500);
+// entries sent in a single append
+const MAX_BATCH: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub term: u64,
+    // empty for the entry appended by a new leader
+    pub collection: String,
+    pub command: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    VoteRequest {
+        term: u64,
+        from: NodeId,
+        last_index: u64,
+        last_term: u64,
+    },
+    Vote {
+        term: u64,
+        from: NodeId,
+        granted: bool,
+    },
+    Append {
+        term: u64,
+        from: NodeId,
+        prev_index: u64,
+        prev_term: u64,
+        commit: u64,
+        entries: Vec<Entry>,
+    },
+    AppendReply {
+        term: u64,
+        from: NodeId,
+        success: bool,
+        match_index: u64,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Follower => "follower",
+            Role::Candidate => "candidate",
+            Role::Leader => "leader",
+        }
+    }
+}
+
+impl Entry {
+    fn encode(&self) -> String {
+        if self.command.is_empty() {
+            self.term.to_string()
+        } else {
+            format!("{} {} {}", self.term, self.collection, self.command)
+        }
+    }
+
+    fn decode(line: &str) -> Option<Entry> {
+        let mut parts = line.splitn(3, ' ');
+        Some(Entry {
+            term: parts.next()?.parse().ok()?,
+            collection: parts.next().unwrap_or_default().to_string(),
+            command: parts.next().unwrap_or_default().to_string(),
+        })
+    }
+}
+
+impl Message {
+    fn term(&self) -> u64 {
+        match self {
+            Message::VoteRequest { term, .. }
+            | Message::Vote { term, .. }
+            | Message::Append { term, .. }
+            | Message::AppendReply { term, .. } => *term,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        match self {
+            Message::VoteRequest {
+                term,
+                from,
+                last_index,
+                last_term,
+            } => format!(
+                "vote-request {} {} {} {}",
+                term, from, last_index, last_term
+            ),
+            Message::Vote {
+                term,
+                from,
+                granted,
+            } => format!("vote {} {} {}", term, from, *granted as u8),
+            Message::Append {
+                term,
+                from,
+                prev_index,
+                prev_term,
+                commit,
+                entries,
+            } => {
+                let mut text = format!(
+                    "append {} {} {} {} {} {}",
+                    term,
+                    from,
+                    prev_index,
+                    prev_term,
+                    commit,
+                    entries.len()
+                );
+                for entry in entries {
+                    text.push('\n');
+                    text.push_str(&entry.encode());
+                }
+                text
+            }
+            Message::AppendReply {
+                term,
+                from,
+                success,
+                match_index,
+            } => format!(
+                "append-reply {} {} {} {}",
+                term, from, *success as u8, match_index
+            ),
+        }
+    }
+}
+
+// Rebuilds the messages from the lines received
+#[derive(Default)]
+pub struct Decoder {
+    // append waiting for its entries and number of entries left
+    append: Option<(Message, usize)>,
+}
+
+impl Decoder {
+    pub fn feed(&mut self, line: &str) -> Option<Message> {
+        if let Some((mut message, left)) = self.append.take() {
+            if let Message::Append { entries, .. } = &mut message {
+                entries.push(Entry::decode(line)?);
+            }
+            return self.pending(message, left - 1);
+        }
+        let parts: Vec<&str> = line.split(' ').collect();
+        let n = |i: usize| parts.get(i).and_then(|v| v.parse::<u64>().ok());
+        match (parts[0], parts.len()) {
+            ("vote-request", 5) => Some(Message::VoteRequest {
+                term: n(1)?,
+                from: n(2)? as NodeId,
+                last_index: n(3)?,
+                last_term: n(4)?,
+            }),
+            ("vote", 4) => Some(Message::Vote {
+                term: n(1)?,
+                from: n(2)? as NodeId,
+                granted: n(3)? == 1,
+            }),
+            ("append", 7) => {
+                let message = Message::Append {
+                    term: n(1)?,
+                    from: n(2)? as NodeId,
+                    prev_index: n(3)?,
+                    prev_term: n(4)?,
+                    commit: n(5)?,
+                    entries: Vec::new(),
+                };
+                self.pending(message, n(6)? as usize)
+            }
+            ("append-reply", 5) => Some(Message::AppendReply {
+                term: n(1)?,
+                from: n(2)? as NodeId,
+                success: n(3)? == 1,
+                match_index: n(4)?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn pending(&mut self, message: Message, left: usize) -> Option<Message> {
+        if left == 0 {
+            return Some(message);
+        }
+        self.append = Some((message, left));
+        None
+    }
+}
+
+struct Storage {
+    path: String,
+    file: File,
+}
+
+impl Storage {
+    fn write(&mut self, line: &str) {
+        let result = writeln!(self.file, "{}", line).and_then(|_| self.file.sync_data());
+        if let Err(err) = result {
+            eprintln!("Error writing {}: {}", self.path, err);
+        }
+    }
+}
+
+pub struct RaftNode {
+    pub id: NodeId,
+    peers: Vec<NodeId>,
+    term: u64,
+    voted_for: Option<NodeId>,
+    // the entry at index i is log[i - 1]
+    log: Vec<Entry>,
+    commit_index: u64,
+    last_applied: u64,
+    role: Role,
+    leader: Option<NodeId>,
+    votes: HashSet<NodeId>,
+    next_index: HashMap<NodeId, u64>,
+    match_index: HashMap<NodeId, u64>,
+    election_deadline: Instant,
+    next_heartbeat: Instant,
+    outbox: Vec<(NodeId, Message)>,
+    storage: Option<Storage>,
+}
+
+// Election timeout between one and two times ELECTION_TIMEOUT, so the
+// nodes rarely start an election at the same time
+fn election_timeout() -> Duration {
+    let jitter = uuid::Uuid::new_v4().as_u128() as u64 % ELECTION_TIMEOUT.as_millis() as u64;
+    ELECTION_TIMEOUT + Duration::from_millis(jitter)
+}
+
+impl RaftNode {
+    pub fn new(id: NodeId, peers: Vec<NodeId>) -> Self {
+        let now = Instant::now();
+        RaftNode {
+            id,
+            peers,
+            term: 0,
+            voted_for: None,
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            role: Role::Follower,
+            leader: None,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            election_deadline: now + election_timeout(),
+            next_heartbeat: now,
+            outbox: Vec::new(),
+            storage: None,
+        }
+    }
+
+    // Restores the state persisted in the file and keeps persisting to it
+    pub fn open(id: NodeId, peers: Vec<NodeId>, path: &str) -> io::Result<Self> {
+        let mut node = RaftNode::new(id, peers);
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        for line in contents.lines() {
+            let mut parts = line.splitn(3, ' ');
+            let kind = parts.next().unwrap_or_default();
+            let value = parts.next().and_then(|v| v.parse::<u64>().ok());
+            let rest = parts.next().unwrap_or_default();
+            match (kind, value) {
+                ("term", Some(term)) => {
+                    node.term = term;
+                    node.voted_for = rest.parse().ok();
+                }
+                ("entry", Some(index)) if index >= 1 => {
+                    let Some(entry) = Entry::decode(rest) else {
+                        continue;
+                    };
+                    node.log.truncate(index as usize - 1);
+                    node.log.push(entry);
+                }
+                _ => {}
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        node.storage = Some(Storage {
+            path: path.to_string(),
+            file,
+        });
+        Ok(node)
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn last_applied(&self) -> u64 {
+        self.last_applied
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            i => self.log.get(i as usize - 1).map(|e| e.term).unwrap_or(0),
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    fn persist_term(&mut self) {
+        let vote = self.voted_for.map(|v| v.to_string());
+        let line = format!("term {} {}", self.term, vote.as_deref().unwrap_or("-"));
+        if let Some(storage) = self.storage.as_mut() {
+            storage.write(&line);
+        }
+    }
+
+    fn append_entry(&mut self, index: u64, entry: Entry) {
+        if let Some(storage) = self.storage.as_mut() {
+            storage.write(&format!("entry {} {}", index, entry.encode()));
+        }
+        self.log.truncate(index as usize - 1);
+        self.log.push(entry);
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        if self.role == Role::Leader {
+            if now >= self.next_heartbeat {
+                self.broadcast_append(now);
+            }
+        } else if now >= self.election_deadline {
+            self.start_election(now);
+        }
+    }
+
+    fn start_election(&mut self, now: Instant) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.id);
+        self.votes = HashSet::from([self.id]);
+        self.persist_term();
+        self.election_deadline = now + election_timeout();
+        if self.votes.len() >= self.quorum() {
+            self.become_leader(now);
+            return;
+        }
+        let request = Message::VoteRequest {
+            term: self.term,
+            from: self.id,
+            last_index: self.last_index(),
+            last_term: self.term_at(self.last_index()),
+        };
+        for peer in self.peers.clone() {
+            self.outbox.push((peer, request.clone()));
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, now: Instant) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.persist_term();
+        }
+        self.role = Role::Follower;
+        self.election_deadline = now + election_timeout();
+    }
+
+    fn become_leader(&mut self, now: Instant) {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        let next = self.last_index() + 1;
+        for peer in self.peers.iter() {
+            self.next_index.insert(*peer, next);
+            self.match_index.insert(*peer, 0);
+        }
+        // entries of previous terms are only committed along with one of the current term
+        let entry = Entry {
+            term: self.term,
+            collection: String::new(),
+            command: String::new(),
+        };
+        self.append_entry(next, entry);
+        self.advance_commit();
+        self.broadcast_append(now);
+    }
+
+    fn send_append(&mut self, peer: NodeId) {
+        let next = self.next_index.get(&peer).copied().unwrap_or(1).max(1);
+        let prev_index = next - 1;
+        let end = self.log.len().min(prev_index as usize + MAX_BATCH);
+        let message = Message::Append {
+            term: self.term,
+            from: self.id,
+            prev_index,
+            prev_term: self.term_at(prev_index),
+            commit: self.commit_index,
+            entries: self.log[prev_index as usize..end].to_vec(),
+        };
+        self.outbox.push((peer, message));
+    }
+
+    fn broadcast_append(&mut self, now: Instant) {
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+        self.next_heartbeat = now + HEARTBEAT_INTERVAL;
+    }
+
+    fn advance_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != self.term {
+                break;
+            }
+            let replicas = self.match_index.values().filter(|m| **m >= index).count();
+            if replicas + 1 >= self.quorum() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+
+    // Appends a command to the log, only the leader accepts them.
+    // Returns the index of the entry or the leader known by the node
+    pub fn propose(&mut self, collection: &str, command: &str) -> Result<u64, Option<NodeId>> {
+        if self.role != Role::Leader {
+            return Err(self.leader);
+        }
+        let index = self.last_index() + 1;
+        let entry = Entry {
+            term: self.term,
+            collection: collection.to_string(),
+            command: command.to_string(),
+        };
+        self.append_entry(index, entry);
+        self.advance_commit();
+        self.broadcast_append(Instant::now());
+        Ok(index)
+    }
+
+    pub fn step(&mut self, message: Message, now: Instant) {
+        if message.term() > self.term {
+            self.become_follower(message.term(), now);
+            self.leader = None;
+        }
+        match message {
+            Message::VoteRequest {
+                term,
+                from,
+                last_index,
+                last_term,
+            } => {
+                let my_last_term = self.term_at(self.last_index());
+                let up_to_date = last_term > my_last_term
+                    || (last_term == my_last_term && last_index >= self.last_index());
+                let granted =
+                    term == self.term && self.voted_for.is_none_or(|v| v == from) && up_to_date;
+                if granted {
+                    self.voted_for = Some(from);
+                    self.persist_term();
+                    self.election_deadline = now + election_timeout();
+                }
+                let reply = Message::Vote {
+                    term: self.term,
+                    from: self.id,
+                    granted,
+                };
+                self.outbox.push((from, reply));
+            }
+            Message::Vote {
+                term,
+                from,
+                granted,
+            } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader(now);
+                    }
+                }
+            }
+            Message::Append {
+                term,
+                from,
+                prev_index,
+                prev_term,
+                commit,
+                entries,
+            } => {
+                if term < self.term {
+                    let reply = Message::AppendReply {
+                        term: self.term,
+                        from: self.id,
+                        success: false,
+                        match_index: 0,
+                    };
+                    self.outbox.push((from, reply));
+                    return;
+                }
+                self.become_follower(term, now);
+                self.leader = Some(from);
+                if prev_index > self.last_index() || self.term_at(prev_index) != prev_term {
+                    // the leader goes back from the last entry that may match
+                    let hint = self.last_index().min(prev_index.saturating_sub(1));
+                    let reply = Message::AppendReply {
+                        term: self.term,
+                        from: self.id,
+                        success: false,
+                        match_index: hint,
+                    };
+                    self.outbox.push((from, reply));
+                    return;
+                }
+                let mut index = prev_index;
+                for entry in entries {
+                    index += 1;
+                    if self.term_at(index) != entry.term || index > self.last_index() {
+                        self.append_entry(index, entry);
+                    }
+                }
+                if commit > self.commit_index {
+                    self.commit_index = commit.min(index);
+                }
+                let reply = Message::AppendReply {
+                    term: self.term,
+                    from: self.id,
+                    success: true,
+                    match_index: index,
+                };
+                self.outbox.push((from, reply));
+            }
+            Message::AppendReply {
+                term,
+                from,
+                success,
+                match_index,
+            } => {
+                if self.role != Role::Leader || term != self.term {
+                    return;
+                }
+                if success {
+                    let matched = self.match_index.entry(from).or_default();
+                    *matched = (*matched).max(match_index);
+                    self.next_index.insert(from, *matched + 1);
+                    self.advance_commit();
+                    if self.next_index[&from] <= self.last_index() {
+                        self.send_append(from);
+                    }
+                } else {
+                    let next = self.next_index.get(&from).copied().unwrap_or(1);
+                    let next = next.saturating_sub(1).min(match_index + 1).max(1);
+                    self.next_index.insert(from, next);
+                    self.send_append(from);
+                }
+            }
+        }
+    }
+
+    pub fn take_messages(&mut self) -> Vec<(NodeId, Message)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    // Committed entries not applied yet, with their index
+    pub fn take_committed(&mut self) -> Vec<(u64, Entry)> {
+        let mut entries = Vec::new();
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = self.log[self.last_applied as usize - 1].clone();
+            entries.push((self.last_applied, entry));
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+fn deliver(nodes: &mut [RaftNode], now: Instant) {
+    loop {
+        let mut messages = Vec::new();
+        for node in nodes.iter_mut() {
+            messages.extend(node.take_messages());
+        }
+        if messages.is_empty() {
+            break;
+        }
+        for (to, message) in messages {
+            if let Some(node) = nodes.iter_mut().find(|n| n.id == to) {
+                node.step(message, now);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn cluster(size: usize) -> Vec<RaftNode> {
+    (1..=size)
+        .map(|id| RaftNode::new(id, (1..=size).filter(|p| *p != id).collect()))
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_election_and_replication() {
+    let now = Instant::now();
+    let mut nodes = cluster(3);
+    nodes[0].tick(now + ELECTION_TIMEOUT * 2);
+    deliver(&mut nodes, now);
+    assert_eq!(nodes[0].role(), Role::Leader);
+    assert!(nodes.iter().all(|n| n.leader() == Some(1)));
+
+    assert_eq!(nodes[1].propose("users", "set a 1"), Err(Some(1)));
+    let index = nodes[0].propose("users", "set a 1").unwrap();
+    deliver(&mut nodes, now);
+    // the followers learn the commit with the next append
+    nodes[0].tick(now + ELECTION_TIMEOUT * 3);
+    deliver(&mut nodes, now);
+    for node in nodes.iter_mut() {
+        let committed = node.take_committed();
+        assert_eq!(committed.len(), 2);
+        assert_eq!(committed[1].0, index);
+        assert_eq!(committed[1].1.command, "set a 1");
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_conflicting_log_is_replaced() {
+    let now = Instant::now();
+    let mut nodes = cluster(3);
+    nodes[0].tick(now + ELECTION_TIMEOUT * 2);
+    deliver(&mut nodes, now);
+    // the leader appends an entry that never reaches the others
+    nodes[0].propose("users", "set lost 1").unwrap();
+    nodes[0].take_messages();
+
+    nodes[1].tick(now + ELECTION_TIMEOUT * 4);
+    deliver(&mut nodes, now);
+    assert_eq!(nodes[1].role(), Role::Leader);
+    assert_eq!(nodes[0].role(), Role::Follower);
+    nodes[1].propose("users", "set kept 1").unwrap();
+    deliver(&mut nodes, now);
+    assert_eq!(nodes[0].log, nodes[1].log);
+    assert!(nodes[0].log.iter().all(|e| e.command != "set lost 1"));
+}
+
+#[cfg(test)]
+#[test]
+fn test_message_lines() {
+    let message = Message::Append {
+        term: 3,
+        from: 2,
+        prev_index: 4,
+        prev_term: 2,
+        commit: 4,
+        entries: vec![
+            Entry {
+                term: 3,
+                collection: String::new(),
+                command: String::new(),
+            },
+            Entry {
+                term: 3,
+                collection: "users".to_string(),
+                command: "set name \"John Doe\"".to_string(),
+            },
+        ],
+    };
+    let mut decoder = Decoder::default();
+    let mut decoded = None;
+    for line in message.encode().lines() {
+        decoded = decoder.feed(line);
+    }
+    assert_eq!(decoded, Some(message));
+}
+
+#[cfg(test)]
+#[test]
+fn test_persisted_state() {
+    let dir = std::env::temp_dir().join(format!("infusedb_raft_{}", std::process::id()));
+    let _ = fs::create_dir_all(&dir);
+    let path = dir.join("db.mdb.raft");
+    let path = path.to_str().unwrap();
+    let now = Instant::now();
+
+    let mut node = RaftNode::open(1, Vec::new(), path).unwrap();
+    node.tick(now + ELECTION_TIMEOUT * 2);
+    node.propose("users", "set a 1").unwrap();
+    node.propose("users", "set b 2").unwrap();
+    assert_eq!(node.take_committed().len(), 3);
+    node.propose("users", "del a").unwrap();
+    drop(node);
+
+    let mut node = RaftNode::open(1, Vec::new(), path).unwrap();
+    assert_eq!(node.term(), 1);
+    assert_eq!(node.last_index(), 4);
+    assert_eq!(node.last_applied(), 0);
+    // the whole log is applied again once committed
+    node.tick(now + ELECTION_TIMEOUT * 2);
+    let committed = node.take_committed();
+    assert_eq!(committed.len(), 5);
+    assert_eq!(committed[1].1.command, "set a 1");
+    assert_eq!(committed[3].1.command, "del a");
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,476 @@
+// Primary/replica replication
+// A replica connects to the primary and sends `replicate`, the primary
+// answers with a full copy of the collections and then streams every
+// mutation, one per line:
+//
+//   sync <seq>                 start of the full copy
+//   [collection]               followed by the lines of Collection::dump
+//   synced                     end of the full copy
+//   set <seq> <ts> <collection> <key.path> <type> <value>
+//   del <seq> <ts> <collection> <key>
+//   drop <seq> <ts> <collection>
+//   ttl <seq> <ts> <collection> <key> <expiry|->
+//   cmd <seq> <ts> <collection> <command>
+//   ping <seq> <ts>            sent periodically to measure the lag
+//
+// <ts> is the time of the primary in milliseconds since the epoch, as is
+// <expiry>. `cmd` carries the commands that change the indexes and the
+// retention. Replicas do not evict, they delete the keys the primary evicts
+use crate::InfuseDB;
+use crate::command::Command;
+use infusedb::{ChangeEvent, ChangeOp, Collection, DataType, utils};
+
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+fn millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+// Mutation made on the primary, changes of the keys and the writes that
+// do not produce one, kept in a single channel to send them in order
+enum Mutation {
+    Change(ChangeEvent),
+    Ttl {
+        collection: String,
+        key: String,
+        expiry: Option<u128>,
+    },
+    Command {
+        collection: String,
+        command: String,
+    },
+}
+
+// Mutations made on the primary waiting to be sent to the replicas
+pub struct Primary {
+    sender: Sender<Mutation>,
+    events: Receiver<Mutation>,
+    seq: u64,
+}
+
+impl Primary {
+    pub fn new(db: &mut InfuseDB) -> Self {
+        let (sender, events) = mpsc::channel();
+        let changes = sender.clone();
+        db.on_change(Arc::new(move |event: &ChangeEvent| {
+            let _ = changes.send(Mutation::Change(event.clone()));
+        }));
+        Primary {
+            sender,
+            events,
+            seq: 0,
+        }
+    }
+
+    // Records what the write command run on the collection changed besides
+    // the keys: the ttl of the key written or the command itself
+    pub fn record(&self, db: &mut InfuseDB, collection: &str, command: &str) {
+        let words = utils::smart_split(command.to_string());
+        let Some(action) = words.first() else {
+            return;
+        };
+        let mutation = match action.as_str() {
+            "set" | "cas" | "restore" | "expire" | "persist" => {
+                let Some(key) = words.get(1) else {
+                    return;
+                };
+                let key = key.split('.').next().unwrap_or_default().to_string();
+                let Some(c) = db.get_collection(collection) else {
+                    return;
+                };
+                let expiry = c.expires_at(&key).map(millis);
+                Mutation::Ttl {
+                    collection: collection.to_string(),
+                    key,
+                    expiry,
+                }
+            }
+            "create" | "drop" | "retention" | "fulltext" => Mutation::Command {
+                collection: collection.to_string(),
+                command: command.lines().collect::<Vec<_>>().join(" "),
+            },
+            // del comes as a change, maxmemory only applies to the primary
+            _ => return,
+        };
+        let _ = self.sender.send(mutation);
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn full_sync(&self, db: &mut InfuseDB) -> Vec<String> {
+        let mut lines = vec![format!("sync {}", self.seq)];
+        for name in db.get_collection_list() {
+            let page = db.get_collection(&name).unwrap().dump();
+            lines.extend(page.lines().map(|line| line.to_string()));
+        }
+        lines.push("synced".to_string());
+        lines
+    }
+
+    // Lines of the mutations made since the last call
+    pub fn pending(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(mutation) = self.events.try_recv() {
+            let event = match mutation {
+                Mutation::Change(event) => event,
+                Mutation::Ttl {
+                    collection,
+                    key,
+                    expiry,
+                } => {
+                    self.seq += 1;
+                    let expiry = expiry.map_or("-".to_string(), |ms| ms.to_string());
+                    let ts = now_millis();
+                    lines.push(format!(
+                        "ttl {} {} {} {} {}",
+                        self.seq, ts, collection, key, expiry
+                    ));
+                    continue;
+                }
+                Mutation::Command {
+                    collection,
+                    command,
+                } => {
+                    self.seq += 1;
+                    let ts = now_millis();
+                    lines.push(format!(
+                        "cmd {} {} {} {}",
+                        self.seq, ts, collection, command
+                    ));
+                    continue;
+                }
+            };
+            self.seq += 1;
+            let ts = event.timestamp_millis();
+            let line = match (event.op, &event.new) {
+                (ChangeOp::Set, Some(value)) => format!(
+                    "set {} {} {} {} {} {}",
+                    self.seq,
+                    ts,
+                    event.collection,
+                    event.key,
+                    value.type_id(),
+                    value.to_string()
+                ),
+                (ChangeOp::Del, _) => {
+                    format!("del {} {} {} {}", self.seq, ts, event.collection, event.key)
+                }
+                (ChangeOp::Drop, _) => format!("drop {} {} {}", self.seq, ts, event.collection),
+                (ChangeOp::Set, None) => continue,
+            };
+            lines.push(line);
+        }
+        lines
+    }
+
+    pub fn ping(&self) -> String {
+        format!("ping {} {}", self.seq, now_millis())
+    }
+}
+
+// Connection of a replica to its primary
+pub struct ReplicaLink {
+    pub primary: SocketAddr,
+    stream: Option<TcpStream>,
+    buffer: Vec<u8>,
+    // collection being received during a full sync
+    page: Option<String>,
+    syncing: bool,
+    synced: bool,
+    applied_seq: u64,
+    primary_seq: u64,
+    last_ts: Option<u128>,
+    last_attempt: Option<Instant>,
+}
+
+impl ReplicaLink {
+    pub fn new(primary: SocketAddr) -> Self {
+        ReplicaLink {
+            primary,
+            stream: None,
+            buffer: Vec::new(),
+            page: None,
+            syncing: false,
+            synced: false,
+            applied_seq: 0,
+            primary_seq: 0,
+            last_ts: None,
+            last_attempt: None,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    // Connects to the primary if the link is down, waiting between attempts
+    pub fn connect(&mut self, registry: &Registry, token: Token) {
+        if self.stream.is_some()
+            || self
+                .last_attempt
+                .is_some_and(|at| at.elapsed() < RETRY_INTERVAL)
+        {
+            return;
+        }
+        self.last_attempt = Some(Instant::now());
+        let stream = net::TcpStream::connect_timeout(&self.primary, CONNECT_TIMEOUT).and_then(
+            |mut stream| {
+                stream.write_all(b"replicate\n")?;
+                stream.set_nonblocking(true)?;
+                Ok(stream)
+            },
+        );
+        match stream {
+            Ok(stream) => {
+                let mut stream = TcpStream::from_std(stream);
+                if registry
+                    .register(&mut stream, token, Interest::READABLE)
+                    .is_ok()
+                {
+                    self.buffer.clear();
+                    self.stream = Some(stream);
+                }
+            }
+            Err(err) => eprintln!("Error connecting to primary {}: {}", self.primary, err),
+        }
+    }
+
+    // Applies every complete line received from the primary
+    pub fn on_readable(&mut self, registry: &Registry, db: &mut InfuseDB) {
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+        let mut buf = [0u8; 4096];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => {
+                    self.disconnect(registry);
+                    break;
+                }
+                Ok(n) => self.buffer.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    eprintln!("Error reading from primary: {}", err);
+                    self.disconnect(registry);
+                    break;
+                }
+            }
+        }
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            self.apply(line.trim_end_matches(['\r', '\n']), db);
+        }
+    }
+
+    fn disconnect(&mut self, registry: &Registry) {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = registry.deregister(&mut stream);
+        }
+        self.synced = false;
+    }
+
+    fn flush_page(&mut self, db: &mut InfuseDB) {
+        if let Some(page) = self.page.take() {
+            let mut collection = Collection::load(&page);
+            collection.set_memory_limit(None);
+            db.insert_collection(collection);
+        }
+    }
+
+    // Applies a line sent by the primary
+    pub fn apply(&mut self, line: &str, db: &mut InfuseDB) {
+        if self.syncing {
+            if line == "synced" {
+                self.flush_page(db);
+                self.syncing = false;
+                self.synced = true;
+            } else if line.starts_with('[') {
+                self.flush_page(db);
+                self.page = Some(format!("{}\n", line));
+            } else if let Some(page) = self.page.as_mut() {
+                page.push_str(line);
+                page.push('\n');
+            }
+            return;
+        }
+
+        // the command of a cmd line is kept whole
+        let count = if line.starts_with("cmd ") { 5 } else { 7 };
+        let parts: Vec<&str> = line.splitn(count, ' ').collect();
+        let number = |i: usize| parts.get(i).and_then(|n| n.parse::<u128>().ok());
+        let (Some(seq), Some(ts)) = (number(1), number(2)) else {
+            if parts[0] == "sync" {
+                self.start_sync(number(1).unwrap_or(0) as u64, db);
+            }
+            // headers and replies to our own commands
+            return;
+        };
+        let seq = seq as u64;
+        match (parts[0], parts.len()) {
+            ("set", 7) => {
+                let t = parts[5].parse::<u16>().unwrap_or(0);
+                let Some(value) = DataType::load(t, parts[6].to_string()) else {
+                    eprintln!("Error parsing replicated value: {}", line);
+                    return;
+                };
+                if db.get_collection(parts[3]).is_none() {
+                    let _ = db.create_collection(parts[3]);
+                }
+                let collection = db.get_collection(parts[3]).unwrap();
+                if let Err(err) = collection.set_path(parts[4], value) {
+                    eprintln!("Error applying {}: {}", line, err);
+                }
+            }
+            ("del", 5) => {
+                if let Some(collection) = db.get_collection(parts[3]) {
+                    collection.rm(parts[4]);
+                }
+            }
+            ("drop", 4) => {
+                if db.get_collection(parts[3]).is_some() {
+                    db.remove_collection(parts[3].to_string());
+                }
+            }
+            ("ttl", 6) => {
+                if let Some(collection) = db.get_collection(parts[3]) {
+                    match parts[5].parse::<u64>() {
+                        Ok(ms) => {
+                            let time = UNIX_EPOCH + Duration::from_millis(ms);
+                            collection.expire_at(parts[4], time);
+                        }
+                        Err(_) => {
+                            collection.persist(parts[4]);
+                        }
+                    }
+                }
+            }
+            ("cmd", 5) => {
+                if db.get_collection(parts[3]).is_none() {
+                    let _ = db.create_collection(parts[3]);
+                }
+                let collection = db.get_collection(parts[3]).unwrap();
+                if let Err(err) = collection.run(parts[4]) {
+                    eprintln!("Error applying {}: {}", line, err.to_string());
+                }
+            }
+            ("ping", 3) => {}
+            _ => return,
+        }
+        if parts[0] != "ping" {
+            self.applied_seq = seq;
+        }
+        self.primary_seq = self.primary_seq.max(seq);
+        self.last_ts = Some(ts);
+    }
+
+    fn start_sync(&mut self, seq: u64, db: &mut InfuseDB) {
+        for name in db.get_collection_list() {
+            db.remove_collection(name);
+        }
+        self.syncing = true;
+        self.page = None;
+        self.applied_seq = seq;
+        self.primary_seq = seq;
+    }
+
+    pub fn status(&self) -> DataType {
+        let mut status = HashMap::new();
+        status.insert(
+            "primary".to_string(),
+            DataType::from(self.primary.to_string()),
+        );
+        status.insert("connected".to_string(), DataType::from(self.is_connected()));
+        status.insert("synced".to_string(), DataType::from(self.synced));
+        status.insert("seq".to_string(), DataType::from(self.applied_seq as f32));
+        let behind = self.primary_seq.saturating_sub(self.applied_seq);
+        status.insert("behind".to_string(), DataType::from(behind as f32));
+        if let Some(ts) = self.last_ts {
+            // seconds since the primary produced the last line received
+            let lag = now_millis().saturating_sub(ts) as f32 / 1000.0;
+            status.insert("lag".to_string(), DataType::from(lag));
+        }
+        DataType::Document(status)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_replication() {
+    let mut db = InfuseDB::new();
+    let mut primary = Primary::new(&mut db);
+    db.create_collection("users").unwrap();
+    // runs the write on the primary as the server does
+    let write = |db: &mut InfuseDB, primary: &Primary, cmd: &str| {
+        db.get_collection("users").unwrap().run(cmd).ok().unwrap();
+        primary.record(db, "users", cmd);
+    };
+    write(&mut db, &primary, "set a {city: \"rome\", age: 30} ttl 100");
+    write(&mut db, &primary, "create index by_city on *.city");
+    write(&mut db, &primary, "retention versions 2");
+    let _ = primary.pending();
+
+    let mut replica = InfuseDB::new();
+    let mut link = ReplicaLink::new("127.0.0.1:1".parse().unwrap());
+    for line in primary.full_sync(&mut db) {
+        link.apply(&line, &mut replica);
+    }
+    assert!(link.synced);
+
+    write(&mut db, &primary, "set b {city: \"oslo\", age: 20}");
+    write(&mut db, &primary, "expire b 50");
+    write(&mut db, &primary, "persist a");
+    write(&mut db, &primary, "drop index by_city");
+    write(&mut db, &primary, "create unique index by_age on *.age");
+    write(&mut db, &primary, "fulltext on city");
+    write(&mut db, &primary, "retention versions 3 age 60");
+    write(&mut db, &primary, "set c 1 ttl 10");
+    write(&mut db, &primary, "del c");
+    let lines = primary.pending();
+    assert!(lines.iter().any(|l| l.starts_with("ttl ")));
+    assert!(lines.iter().any(|l| l.starts_with("cmd ")));
+    for line in lines {
+        link.apply(&line, &mut replica);
+    }
+    assert_eq!(link.applied_seq, primary.seq());
+
+    // the ttls, indexes and retention, the fields of the documents are not
+    // dumped in order and the versions are given by each server
+    let settings = |db: &mut InfuseDB| {
+        let dump = db.get_collection("users").unwrap().dump();
+        let lines = dump.lines().filter(|l| !l.starts_with(char::is_numeric));
+        let lines = lines.filter(|l| !l.starts_with("revision"));
+        let mut lines: Vec<String> = lines.map(|l| l.to_string()).collect();
+        lines.sort();
+        lines
+    };
+    assert_eq!(settings(&mut replica), settings(&mut db));
+    let list = |db: &mut InfuseDB| db.get_collection("users").unwrap().list();
+    assert_eq!(list(&mut replica), list(&mut db));
+    let users = replica.get_collection("users").unwrap();
+    assert!(users.ttl("a").is_none());
+    assert!(users.ttl("b").is_some_and(|t| t.as_secs() > 40));
+    assert!(users.get("c").is_none());
+    assert_eq!(users.indexes(), vec![("by_age", "*.age".to_string(), true)]);
+}
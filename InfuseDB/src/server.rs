@@ -1,62 +1,269 @@
 use crate::InfuseDB;
 use crate::VERSION;
-use crate::command::Command;
-use crate::infusedb::DataType;
+use crate::audit::{self, AuditLog};
+use crate::cluster::{CLUSTER, Cluster};
+use crate::command::{self, Command, CommandError};
+use crate::metrics::{self, Metrics};
+use crate::raft::{self, NodeId, RaftNode, Role};
+use crate::replication::{Primary, ReplicaLink};
+use infusedb::{ChangeEvent, DataType, utils};
 
 use mio::net::{TcpListener, TcpStream};
-use mio::{Events, Interest, Poll, Token};
-use std::collections::HashMap;
+use mio::{Events, Interest, Poll, Registry, Token};
+use std::collections::{HashMap, HashSet};
 
 use std::{
     io::{Read, Write},
     net::SocketAddr,
-    sync::{Arc, Mutex},
+    sync::mpsc::{Receiver, TryRecvError},
+    time::{Duration, Instant},
 };
 
+/// Server options, `None` means unlimited or disabled.
+#[derive(Default)]
+pub struct ServerConfig {
+    pub max_connections: Option<usize>,
+    pub idle_timeout: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+    /// Address of the Prometheus metrics listener
+    pub metrics_addr: Option<SocketAddr>,
+    /// NDJSON file with every command received
+    pub audit_log: Option<String>,
+    /// NDJSON file with the commands slower than `slow_threshold`
+    pub slow_log: Option<String>,
+    pub slow_threshold: Duration,
+    /// Primary to replicate from, the server rejects writes when set
+    pub replica_of: Option<SocketAddr>,
+    /// Client addresses of the nodes of the Raft cluster, empty when not clustered
+    pub cluster: HashMap<NodeId, SocketAddr>,
+    /// Id of this server in `cluster`
+    pub node_id: NodeId,
+}
+
 pub struct Server {
     addr: SocketAddr,
     db: InfuseDB,
+    pub config: ServerConfig,
+    started_at: Instant,
+    commands_served: u64,
+    metrics: Metrics,
+    audit: Option<AuditLog>,
+    // mutations to stream, created when the first replica connects
+    primary: Option<Primary>,
+    replica: Option<ReplicaLink>,
+    cluster: Option<Cluster>,
+    // clients waiting for the commit of their command, by log index
+    waiting: HashMap<u64, (Token, u64)>,
 }
 
 pub struct Context {
     socket: TcpStream,
+    peer: SocketAddr,
     collection: Option<String>,
+    connected_at: Instant,
+    last_active: Instant,
+    // output waiting for the socket to be writable
+    pending: Vec<u8>,
+    // the client did not read its output and went over MAX_PENDING
+    overflowed: bool,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    watches: Vec<Receiver<ChangeEvent>>,
+    // connection of a replica receiving the mutations
+    replica: bool,
+    // commands waiting for exec with their collection, None outside a
+    // transaction
+    queued: Option<Vec<(String, String)>>,
+    // keys that must not change before exec, with their collection and
+    // version when watched
+    watched: Vec<(String, String, u64)>,
 }
 
-fn process_request(db: &Arc<Mutex<InfuseDB>>, collection: String, cmd: &str) -> String {
-    let r = {
-        let mut db = db.lock().unwrap();
-        db.get_collection(&collection).unwrap().run(cmd)
-    };
+impl Context {
+    fn new(socket: TcpStream, peer: SocketAddr) -> Self {
+        let now = Instant::now();
+        Context {
+            socket,
+            peer,
+            collection: None,
+            connected_at: now,
+            last_active: now,
+            pending: Vec::new(),
+            overflowed: false,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            watches: Vec::new(),
+            replica: false,
+            queued: None,
+            watched: Vec::new(),
+        }
+    }
+
+    // Queues a line for the client and writes as much as the socket accepts.
+    // Once the output of a slow client goes over MAX_PENDING it is dropped
+    // and the connection closed on the next tick
+    fn send(&mut self, registry: &Registry, token: Token, line: &str) {
+        if self.overflowed {
+            return;
+        }
+        self.pending.extend_from_slice(line.as_bytes());
+        self.pending.extend_from_slice(b"\r\n");
+        self.flush(registry, token);
+        if self.pending.len() > MAX_PENDING {
+            self.pending = Vec::new();
+            self.overflowed = true;
+        }
+    }
+
+    fn flush(&mut self, registry: &Registry, token: Token) {
+        let was_waiting = !self.pending.is_empty();
+        while !self.pending.is_empty() {
+            match self.socket.write(&self.pending) {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    // broken connection, it will be closed on the next read
+                    self.pending.clear();
+                }
+            }
+        }
+        let interest = if self.pending.is_empty() {
+            Interest::READABLE
+        } else {
+            Interest::READABLE | Interest::WRITABLE
+        };
+        if was_waiting || !self.pending.is_empty() {
+            let _ = registry.reregister(&mut self.socket, token, interest);
+        }
+    }
+
+    // Pushes the pending change events of the watched collections
+    fn push_events(&mut self, registry: &Registry, token: Token) {
+        let mut events = Vec::new();
+        self.watches.retain(|watch| {
+            loop {
+                match watch.try_recv() {
+                    Ok(event) => events.push(event),
+                    Err(TryRecvError::Empty) => break true,
+                    Err(TryRecvError::Disconnected) => break false,
+                }
+            }
+        });
+        for event in events {
+            self.send(registry, token, &format!("event: {}", event.to_json()));
+        }
+    }
 
-    match r {
-        Ok(result) => result.to_string(),
-        Err(err) => err.to_string(),
+    fn is_subscribed(&self, channel: &str) -> bool {
+        self.channels.contains(channel)
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| utils::glob_match(pattern, channel))
+    }
+
+    fn describe(&self, token: Token, now: Instant) -> DataType {
+        let mut client = HashMap::new();
+        client.insert("id".to_string(), DataType::from(token.0 as f32));
+        client.insert("addr".to_string(), DataType::from(self.peer.to_string()));
+        if let Some(collection) = &self.collection {
+            client.insert(
+                "collection".to_string(),
+                DataType::from(collection.as_str()),
+            );
+        }
+        let idle = now.duration_since(self.last_active).as_secs_f32();
+        client.insert("idle".to_string(), DataType::from(idle));
+        let age = now.duration_since(self.connected_at).as_secs_f32();
+        client.insert("age".to_string(), DataType::from(age));
+        DataType::Document(client)
+    }
+
+    // Reason to close the connection, if any limit has been reached
+    fn expired(&self, config: &ServerConfig, now: Instant) -> Option<&'static str> {
+        if self.overflowed {
+            return Some("Output buffer full");
+        }
+        if self.replica {
+            return None;
+        }
+        if let Some(max) = config.max_lifetime
+            && now.duration_since(self.connected_at) >= max
+        {
+            return Some("Session expired");
+        }
+        if let Some(idle) = config.idle_timeout
+            && now.duration_since(self.last_active) >= idle
+        {
+            return Some("Idle timeout");
+        }
+        None
     }
 }
 
 enum ProcessError {
     InvalidCommand,
     NotFound,
+    NoCollection,
     Other(&'static str),
+    Command(CommandError),
+    // write sent to a node of the cluster that is not the leader
+    Redirect(SocketAddr),
+    // the reply is sent once the cluster commits the command
+    Deferred,
+}
+
+impl ProcessError {
+    fn kind(&self) -> &'static str {
+        match self {
+            ProcessError::InvalidCommand => "invalid_command",
+            ProcessError::NotFound => "not_found",
+            ProcessError::NoCollection => "no_collection",
+            ProcessError::Other(_) => "other",
+            ProcessError::Command(err) => err.kind(),
+            ProcessError::Redirect(_) => "redirect",
+            ProcessError::Deferred => "deferred",
+        }
+    }
+}
+
+impl std::fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcessError::InvalidCommand => write!(f, "Invalid Command"),
+            ProcessError::NotFound => write!(f, "Command does not exists"),
+            ProcessError::NoCollection => write!(f, "No collection selected"),
+            ProcessError::Other(text) => write!(f, "{}", text),
+            ProcessError::Command(err) => write!(f, "{}", err.to_string()),
+            ProcessError::Redirect(addr) => write!(f, "Redirect {}", addr),
+            ProcessError::Deferred => write!(f, "Command queued"),
+        }
+    }
 }
 
 fn process_cmd(cmd: &str, ctx: &mut Context, db: &mut InfuseDB) -> Result<DataType, ProcessError> {
     let args: Vec<&str> = cmd.split_whitespace().collect();
 
     if let Some(collection) = ctx.collection.clone() {
-        return db
-            .get_collection(&collection)
-            .unwrap()
-            .run(cmd)
-            .map_err(|err| match err {
+        let result = db.get_collection(&collection).unwrap().run(cmd);
+        // not a collection command, try with the server ones
+        if !matches!(result, Err(CommandError::UnknownCommand)) {
+            return result.map_err(|err| match err {
                 crate::command::CommandError::EmptyCommand => ProcessError::InvalidCommand,
                 crate::command::CommandError::NoEnoughArgs => ProcessError::InvalidCommand,
                 crate::command::CommandError::UnknownCommand => ProcessError::NotFound,
                 crate::command::CommandError::ErrorParsing => ProcessError::InvalidCommand,
-                crate::command::CommandError::KeyNotFound(_, _) => ProcessError::NotFound,
+                err @ (crate::command::CommandError::KeyNotFound(_, _)
+                | crate::command::CommandError::VersionMismatch(_)
+                | crate::command::CommandError::ConstraintViolation(_, _)) => {
+                    ProcessError::Command(err)
+                }
                 crate::command::CommandError::Custom(err) => ProcessError::Other(err),
             });
+        }
     }
 
     match *args.first().ok_or(ProcessError::InvalidCommand)? {
@@ -90,10 +297,45 @@ fn process_cmd(cmd: &str, ctx: &mut Context, db: &mut InfuseDB) -> Result<DataTy
                 .collect();
             Ok(DataType::Array(list))
         }
+        "search" => {
+            // search <collection> "<terms>"
+            let name = args.get(1).ok_or(ProcessError::InvalidCommand)?;
+            let collection = db
+                .get_collection(name)
+                .ok_or(ProcessError::Other("Collection does not exist"))?;
+            let terms = args[2..].join(" ");
+            let terms = terms.trim_matches('"');
+            collection
+                .run(&format!("search \"{}\"", terms))
+                .map_err(ProcessError::Command)
+        }
         _ => Err(ProcessError::NotFound),
     }
 }
+// Commands of the server, they can not be part of a transaction
+const SERVER_COMMANDS: &[&str] = &[
+    "echo",
+    "select",
+    "unselect",
+    "info",
+    "client",
+    "publish",
+    "subscribe",
+    "psubscribe",
+    "unsubscribe",
+    "watch",
+    "unwatch",
+    "replicate",
+];
+
 const SERVER: Token = Token(0);
+const METRICS: Token = Token(usize::MAX);
+const REPLICA: Token = Token(usize::MAX - 1);
+// Max time between ticks, which reap the expired keys, push the change
+// events and check the connection limits
+const TICK: Duration = Duration::from_millis(500);
+// Bytes of output kept for a client that does not read them
+const MAX_PENDING: usize = 32 * 1024 * 1024;
 
 impl Server {
     pub fn new(host: &str, port: usize, db: InfuseDB) -> Result<Self, &'static str> {
@@ -102,10 +344,587 @@ impl Server {
                 .parse()
                 .map_err(|_| "Invalid address")?,
             db,
+            config: ServerConfig::default(),
+            started_at: Instant::now(),
+            commands_served: 0,
+            metrics: Metrics::new(),
+            audit: None,
+            primary: None,
+            replica: None,
+            cluster: None,
+            waiting: HashMap::new(),
         };
         Ok(server)
     }
 
+    fn poll_timeout(&self) -> Duration {
+        if self.cluster.is_some() {
+            return raft::HEARTBEAT_INTERVAL;
+        }
+        [self.config.idle_timeout, self.config.max_lifetime]
+            .into_iter()
+            .flatten()
+            .fold(TICK, Duration::min)
+    }
+
+    fn accept(
+        &self,
+        poll: &Poll,
+        listener: &TcpListener,
+        connections: &mut HashMap<Token, Context>,
+        unique_token: &mut usize,
+    ) -> std::io::Result<()> {
+        loop {
+            let (mut stream, peer) = match listener.accept() {
+                Ok(conn) => conn,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            if let Some(max) = self.config.max_connections
+                && connections.len() >= max
+            {
+                let _ = stream.write_all(b"err: Too many connections\r\n");
+                continue;
+            }
+            let token = Token(*unique_token);
+            *unique_token += 1;
+            let header = format!("InfuseDB {}\r\n", VERSION);
+            if stream.write_all(header.as_bytes()).is_err() {
+                continue;
+            }
+            poll.registry()
+                .register(&mut stream, token, Interest::READABLE)?;
+            connections.insert(token, Context::new(stream, peer));
+        }
+    }
+
+    fn accept_scrapes(
+        &self,
+        poll: &Poll,
+        listener: &TcpListener,
+        scrapes: &mut HashMap<Token, TcpStream>,
+        unique_token: &mut usize,
+    ) -> std::io::Result<()> {
+        loop {
+            let (mut stream, _) = match listener.accept() {
+                Ok(conn) => conn,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let token = Token(*unique_token);
+            *unique_token += 1;
+            poll.registry()
+                .register(&mut stream, token, Interest::READABLE)?;
+            scrapes.insert(token, stream);
+        }
+    }
+
+    // Any request on the metrics listener gets the metrics and is closed
+    fn scrape(&mut self, mut stream: TcpStream, connections: usize) {
+        let mut buf = [0u8; 1024];
+        let _ = stream.read(&mut buf);
+        let body = self.metrics.render(&mut self.db, connections);
+        let _ = stream.write_all(metrics::http_response(&body).as_bytes());
+    }
+
+    fn close_expired(&self, poll: &Poll, connections: &mut HashMap<Token, Context>) {
+        let now = Instant::now();
+        let expired: Vec<(Token, &'static str)> = connections
+            .iter()
+            .filter_map(|(token, ctx)| ctx.expired(&self.config, now).map(|r| (*token, r)))
+            .collect();
+        for (token, reason) in expired {
+            if let Some(mut ctx) = connections.remove(&token) {
+                let _ = ctx
+                    .socket
+                    .write_all(format!("err: {}\r\n", reason).as_bytes());
+                let _ = poll.registry().deregister(&mut ctx.socket);
+            }
+        }
+    }
+
+    fn info(&mut self, connections: usize, replicas: usize) -> DataType {
+        let mut info = HashMap::new();
+        info.insert("version".to_string(), DataType::from(VERSION));
+        let uptime = self.started_at.elapsed().as_secs_f32();
+        info.insert("uptime".to_string(), DataType::from(uptime));
+        info.insert(
+            "connections".to_string(),
+            DataType::from(connections as f32),
+        );
+        let mut keys = HashMap::new();
+        for name in self.db.get_collection_list() {
+            let count = self.db.get_collection(&name).unwrap().count();
+            keys.insert(name, DataType::from(count as f32));
+        }
+        info.insert("collections".to_string(), DataType::from(keys.len() as f32));
+        info.insert("keys".to_string(), DataType::Document(keys));
+        let memory = self.db.approx_size() as f32;
+        info.insert("memory".to_string(), DataType::from(memory));
+        if let Some(limit) = self.db.memory_limit() {
+            let max = limit.max_bytes as f32;
+            info.insert("maxmemory".to_string(), DataType::from(max));
+            let policy = limit.policy.as_str();
+            info.insert("maxmemory_policy".to_string(), DataType::from(policy));
+        }
+        let evicted = self.db.evicted() as f32;
+        info.insert("evicted".to_string(), DataType::from(evicted));
+        let commands = self.commands_served as f32;
+        info.insert("commands".to_string(), DataType::from(commands));
+        if let Some(saved) = self.db.last_save() {
+            // seconds since the last commit
+            let ago = saved.elapsed().unwrap_or_default().as_secs_f32();
+            info.insert("last_save".to_string(), DataType::from(ago));
+        }
+        match (&self.replica, &self.primary) {
+            _ if self.cluster.is_some() => {
+                let cluster = self.cluster.as_ref().unwrap();
+                let node = &cluster.node;
+                info.insert("role".to_string(), DataType::from(node.role().as_str()));
+                let mut status = HashMap::new();
+                status.insert("node".to_string(), DataType::from(node.id as f32));
+                status.insert("term".to_string(), DataType::from(node.term() as f32));
+                if let Some(addr) = node.leader().and_then(|id| cluster.addr(id)) {
+                    status.insert("leader".to_string(), DataType::from(addr.to_string()));
+                }
+                let commit = node.commit_index() as f32;
+                status.insert("commit".to_string(), DataType::from(commit));
+                let applied = node.last_applied() as f32;
+                status.insert("applied".to_string(), DataType::from(applied));
+                let peers = cluster.connected() as f32;
+                status.insert("connected".to_string(), DataType::from(peers));
+                info.insert("cluster".to_string(), DataType::Document(status));
+            }
+            (Some(link), _) => {
+                info.insert("role".to_string(), DataType::from("replica"));
+                info.insert("replication".to_string(), link.status());
+            }
+            (None, primary) => {
+                info.insert("role".to_string(), DataType::from("primary"));
+                let seq = primary.as_ref().map(|p| p.seq()).unwrap_or(0);
+                let mut replication = HashMap::new();
+                replication.insert("seq".to_string(), DataType::from(seq as f32));
+                let replicas = replicas as f32;
+                replication.insert("replicas".to_string(), DataType::from(replicas));
+                info.insert("replication".to_string(), DataType::Document(replication));
+            }
+        }
+        DataType::Document(info)
+    }
+
+    fn client(
+        &self,
+        args: &[&str],
+        poll: &Poll,
+        connections: &mut HashMap<Token, Context>,
+    ) -> Result<DataType, ProcessError> {
+        match args.first() {
+            Some(&"list") => {
+                let now = Instant::now();
+                let mut tokens: Vec<&Token> = connections.keys().collect();
+                tokens.sort();
+                let clients = tokens
+                    .into_iter()
+                    .map(|token| connections[token].describe(*token, now))
+                    .collect();
+                Ok(DataType::Array(clients))
+            }
+            Some(&"kill") => {
+                let id = args
+                    .get(1)
+                    .and_then(|id| id.parse::<usize>().ok())
+                    .ok_or(ProcessError::Other("Invalid client id"))?;
+                let killed = match connections.remove(&Token(id)) {
+                    Some(mut ctx) => {
+                        let _ = ctx.socket.write_all(b"err: Killed\r\n");
+                        let _ = poll.registry().deregister(&mut ctx.socket);
+                        true
+                    }
+                    None => false,
+                };
+                Ok(DataType::Boolean(killed))
+            }
+            _ => Err(ProcessError::InvalidCommand),
+        }
+    }
+
+    fn subscribe(args: &[&str], ctx: &mut Context) -> Result<DataType, ProcessError> {
+        let names = &args[1..];
+        match args[0] {
+            "subscribe" | "psubscribe" if names.is_empty() => {
+                return Err(ProcessError::Other("No channel provided"));
+            }
+            "subscribe" => ctx.channels.extend(names.iter().map(|s| s.to_string())),
+            "psubscribe" => ctx.patterns.extend(names.iter().map(|s| s.to_string())),
+            _ if names.is_empty() => {
+                ctx.channels.clear();
+                ctx.patterns.clear();
+            }
+            _ => {
+                for name in names {
+                    ctx.channels.remove(*name);
+                    ctx.patterns.remove(*name);
+                }
+            }
+        }
+        let count = ctx.channels.len() + ctx.patterns.len();
+        Ok(DataType::Number(count as f32))
+    }
+
+    // Pushes the value to every subscribed connection
+    fn publish(
+        &self,
+        cmd: &str,
+        poll: &Poll,
+        connections: &mut HashMap<Token, Context>,
+    ) -> Result<DataType, ProcessError> {
+        let args = utils::smart_split(cmd.to_string());
+        if args.len() < 3 {
+            return Err(ProcessError::Command(CommandError::NoEnoughArgs));
+        }
+        let channel = &args[1];
+        let t = DataType::infer_type(&args[2]);
+        let value = DataType::load(t, args[2].clone())
+            .ok_or(ProcessError::Command(CommandError::ErrorParsing))?;
+        let message = format!("msg: {} {}", channel, value.to_json());
+        let mut receivers = 0;
+        for (token, ctx) in connections.iter_mut() {
+            if ctx.is_subscribed(channel) {
+                ctx.send(poll.registry(), *token, &message);
+                receivers += 1;
+            }
+        }
+        Ok(DataType::Number(receivers as f32))
+    }
+
+    fn execute(
+        &mut self,
+        cmd: &str,
+        token: Token,
+        poll: &Poll,
+        connections: &mut HashMap<Token, Context>,
+    ) -> Result<DataType, ProcessError> {
+        let start = Instant::now();
+        let client = connections
+            .get(&token)
+            .map(|ctx| (ctx.peer, ctx.collection.clone()));
+        let result = self.dispatch(cmd, token, poll, connections);
+        let name = match &result {
+            Err(ProcessError::NotFound)
+            | Err(ProcessError::Command(CommandError::UnknownCommand)) => "unknown".to_string(),
+            _ => cmd
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_lowercase(),
+        };
+        let elapsed = start.elapsed();
+        let failure = result
+            .as_ref()
+            .err()
+            .filter(|err| !matches!(err, ProcessError::Deferred));
+        let error = failure.map(|err| err.kind());
+        self.metrics.command(&name, elapsed, error);
+        if let Some(audit) = self.audit.as_mut()
+            && let Some((peer, collection)) = client
+        {
+            audit.record(&audit::Entry {
+                client: peer,
+                user: None,
+                collection: collection.as_deref(),
+                command: cmd,
+                error: failure.map(|err| err.to_string()),
+                duration: elapsed,
+            });
+        }
+        result
+    }
+
+    fn dispatch(
+        &mut self,
+        cmd: &str,
+        token: Token,
+        poll: &Poll,
+        connections: &mut HashMap<Token, Context>,
+    ) -> Result<DataType, ProcessError> {
+        self.commands_served += 1;
+        let args: Vec<&str> = cmd.split_whitespace().collect();
+        if let Some(result) = self.transaction(cmd, &args, token, connections) {
+            return result;
+        }
+        match args.first() {
+            Some(&"info") => {
+                let replicas = connections.values().filter(|ctx| ctx.replica).count();
+                return Ok(self.info(connections.len(), replicas));
+            }
+            Some(&"client") => return self.client(&args[1..], poll, connections),
+            Some(&"publish") => return self.publish(cmd, poll, connections),
+            _ => {}
+        }
+        let ctx = connections
+            .get_mut(&token)
+            .ok_or(ProcessError::Other("Connection closed"))?;
+        match args.first() {
+            Some(&"subscribe" | &"psubscribe" | &"unsubscribe") => {
+                return Server::subscribe(&args, ctx);
+            }
+            Some(&"watch") => {
+                let name = args
+                    .get(1)
+                    .ok_or(ProcessError::Other("No collection name provided"))?;
+                let collection = self
+                    .db
+                    .get_collection(name)
+                    .ok_or(ProcessError::Other("Collection does not exist"))?;
+                ctx.watches.push(collection.watch(args.get(2).copied()));
+                return Ok(DataType::Boolean(true));
+            }
+            Some(&"unwatch") => {
+                let watching = !ctx.watches.is_empty();
+                ctx.watches.clear();
+                return Ok(DataType::Boolean(watching));
+            }
+            Some(&"replicate") => {
+                if self.replica.is_some() {
+                    return Err(ProcessError::Other("Replicas can not be replicated"));
+                }
+                let primary = self
+                    .primary
+                    .get_or_insert_with(|| Primary::new(&mut self.db));
+                for line in primary.full_sync(&mut self.db) {
+                    ctx.send(poll.registry(), token, &line);
+                }
+                ctx.replica = true;
+                return Ok(DataType::Boolean(true));
+            }
+            _ => {}
+        }
+        if self.replica.is_some() && ctx.collection.is_some() && command::is_write(cmd) {
+            return Err(ProcessError::Other("Read only replica"));
+        }
+        if let Some(collection) = ctx.collection.as_ref()
+            && command::is_write(cmd)
+        {
+            let size = command::write_size(&mut self.db, collection, cmd);
+            self.db.make_room(size).map_err(ProcessError::Other)?;
+        }
+        if let Some(cluster) = self.cluster.as_mut()
+            && let Some(collection) = ctx.collection.as_ref()
+            && command::is_write(cmd)
+        {
+            let command = cmd.trim();
+            if command.contains('\n') {
+                return Err(ProcessError::Other("One command per line in cluster mode"));
+            }
+            return match cluster.node.propose(collection, command) {
+                Ok(index) => {
+                    self.waiting.insert(index, (token, cluster.node.term()));
+                    Err(ProcessError::Deferred)
+                }
+                Err(leader) => match leader.and_then(|id| cluster.addr(id)) {
+                    Some(addr) => Err(ProcessError::Redirect(addr)),
+                    None => Err(ProcessError::Other("No leader elected")),
+                },
+            };
+        }
+        let collection = ctx.collection.clone();
+        let result = match process_cmd(cmd, ctx, &mut self.db) {
+            Err(ProcessError::NotFound) if collection.is_none() => Err(ProcessError::NoCollection),
+            result => result,
+        };
+        // the replicas also get what the write changed besides the keys
+        if result.is_ok()
+            && command::is_write(cmd)
+            && let Some(collection) = collection
+            && let Some(primary) = self.primary.as_ref()
+        {
+            primary.record(&mut self.db, &collection, cmd);
+        }
+        result
+    }
+
+    // Commands of the transactions, None if the command is not part of one
+    fn transaction(
+        &mut self,
+        cmd: &str,
+        args: &[&str],
+        token: Token,
+        connections: &mut HashMap<Token, Context>,
+    ) -> Option<Result<DataType, ProcessError>> {
+        let ctx = connections.get_mut(&token)?;
+        let result = match (args.first(), ctx.queued.as_mut()) {
+            (Some(&"begin"), Some(_)) => Err(ProcessError::Other("Transaction already started")),
+            (Some(&"begin"), None) => {
+                ctx.queued = Some(Vec::new());
+                Ok(DataType::Boolean(true))
+            }
+            (Some(&"discard"), queued) => {
+                let started = queued.is_some();
+                ctx.queued = None;
+                ctx.watched.clear();
+                Ok(DataType::Boolean(started))
+            }
+            (Some(&"exec"), _) => return Some(self.exec(token, connections)),
+            (Some(&"watchkey"), _) => {
+                let collection = ctx.collection.clone().ok_or(ProcessError::NoCollection);
+                let key = args.get(1).ok_or(ProcessError::InvalidCommand);
+                collection.and_then(|collection| {
+                    let key = key?.to_string();
+                    let version = self
+                        .db
+                        .get_collection(&collection)
+                        .map(|c| c.version(&key))
+                        .unwrap_or(0);
+                    ctx.watched.push((collection, key, version));
+                    Ok(DataType::Boolean(true))
+                })
+            }
+            (Some(action), Some(_)) if SERVER_COMMANDS.contains(action) => Err(
+                ProcessError::Other("Server commands can not be part of a transaction"),
+            ),
+            (Some(_), Some(queued)) => match ctx.collection.clone() {
+                Some(collection) => {
+                    queued.push((collection, cmd.trim().to_string()));
+                    Ok(DataType::from("queued"))
+                }
+                None => Err(ProcessError::NoCollection),
+            },
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    // Runs the queued commands as a transaction with command::exec, no other
+    // client is served in between. Replies with the result of each command
+    fn exec(
+        &mut self,
+        token: Token,
+        connections: &mut HashMap<Token, Context>,
+    ) -> Result<DataType, ProcessError> {
+        let ctx = connections
+            .get_mut(&token)
+            .ok_or(ProcessError::Other("Connection closed"))?;
+        let queued = ctx
+            .queued
+            .take()
+            .ok_or(ProcessError::Other("No transaction started"))?;
+        for (collection, key, version) in std::mem::take(&mut ctx.watched) {
+            let current = self
+                .db
+                .get_collection(&collection)
+                .map(|c| c.version(&key))
+                .unwrap_or(0);
+            if current != version {
+                return Err(ProcessError::Other(
+                    "Transaction aborted, a watched key changed",
+                ));
+            }
+        }
+        let writes = queued.iter().any(|(_, cmd)| command::is_write(cmd));
+        if writes && self.cluster.is_some() {
+            return Err(ProcessError::Other(
+                "Transactions are not supported in cluster mode",
+            ));
+        }
+        if writes && self.replica.is_some() {
+            return Err(ProcessError::Other("Read only replica"));
+        }
+        if writes {
+            let size = queued
+                .iter()
+                .map(|(c, cmd)| command::write_size(&mut self.db, c, cmd))
+                .sum();
+            self.db.make_room(size).map_err(ProcessError::Other)?;
+        }
+        let results = command::exec(&mut self.db, &queued).map_err(ProcessError::Command)?;
+        if let Some(primary) = self.primary.as_ref() {
+            for (collection, cmd) in queued.iter().filter(|(_, cmd)| command::is_write(cmd)) {
+                primary.record(&mut self.db, collection, cmd);
+            }
+        }
+        Ok(DataType::Array(results))
+    }
+
+    // Applies the commands committed by the cluster and answers the clients
+    // that sent them to this node
+    fn apply_committed(&mut self, poll: &Poll, connections: &mut HashMap<Token, Context>) {
+        let Some(cluster) = self.cluster.as_mut() else {
+            return;
+        };
+        for (index, entry) in cluster.node.take_committed() {
+            let result = if entry.command.is_empty() {
+                Ok(DataType::Boolean(true))
+            } else {
+                if self.db.get_collection(&entry.collection).is_none() {
+                    let _ = self.db.create_collection(&entry.collection);
+                }
+                let collection = self.db.get_collection(&entry.collection).unwrap();
+                collection
+                    .run(&entry.command)
+                    .map_err(ProcessError::Command)
+            };
+            let Some((token, term)) = self.waiting.remove(&index) else {
+                continue;
+            };
+            let line = match result {
+                // another leader replaced the command with its own
+                _ if term != entry.term => "err: Leadership lost".to_string(),
+                Ok(result) => format!("ok: {}", result.to_json()),
+                Err(err) => format!("err: {}", err),
+            };
+            if let Some(ctx) = connections.get_mut(&token) {
+                self.metrics.bytes_out(line.len() + 2);
+                ctx.send(poll.registry(), token, &line);
+            }
+        }
+        if cluster.node.role() != Role::Leader {
+            // the commands may still be committed by the new leader
+            for (token, _) in self.waiting.drain().map(|(_, waiting)| waiting) {
+                if let Some(ctx) = connections.get_mut(&token) {
+                    ctx.send(poll.registry(), token, "err: Leadership lost");
+                }
+            }
+        }
+    }
+
+    // Sends the changes made by the last command to watchers and replicas
+    fn propagate(&mut self, poll: &Poll, connections: &mut HashMap<Token, Context>) {
+        let lines = match self.primary.as_mut() {
+            Some(primary) => primary.pending(),
+            None => Vec::new(),
+        };
+        for (token, ctx) in connections.iter_mut() {
+            ctx.push_events(poll.registry(), *token);
+            if ctx.replica {
+                for line in lines.iter() {
+                    ctx.send(poll.registry(), *token, line);
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self, poll: &Poll, connections: &mut HashMap<Token, Context>) {
+        if let Some(link) = self.replica.as_mut() {
+            link.connect(poll.registry(), REPLICA);
+        }
+        if let Some(cluster) = self.cluster.as_mut() {
+            cluster.tick(poll.registry());
+        }
+        // a replica deletes the expired keys when its primary does
+        if self.replica.is_none() {
+            self.db.reap_expired();
+        }
+        self.apply_committed(poll, connections);
+        self.propagate(poll, connections);
+        if let Some(primary) = self.primary.as_ref() {
+            let ping = primary.ping();
+            for (token, ctx) in connections.iter_mut().filter(|(_, ctx)| ctx.replica) {
+                ctx.send(poll.registry(), *token, &ping);
+            }
+        }
+        self.close_expired(poll, connections);
+    }
+
     pub fn listen(&mut self) -> std::io::Result<()> {
         let mut poll = Poll::new()?;
         let mut events = Events::with_capacity(128);
@@ -114,31 +933,82 @@ impl Server {
         let mut unique_token = 1;
         poll.registry()
             .register(&mut listener, SERVER, Interest::READABLE)?;
+        let mut metrics_listener = match self.config.metrics_addr {
+            Some(addr) => {
+                let mut listener = TcpListener::bind(addr)?;
+                poll.registry()
+                    .register(&mut listener, METRICS, Interest::READABLE)?;
+                Some(listener)
+            }
+            None => None,
+        };
+        let mut scrapes: HashMap<Token, TcpStream> = HashMap::new();
+        if self.config.audit_log.is_some() || self.config.slow_log.is_some() {
+            self.audit = Some(AuditLog::open(
+                self.config.audit_log.as_deref(),
+                self.config.slow_log.as_deref(),
+                self.config.slow_threshold,
+            )?);
+        }
+        if let Some(primary) = self.config.replica_of {
+            let mut link = ReplicaLink::new(primary);
+            link.connect(poll.registry(), REPLICA);
+            self.replica = Some(link);
+        }
+        if !self.config.cluster.is_empty() {
+            let id = self.config.node_id;
+            let peers = self.config.cluster.keys().filter(|p| **p != id).copied();
+            let path = format!("{}.raft", self.db.path);
+            let node = RaftNode::open(id, peers.collect(), &path)?;
+            // each node would choose different keys to evict
+            self.db.set_eviction(false);
+            let addrs = self.config.cluster.clone();
+            self.cluster = Some(Cluster::new(node, addrs, poll.registry())?);
+        }
 
         loop {
-            poll.poll(&mut events, None)?;
+            poll.poll(&mut events, Some(self.poll_timeout()))?;
             for event in events.iter() {
                 match event.token() {
                     SERVER => {
                         // Nueva conexión entrante
-                        let (mut stream, _) = listener.accept()?;
-                        let token = Token(unique_token);
-                        unique_token += 1;
-                        let header = format!("InfuseDB {}\r\n", VERSION);
-                        stream.write_all(header.as_bytes()).unwrap();
-                        poll.registry()
-                            .register(&mut stream, token, Interest::READABLE)?;
-                        connections.insert(
-                            token,
-                            Context {
-                                socket: stream,
-                                collection: None,
-                            },
-                        );
+                        self.accept(&poll, &listener, &mut connections, &mut unique_token)?;
+                    }
+                    METRICS => {
+                        if let Some(listener) = metrics_listener.as_mut() {
+                            self.accept_scrapes(&poll, listener, &mut scrapes, &mut unique_token)?;
+                        }
+                    }
+                    CLUSTER => {
+                        if let Some(cluster) = self.cluster.as_mut() {
+                            cluster.accept(poll.registry(), &mut unique_token)?;
+                        }
+                    }
+                    token if self.cluster.as_ref().is_some_and(|c| c.owns(token)) => {
+                        let cluster = self.cluster.as_mut().unwrap();
+                        cluster.on_event(poll.registry(), token, event.is_writable());
+                    }
+                    REPLICA => {
+                        if let Some(link) = self.replica.as_mut() {
+                            link.on_readable(poll.registry(), &mut self.db);
+                        }
+                        self.propagate(&poll, &mut connections);
+                    }
+                    token if scrapes.contains_key(&token) => {
+                        let stream = scrapes.remove(&token).unwrap();
+                        self.scrape(stream, connections.len());
                     }
                     token => {
                         // Socket de cliente listo
-                        let ctx = connections.get_mut(&token).unwrap();
+                        let Some(ctx) = connections.get_mut(&token) else {
+                            continue;
+                        };
+                        if event.is_writable() {
+                            ctx.flush(poll.registry(), token);
+                        }
+                        if !event.is_readable() {
+                            continue;
+                        }
 
                         let mut buf = [0u8; 1024];
                         match ctx.socket.read(&mut buf) {
@@ -148,40 +1018,24 @@ impl Server {
                             }
                             Ok(n) => {
                                 // procesar datos
-                                let data = &buf[..n];
-                                let cmd = str::from_utf8(&data).unwrap();
-                                let cmd_result: Result<DataType, ProcessError> =
-                                    process_cmd(cmd, ctx, &mut self.db);
-                                let result: Result<DataType, String> = match cmd_result {
-                                    Ok(result) => Ok(result),
-                                    Err(err) => match err {
-                                        ProcessError::InvalidCommand => {
-                                            Err("Invalid Command".to_string())
-                                        }
-                                        ProcessError::NotFound => {
-                                            if let Some(collection) = ctx.collection.clone() {
-                                                self.db
-                                                    .get_collection(&collection)
-                                                    .unwrap()
-                                                    .run(cmd)
-                                                    .map_err(|err| err.to_string())
-                                            } else {
-                                                Err("No collection selected".to_string())
-                                            }
-                                        }
-                                        ProcessError::Other(text) => Err(text.to_string()),
-                                    },
-                                };
-
-                                let result = if result.is_ok() {
-                                    let r = result.unwrap();
-                                    format!("ok: {}", r.to_json())
-                                } else {
-                                    format!("err: {}", result.err().unwrap().to_string())
-                                };
-
-                                ctx.socket.write_all(result.as_bytes()).unwrap();
-                                ctx.socket.write_all(b"\r\n").unwrap(); // Respuesta simple
+                                ctx.last_active = Instant::now();
+                                self.metrics.bytes_in(n);
+                                let cmd = String::from_utf8_lossy(&buf[..n]).to_string();
+                                let result =
+                                    match self.execute(&cmd, token, &poll, &mut connections) {
+                                        Ok(result) => Some(format!("ok: {}", result.to_json())),
+                                        Err(ProcessError::Deferred) => None,
+                                        Err(err) => Some(format!("err: {}", err)),
+                                    };
+
+                                // the client may have killed its own connection
+                                if let Some(result) = result
+                                    && let Some(ctx) = connections.get_mut(&token)
+                                {
+                                    self.metrics.bytes_out(result.len() + 2);
+                                    ctx.send(poll.registry(), token, &result); // Respuesta simple
+                                }
+                                self.propagate(&poll, &mut connections);
                             }
                             Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                                 // no hay nada realmente
@@ -194,7 +1048,125 @@ impl Server {
                     }
                 }
             }
+            self.tick(&poll, &mut connections);
         }
         //Ok(())
     }
 }
+
+#[cfg(test)]
+#[test]
+fn test_expired() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = listener.local_addr().unwrap();
+    let socket = std::net::TcpStream::connect(peer).unwrap();
+    let mut ctx = Context::new(TcpStream::from_std(socket), peer);
+    let now = ctx.connected_at;
+    let second = Duration::from_secs(1);
+
+    let mut config = ServerConfig::default();
+    assert_eq!(ctx.expired(&config, now + second * 3600), None);
+
+    config.idle_timeout = Some(second * 10);
+    assert_eq!(ctx.expired(&config, now + second * 9), None);
+    assert_eq!(
+        ctx.expired(&config, now + second * 10),
+        Some("Idle timeout")
+    );
+    // activity resets the idle time but not the age of the session
+    ctx.last_active = now + second * 8;
+    assert_eq!(ctx.expired(&config, now + second * 15), None);
+
+    config.max_lifetime = Some(second * 12);
+    assert_eq!(ctx.expired(&config, now + second * 11), None);
+    assert_eq!(
+        ctx.expired(&config, now + second * 12),
+        Some("Session expired")
+    );
+
+    // replicas are never closed
+    ctx.replica = true;
+    assert_eq!(ctx.expired(&config, now + second * 60), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_output_limit() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = listener.local_addr().unwrap();
+    let socket = std::net::TcpStream::connect(peer).unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let poll = Poll::new().unwrap();
+    let token = Token(1);
+    let mut ctx = Context::new(TcpStream::from_std(socket), peer);
+    poll.registry()
+        .register(&mut ctx.socket, token, Interest::READABLE)
+        .unwrap();
+
+    // the other end never reads
+    let message = "m".repeat(1024 * 1024);
+    let config = ServerConfig::default();
+    while !ctx.overflowed {
+        assert_eq!(ctx.expired(&config, Instant::now()), None);
+        ctx.send(poll.registry(), token, &message);
+    }
+    assert!(ctx.pending.is_empty());
+    let reason = ctx.expired(&config, Instant::now());
+    assert_eq!(reason, Some("Output buffer full"));
+}
+
+#[cfg(test)]
+#[test]
+fn test_dispatch_replication() {
+    let mut db = InfuseDB::new();
+    db.create_collection("users").unwrap();
+    let mut server = Server::new("127.0.0.1", 0, db).unwrap();
+    server.primary = Some(Primary::new(&mut server.db));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = listener.local_addr().unwrap();
+    let socket = std::net::TcpStream::connect(peer).unwrap();
+    let poll = Poll::new().unwrap();
+    let token = Token(1);
+    let mut connections = HashMap::new();
+    connections.insert(token, Context::new(TcpStream::from_std(socket), peer));
+    let mut dispatch = |server: &mut Server, cmd: &str| {
+        server
+            .dispatch(cmd, token, &poll, &mut connections)
+            .map(|value| value.to_string())
+            .map_err(|err| err.to_string())
+    };
+
+    assert_eq!(
+        dispatch(&mut server, "get a").err().unwrap(),
+        "No collection selected"
+    );
+    dispatch(&mut server, "select users").unwrap();
+    // a missing key is not taken for an unknown command
+    let missing = dispatch(&mut server, "get a").err().unwrap();
+    assert_ne!(missing, ProcessError::NotFound.to_string());
+    assert_eq!(
+        dispatch(&mut server, "nope").err().unwrap(),
+        ProcessError::NotFound.to_string()
+    );
+
+    let mut replica = InfuseDB::new();
+    let mut link = ReplicaLink::new("127.0.0.1:1".parse().unwrap());
+    let primary = server.primary.as_ref().unwrap();
+    for line in primary.full_sync(&mut server.db) {
+        link.apply(&line, &mut replica);
+    }
+    dispatch(&mut server, "set a {city: \"rome\"} ttl 100").unwrap();
+    dispatch(&mut server, "set b {city: \"oslo\"}").unwrap();
+    dispatch(&mut server, "expire b 50").unwrap();
+    dispatch(&mut server, "create index by_city on *.city").unwrap();
+    for line in server.primary.as_mut().unwrap().pending() {
+        link.apply(&line, &mut replica);
+    }
+    let users = replica.get_collection("users").unwrap();
+    assert!(users.ttl("a").is_some_and(|t| t.as_secs() > 90));
+    assert!(users.ttl("b").is_some_and(|t| t.as_secs() > 40));
+    assert_eq!(
+        users.indexes(),
+        vec![("by_city", "*.city".to_string(), false)]
+    );
+}
@@ -0,0 +1,335 @@
+// Undo and redo of the changes made in the REPL
+// Each change keeps what is needed to revert it and to make it again: the
+// value of the top level key before and after a command, or the contents
+// of a dropped collection.
+use crate::command::{self, Command, CommandError};
+use infusedb::{Collection, DataType, InfuseDB, utils};
+
+// Changes kept, the oldest are forgotten
+const MAX_UNDO: usize = 100;
+
+enum Change {
+    Key {
+        collection: String,
+        key: String,
+        before: Option<DataType>,
+        after: Option<DataType>,
+    },
+    Create(String),
+    // page of the collection in the .mdb format
+    Drop(String, String),
+    // changes of the keys made by a transaction
+    Keys(Vec<Change>),
+}
+
+struct Step {
+    description: String,
+    change: Change,
+}
+
+#[derive(Default)]
+pub struct UndoStack {
+    undo: Vec<Step>,
+    redo: Vec<Step>,
+}
+
+fn set_key(
+    db: &mut InfuseDB,
+    collection: &str,
+    key: &str,
+    value: &Option<DataType>,
+) -> Result<(), &'static str> {
+    let collection = db
+        .get_collection(collection)
+        .ok_or("Collection does not exist")?;
+    match value {
+        Some(value) => collection.add(key, value.clone()).map(|_| ()),
+        None => {
+            collection.rm(key);
+            Ok(())
+        }
+    }
+}
+
+// Top level key changed by a write command, None for the other commands
+fn changed_key(cmd: &str) -> Option<String> {
+    if !command::is_write(cmd) {
+        return None;
+    }
+    let words = utils::smart_split(cmd.to_string());
+    let key = words.get(1)?.split('.').next().unwrap_or_default();
+    (!key.is_empty()).then(|| key.to_string())
+}
+
+fn value_of(db: &mut InfuseDB, collection: &str, key: &str) -> Option<DataType> {
+    db.get_collection(collection)?.get(key).cloned()
+}
+
+// Puts back the values before the change
+fn revert(db: &mut InfuseDB, change: &Change) -> Result<(), &'static str> {
+    match change {
+        Change::Key {
+            collection,
+            key,
+            before,
+            ..
+        } => set_key(db, collection, key, before),
+        Change::Create(name) => {
+            if db.get_collection(name).is_some() {
+                db.remove_collection(name.clone());
+            }
+            Ok(())
+        }
+        Change::Drop(_, page) => {
+            db.insert_collection(Collection::load(page));
+            Ok(())
+        }
+        Change::Keys(changes) => changes.iter().rev().try_for_each(|c| revert(db, c)),
+    }
+}
+
+// Makes the change again
+fn apply(db: &mut InfuseDB, change: &Change) -> Result<(), &'static str> {
+    match change {
+        Change::Key {
+            collection,
+            key,
+            after,
+            ..
+        } => set_key(db, collection, key, after),
+        Change::Create(name) => match db.create_collection(name) {
+            Ok(_) => Ok(()),
+            Err(_) => Err("Collection already exists"),
+        },
+        Change::Drop(name, _) => {
+            if db.get_collection(name).is_some() {
+                db.remove_collection(name.clone());
+            }
+            Ok(())
+        }
+        Change::Keys(changes) => changes.iter().try_for_each(|c| apply(db, c)),
+    }
+}
+
+impl UndoStack {
+    fn push(&mut self, description: String, change: Change) {
+        self.undo.push(Step {
+            description,
+            change,
+        });
+        if self.undo.len() > MAX_UNDO {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+    }
+
+    // Runs a command of the collection, recording the change of its key
+    pub fn run(
+        &mut self,
+        db: &mut InfuseDB,
+        collection: &str,
+        cmd: &str,
+    ) -> Result<DataType, CommandError> {
+        let target = db
+            .get_collection(collection)
+            .ok_or(CommandError::Custom("Collection does not exist"))?;
+        let Some(key) = changed_key(cmd) else {
+            return target.run(cmd);
+        };
+        let before = target.get(&key).cloned();
+        let result = target.run(cmd);
+        let after = target.get(&key).cloned();
+        if before != after {
+            let change = Change::Key {
+                collection: collection.to_string(),
+                key,
+                before,
+                after,
+            };
+            self.push(format!("{} [{}]", cmd.trim(), collection), change);
+        }
+        result
+    }
+
+    // Runs the commands of a transaction with command::exec, its changes are
+    // undone at once
+    pub fn exec(
+        &mut self,
+        db: &mut InfuseDB,
+        queued: &[(String, String)],
+    ) -> Result<Vec<DataType>, CommandError> {
+        let mut keys: Vec<(String, String)> = Vec::new();
+        for (collection, cmd) in queued {
+            if let Some(key) = changed_key(cmd)
+                && !keys.iter().any(|(c, k)| c == collection && *k == key)
+            {
+                keys.push((collection.clone(), key));
+            }
+        }
+        let before: Vec<Option<DataType>> = keys
+            .iter()
+            .map(|(collection, key)| value_of(db, collection, key))
+            .collect();
+        let results = command::exec(db, queued)?;
+        let mut changes = Vec::new();
+        for ((collection, key), before) in keys.into_iter().zip(before) {
+            let after = value_of(db, &collection, &key);
+            if before != after {
+                changes.push(Change::Key {
+                    collection,
+                    key,
+                    before,
+                    after,
+                });
+            }
+        }
+        if !changes.is_empty() {
+            let description = format!("exec of {} commands", queued.len());
+            self.push(description, Change::Keys(changes));
+        }
+        Ok(results)
+    }
+
+    pub fn created(&mut self, name: &str) {
+        self.push(format!("new {}", name), Change::Create(name.to_string()));
+    }
+
+    pub fn dropped(&mut self, collection: &Collection) {
+        let change = Change::Drop(collection.name.clone(), collection.dump());
+        self.push(format!("del_col {}", collection.name), change);
+    }
+
+    // Reverts the last change, returns its description, None if there is
+    // nothing to undo. A change that can not be reverted is forgotten
+    pub fn undo(&mut self, db: &mut InfuseDB) -> Option<Result<String, String>> {
+        let step = self.undo.pop()?;
+        if let Err(err) = revert(db, &step.change) {
+            return Some(Err(format!("{}: {}", step.description, err)));
+        }
+        let description = step.description.clone();
+        self.redo.push(step);
+        Some(Ok(description))
+    }
+
+    // Makes again the last change reverted, returns its description
+    pub fn redo(&mut self, db: &mut InfuseDB) -> Option<Result<String, String>> {
+        let step = self.redo.pop()?;
+        if let Err(err) = apply(db, &step.change) {
+            return Some(Err(format!("{}: {}", step.description, err)));
+        }
+        let description = step.description.clone();
+        self.undo.push(step);
+        Some(Ok(description))
+    }
+
+    // Descriptions of the changes that undo would revert, the next one first
+    pub fn pending(&self) -> Vec<&str> {
+        self.undo
+            .iter()
+            .rev()
+            .map(|step| step.description.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_undo_keys() {
+    let mut db = InfuseDB::new();
+    db.create_collection("users").unwrap();
+    let mut undo = UndoStack::default();
+    let value = |db: &mut InfuseDB| db.get_collection("users").unwrap().get("a").cloned();
+    undo.run(&mut db, "users", "set a 1").ok().unwrap();
+    undo.run(&mut db, "users", "set a 2").ok().unwrap();
+    undo.run(&mut db, "users", "del a").ok().unwrap();
+    // reads are not changes
+    undo.run(&mut db, "users", "get b").err().unwrap();
+    assert_eq!(
+        undo.pending(),
+        vec!["del a [users]", "set a 2 [users]", "set a 1 [users]"]
+    );
+
+    assert_eq!(undo.undo(&mut db), Some(Ok("del a [users]".to_string())));
+    assert_eq!(value(&mut db), Some(DataType::from(2)));
+    undo.undo(&mut db).unwrap().unwrap();
+    assert_eq!(value(&mut db), Some(DataType::from(1)));
+    undo.undo(&mut db).unwrap().unwrap();
+    assert_eq!(value(&mut db), None);
+    assert_eq!(undo.undo(&mut db), None);
+
+    assert_eq!(undo.redo(&mut db), Some(Ok("set a 1 [users]".to_string())));
+    assert_eq!(value(&mut db), Some(DataType::from(1)));
+    // a new change forgets what was undone
+    undo.run(&mut db, "users", "set a 3").ok().unwrap();
+    assert_eq!(undo.redo(&mut db), None);
+    assert_eq!(undo.pending(), vec!["set a 3 [users]", "set a 1 [users]"]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_undo_collections() {
+    let mut db = InfuseDB::new();
+    let mut undo = UndoStack::default();
+    db.create_collection("users").unwrap();
+    undo.created("users");
+    undo.run(&mut db, "users", "set a 1").ok().unwrap();
+    undo.dropped(db.get_collection("users").unwrap());
+    db.remove_collection("users".to_string());
+
+    undo.undo(&mut db).unwrap().unwrap();
+    let users = db.get_collection("users").unwrap();
+    assert_eq!(users.get("a"), Some(&DataType::from(1)));
+    undo.undo(&mut db).unwrap().unwrap();
+    undo.undo(&mut db).unwrap().unwrap();
+    assert!(db.get_collection("users").is_none());
+
+    undo.redo(&mut db).unwrap().unwrap();
+    assert!(db.get_collection("users").is_some());
+    undo.redo(&mut db).unwrap().unwrap();
+    undo.redo(&mut db).unwrap().unwrap();
+    assert!(db.get_collection("users").is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn test_undo_errors() {
+    let mut db = InfuseDB::new();
+    let mut undo = UndoStack::default();
+    db.create_collection("users").unwrap();
+    undo.run(&mut db, "users", "set a 1").ok().unwrap();
+    undo.run(&mut db, "users", "set b 1").ok().unwrap();
+    // dropped without recording it, the change is not made in a new collection
+    db.remove_collection("users".to_string());
+    let err = undo.undo(&mut db).unwrap().unwrap_err();
+    assert_eq!(err, "set b 1 [users]: Collection does not exist");
+    assert!(db.get_collection("users").is_none());
+    assert_eq!(undo.pending(), vec!["set a 1 [users]"]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_undo_exec() {
+    let mut db = InfuseDB::new();
+    let mut undo = UndoStack::default();
+    db.create_collection("users").unwrap();
+    undo.run(&mut db, "users", "set a 1").ok().unwrap();
+    let queued: Vec<(String, String)> = ["set a 2", "set b 3", "set a 4"]
+        .iter()
+        .map(|cmd| ("users".to_string(), cmd.to_string()))
+        .collect();
+    undo.exec(&mut db, &queued).ok().unwrap();
+    assert_eq!(
+        undo.pending(),
+        vec!["exec of 3 commands", "set a 1 [users]"]
+    );
+
+    // the commands are reverted together
+    undo.undo(&mut db).unwrap().unwrap();
+    let users = db.get_collection("users").unwrap();
+    assert_eq!(users.get("a"), Some(&DataType::from(1)));
+    assert!(users.get("b").is_none());
+    undo.redo(&mut db).unwrap().unwrap();
+    let users = db.get_collection("users").unwrap();
+    assert_eq!(users.get("a"), Some(&DataType::from(4)));
+    assert_eq!(users.get("b"), Some(&DataType::from(3)));
+}
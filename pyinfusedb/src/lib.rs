@@ -155,7 +155,7 @@ impl Collection {
         }
         let c = c.unwrap();
         let v = convert_py_to_data_type(py, &v)?;
-        c.add(k, v);
+        c.add(k, v).map_err(PyValueError::new_err)?;
         return Ok(());
     }
 